@@ -2,7 +2,120 @@ use std::env;
 use std::fs;
 use std::path::PathBuf;
 
+#[allow(dead_code)]
+#[path = "src/cli/embedding_file.rs"]
+mod embedding_file;
+#[allow(dead_code)]
+#[path = "src/cli/local_embedding.rs"]
+mod local_embedding;
+
+use embedding_file::{EmbeddingFile, Staleness, StoredEmbedding};
+use local_embedding::{embed, function_text, LOCAL_EMBED_MODEL};
+
+/// The name and description of every `Commands` variant, read from its source. Like the
+/// `ToolSet` macro, this takes a variant's description to be the last line of its doc comment.
+fn command_descriptions(source: &str) -> Vec<(String, String)> {
+    let Some(body) = source.split("pub enum Commands {").nth(1) else {
+        return Vec::new();
+    };
+
+    let mut commands = Vec::new();
+    let mut description = "";
+    for line in body.lines() {
+        if line.starts_with('}') {
+            break;
+        }
+        // Variants are indented once. Their fields and attributes don't matter here.
+        if !line.starts_with("    ") || line.starts_with("     ") {
+            continue;
+        }
+
+        let line = line.trim();
+        if let Some(doc) = line.strip_prefix("///") {
+            description = doc.trim();
+        } else if !line.starts_with("#[") {
+            let name: String = line
+                .chars()
+                .take_while(|c| c.is_alphanumeric() || *c == '_')
+                .collect();
+            if !name.is_empty() {
+                commands.push((name, description.to_string()));
+            }
+            description = "";
+        }
+    }
+    commands
+}
+
+/// Makes the local model's embeddings for every command. This needs no network, so unlike the
+/// embeddings `compile_embeddings_all` makes, they're made on every build that changes a command,
+/// into `OUT_DIR`, and built into the crate from there.
+fn write_local_embeddings(commands_path: &str, embed_path: &PathBuf) {
+    let source = fs::read_to_string(commands_path).expect("Failed to read the commands");
+    let functions = command_descriptions(&source)
+        .into_iter()
+        .filter(|(_, description)| !description.is_empty())
+        .map(|(name, description)| StoredEmbedding {
+            embedding: embed(&function_text(&name, &description)),
+            name,
+            description,
+        })
+        .collect();
+    let file = EmbeddingFile {
+        model: LOCAL_EMBED_MODEL.to_string(),
+        functions,
+    };
+
+    let bytes = rkyv::to_bytes::<_, 4096>(&file).expect("Failed to serialize local embeddings");
+    // Only write when something changed, so the file isn't touched on every build.
+    if fs::read(embed_path).ok().as_deref() != Some(&bytes[..]) {
+        fs::write(embed_path, &bytes[..]).expect("Failed to write local embeddings");
+    }
+}
+
+/// Warns about anything stale in the embeddings the `ToolSet` macro wrote, which are only made
+/// again when asked for, since that needs the network. They aren't there to check until the macro
+/// has made them once.
+fn check_func_enums_embeddings(commands_path: &str, embed_path: &PathBuf) {
+    let Ok(bytes) = fs::read(embed_path) else {
+        return;
+    };
+    let mut aligned = rkyv::AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(&bytes);
+    // The macro's file has the same layout as a list of `StoredEmbedding`s.
+    let Ok(stored) = rkyv::from_bytes::<Vec<StoredEmbedding>>(&aligned) else {
+        println!(
+            "cargo:warning={} isn't a valid embeddings file. Build with the \
+             compile_embeddings_all feature to make it again.",
+            embed_path.display()
+        );
+        return;
+    };
+    let stored: Vec<(String, String)> = stored
+        .into_iter()
+        .map(|function| (function.name, function.description))
+        .collect();
+
+    let source = fs::read_to_string(commands_path).expect("Failed to read the commands");
+    let staleness = Staleness::compare(&stored, &command_descriptions(&source));
+    if !staleness.is_empty() {
+        println!(
+            "cargo:warning={}",
+            staleness.describe(
+                &embed_path.display().to_string(),
+                "Build with the compile_embeddings_update feature to embed them again."
+            )
+        );
+    }
+}
+
 fn main() {
+    let commands_path = "src/cli/commands.rs";
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", commands_path);
+    println!("cargo:rerun-if-changed=src/cli/embedding_file.rs");
+    println!("cargo:rerun-if-changed=src/cli/local_embedding.rs");
+
     // This is the path to where embeddings will be stored.
     let relative_path = PathBuf::from("../embedding/function_embeddings.bin");
     let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
@@ -14,6 +127,8 @@ fn main() {
         }
     }
 
+    println!("cargo:rerun-if-changed={}", absolute_path.display());
+    check_func_enums_embeddings(commands_path, &absolute_path);
     println!(
         "cargo:warning=FUNC_ENUMS_EMBED_PATH set to: {}",
         absolute_path.display()
@@ -23,6 +138,10 @@ fn main() {
         absolute_path.display()
     );
 
+    let local_embed_path =
+        PathBuf::from(env::var("OUT_DIR").unwrap()).join("local_function_embeddings.bin");
+    write_local_embeddings(commands_path, &local_embed_path);
+
     let embedding_model = "text-embedding-3-small";
     println!(
         "cargo:warning=FUNC_ENUMS_EMBED_MODEL set to: {}",
@@ -50,7 +169,9 @@ fn main() {
         max_request_tokens
     );
 
-    let max_func_tokens = 500_u16;
+    // Enough for CallMultiStep, Search and the few settings a typical request needs to prepare
+    // it. Search and FileTypeFilter alone take up about half of this.
+    let max_func_tokens = 1000_u16;
     println!(
         "cargo:warning=FUNC_ENUMS_MAX_FUNC_TOKENS set to: {}",
         max_func_tokens
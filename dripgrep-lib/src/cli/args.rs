@@ -1,9 +1,11 @@
 use clap::ValueEnum;
-use openai_func_enums::{arg_description, EnumDescriptor, VariantDescriptors};
-use serde::Deserialize;
+use openai_func_enums::{EnumDescriptor, VariantDescriptors};
+use serde::{Deserialize, Serialize};
 
 // TODO: Need to implement similarity search/culling arg enums with too many allowed options.
-#[derive(Clone, Debug, Deserialize, EnumDescriptor, VariantDescriptors, ValueEnum)]
+#[derive(
+    Clone, Debug, Deserialize, EnumDescriptor, PartialEq, Serialize, VariantDescriptors, ValueEnum,
+)]
 #[arg_description(description = "The supported filters for file types.")]
 pub enum FileType {
     C,
@@ -73,7 +75,9 @@ impl FileType {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, EnumDescriptor, VariantDescriptors, ValueEnum)]
+#[derive(
+    Clone, Debug, Deserialize, EnumDescriptor, PartialEq, Serialize, VariantDescriptors, ValueEnum,
+)]
 #[arg_description(description = "The supported ways to sort search results.")]
 pub enum SortBy {
     None,
@@ -95,7 +99,9 @@ impl SortBy {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, EnumDescriptor, VariantDescriptors, ValueEnum)]
+#[derive(
+    Clone, Debug, Deserialize, EnumDescriptor, PartialEq, Serialize, VariantDescriptors, ValueEnum,
+)]
 #[arg_description(description = "The order in which to sort results.")]
 pub enum SortOrdering {
     Ascending,
@@ -103,14 +109,18 @@ pub enum SortOrdering {
 }
 
 // This is just a convenience. Right boolean arguments to functions aren't supported
-#[derive(Clone, Debug, Deserialize, EnumDescriptor, VariantDescriptors, ValueEnum)]
+#[derive(
+    Clone, Debug, Deserialize, EnumDescriptor, PartialEq, Serialize, VariantDescriptors, ValueEnum,
+)]
 #[arg_description(description = "Whether the setting should be enabled or not.")]
 pub enum Enabled {
     Yes,
     No,
 }
 
-#[derive(Clone, Debug, Deserialize, EnumDescriptor, VariantDescriptors, ValueEnum)]
+#[derive(
+    Clone, Debug, Deserialize, EnumDescriptor, PartialEq, Serialize, VariantDescriptors, ValueEnum,
+)]
 #[arg_description(description = "The ways to treat case sensitivity.")]
 pub enum CaseSensitivity {
     IgnoreCase,
@@ -118,7 +128,9 @@ pub enum CaseSensitivity {
     SmartCase,
 }
 
-#[derive(Clone, Debug, Deserialize, EnumDescriptor, VariantDescriptors, ValueEnum)]
+#[derive(
+    Clone, Debug, Deserialize, EnumDescriptor, PartialEq, Serialize, VariantDescriptors, ValueEnum,
+)]
 #[arg_description(
     description = "Specify search restrictions. Search in ignored, ignored and hidden, or ignored, hidden, and binary files."
 )]
@@ -129,7 +141,9 @@ pub enum Restriction {
     IncludeIgnoredHiddenBinary,
 }
 
-#[derive(Clone, Debug, Deserialize, EnumDescriptor, VariantDescriptors, ValueEnum)]
+#[derive(
+    Clone, Debug, Deserialize, EnumDescriptor, PartialEq, Serialize, VariantDescriptors, ValueEnum,
+)]
 #[arg_description(
     description = "Specify how file paths will print. Show files included in search, files with at least one match, or files with no matches."
 )]
@@ -138,3 +152,12 @@ pub enum FileDisplayMode {
     FilesWithMatch,
     FilesWithoutMatch,
 }
+
+#[derive(
+    Clone, Debug, Deserialize, EnumDescriptor, PartialEq, Serialize, VariantDescriptors, ValueEnum,
+)]
+#[arg_description(description = "Whether globs match file and directory names case sensitively.")]
+pub enum GlobCaseSensitivity {
+    CaseSensitive,
+    IgnoreCase,
+}
@@ -0,0 +1,224 @@
+use super::search_plan::SearchPlan;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// A search that has run: its pattern and the plan it ran with, paths and globs included.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LastSearch {
+    pub pattern: String,
+    pub plan: SearchPlan,
+}
+
+/// Everything that travels with a call: the search plan being built, and what the conversation
+/// knows besides the plan's settings.
+///
+/// `openai-func-enums` threads state between tool calls as an `Option<Vec<String>>`, so the
+/// context travels through that channel as a single serialized element. Use
+/// [`CallContext::from_arguments`] and [`CallContext::into_arguments`] at that boundary.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CallContext {
+    pub plan: SearchPlan,
+    /// The search that last ran with this context. A search starts the next plan over, so this
+    /// is how an interactive session finds out what was searched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_search: Option<Box<LastSearch>>,
+    /// The request the model was given, which nested plans show the model again when they check
+    /// in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<String>,
+    /// The prompt lists of the multi-step plans this call is running inside, outermost first,
+    /// so a nested plan can tell how deep it is and whether it repeats one of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plans_running: Vec<Vec<String>>,
+}
+
+impl CallContext {
+    /// A context with nothing in it but `plan`.
+    pub fn new(plan: SearchPlan) -> CallContext {
+        CallContext {
+            plan,
+            ..CallContext::default()
+        }
+    }
+
+    /// Recovers the context from the argument channel used by `RunCommand`. A missing or empty
+    /// channel is an empty context.
+    pub fn from_arguments(
+        arguments: Option<Vec<String>>,
+    ) -> Result<CallContext, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        match arguments.as_deref() {
+            None | Some([]) => Ok(CallContext::default()),
+            Some([serialized]) => Ok(serde_json::from_str(serialized)?),
+            Some(_) => Err(Box::new(openai_func_enums::CommandError::new(
+                "Expected a single serialized call context in the argument list.",
+            ))),
+        }
+    }
+
+    /// Packs the context back into the argument channel used by `RunCommand`.
+    pub fn into_arguments(self) -> Option<Vec<String>> {
+        serde_json::to_string(&self)
+            .ok()
+            .map(|context| vec![context])
+    }
+
+    /// The context to carry on from once the plan has searched for `pattern`: an empty plan,
+    /// with the search recorded in [`CallContext::last_search`].
+    pub fn searched(self, pattern: &str) -> CallContext {
+        CallContext {
+            plan: SearchPlan::default(),
+            last_search: Some(Box::new(LastSearch {
+                pattern: pattern.to_string(),
+                plan: self.plan,
+            })),
+            request: self.request,
+            plans_running: self.plans_running,
+        }
+    }
+}
+
+/// The argument channel after a call that started from `base` and ended with `result`, when
+/// `current` is what other calls have left in it since. Only what the call changed is taken from
+/// `result`, so calls that run at the same time keep each other's changes, and a setting both
+/// changed ends up as whichever finished last left it.
+pub fn merge_arguments(
+    current: Option<Vec<String>>,
+    base: &Option<Vec<String>>,
+    result: Option<Vec<String>>,
+) -> Option<Vec<String>> {
+    let parse = |arguments: &Option<Vec<String>>| match arguments.as_deref() {
+        None | Some([]) => Some(Value::Object(Map::new())),
+        Some([serialized]) => serde_json::from_str::<Value>(serialized).ok(),
+        Some(_) => None,
+    };
+    let (Some(mut merged), Some(base_value), Some(result_value)) =
+        (parse(&current), parse(base), parse(&result))
+    else {
+        return result;
+    };
+    merge_changes(&mut merged, &base_value, &result_value);
+    serde_json::to_string(&merged)
+        .ok()
+        .map(|merged| vec![merged])
+}
+
+fn merge_changes(current: &mut Value, base: &Value, result: &Value) {
+    match (current, base, result) {
+        (Value::Object(current), Value::Object(base), Value::Object(result)) => {
+            for key in base.keys().chain(result.keys()) {
+                match (base.get(key), result.get(key)) {
+                    (Some(before), Some(after)) if before == after => {}
+                    (_, None) => {
+                        current.remove(key);
+                    }
+                    (before, Some(after)) => match current.get_mut(key) {
+                        Some(value) => merge_changes(value, before.unwrap_or(&Value::Null), after),
+                        None => {
+                            current.insert(key.clone(), after.clone());
+                        }
+                    },
+                }
+            }
+        }
+        (current, _, result) => *current = result.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::args::{CaseSensitivity, FileType};
+
+    #[test]
+    fn empty_channel_is_empty_context() {
+        let context = CallContext::from_arguments(None).unwrap();
+        assert!(context.plan.to_args().is_empty());
+        let context = CallContext::from_arguments(Some(Vec::new())).unwrap();
+        assert!(context.plan.to_args().is_empty());
+        assert!(context.request.is_none());
+    }
+
+    #[test]
+    fn channel_with_several_elements_is_rejected() {
+        let arguments = Some(vec![String::from("{}"), String::from("{}")]);
+        assert!(CallContext::from_arguments(arguments).is_err());
+    }
+
+    #[test]
+    fn context_survives_the_argument_channel() {
+        let mut plan = SearchPlan {
+            case_sensitivity: Some(CaseSensitivity::IgnoreCase),
+            paths: vec![String::from("src")],
+            ..SearchPlan::default()
+        };
+        plan.set_file_type(FileType::Rust, true);
+        let context = CallContext {
+            request: Some(String::from("find TODOs")),
+            plans_running: vec![vec![String::from("step")]],
+            ..CallContext::new(plan)
+        };
+
+        let restored = CallContext::from_arguments(context.clone().into_arguments()).unwrap();
+        assert_eq!(restored.plan.to_args(), context.plan.to_args());
+        assert_eq!(restored.request, context.request);
+        assert_eq!(restored.plans_running, context.plans_running);
+    }
+
+    #[test]
+    fn searching_starts_the_next_plan_over() {
+        let context = CallContext {
+            request: Some(String::from("count matches")),
+            plans_running: vec![vec![String::from("step")]],
+            ..CallContext::new(SearchPlan {
+                statistics: Some(true),
+                ..SearchPlan::default()
+            })
+        };
+
+        let next = context.searched("needle");
+        assert!(next.plan.to_args().is_empty());
+        assert_eq!(next.request.as_deref(), Some("count matches"));
+        assert_eq!(next.plans_running.len(), 1);
+
+        let last = next.last_search.unwrap();
+        assert_eq!(last.pattern, "needle");
+        assert_eq!(last.plan.to_args(), ["--stats"]);
+    }
+
+    #[test]
+    fn merging_keeps_changes_made_at_the_same_time() {
+        let base = CallContext::default().into_arguments();
+
+        let mut first = CallContext::default();
+        first.plan.statistics = Some(true);
+        let mut second = CallContext::default();
+        second.plan.set_file_type(FileType::Rust, true);
+
+        let current = merge_arguments(base.clone(), &base, first.into_arguments());
+        let current = merge_arguments(current, &base, second.into_arguments());
+        let merged = CallContext::from_arguments(current).unwrap();
+        assert_eq!(merged.plan.to_args(), ["--type", "rust", "--stats"]);
+    }
+
+    #[test]
+    fn merging_leaves_settings_a_call_did_not_change() {
+        let mut start = CallContext::default();
+        start.plan.statistics = Some(true);
+        let base = start.clone().into_arguments();
+
+        let mut changed = start.clone();
+        changed.plan.statistics = Some(false);
+        let current = merge_arguments(base.clone(), &base, changed.into_arguments());
+
+        let mut unchanged = start;
+        unchanged.plan.case_sensitivity = Some(CaseSensitivity::IgnoreCase);
+        let current = merge_arguments(current, &base, unchanged.into_arguments());
+
+        let merged = CallContext::from_arguments(current).unwrap();
+        assert_eq!(merged.plan.statistics, Some(false));
+        assert_eq!(
+            merged.plan.case_sensitivity,
+            Some(CaseSensitivity::IgnoreCase)
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use super::check_in::StepReport;
+use super::run_context::run_context;
+use super::usage::cost_refused;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// How many multi-step plans can run inside each other unless `--max-plan-depth` says
+/// otherwise. The plan `dripgrep gpt` starts is the first.
+pub const DEFAULT_MAX_PLAN_DEPTH: usize = 3;
+
+/// How many chat completion requests one request can make unless `--max-model-calls` says
+/// otherwise.
+pub const DEFAULT_MAX_MODEL_CALLS: usize = 60;
+
+/// The most of each step's result an abort message shows.
+const ABORT_RESULT_MAX_CHARS: usize = 200;
+
+static MODEL_CALLS: AtomicUsize = AtomicUsize::new(0);
+static ANSWERED: AtomicBool = AtomicBool::new(false);
+
+/// A model call that wasn't made, because the model call budget or the cost budget would have
+/// been exceeded. A plan that runs into this stops and reports what it finished.
+#[derive(Debug)]
+pub struct BudgetSpent {
+    pub message: String,
+}
+
+impl fmt::Display for BudgetSpent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for BudgetSpent {}
+
+/// Whether `error`, or anything it was caused by, is [`BudgetSpent`].
+pub fn is_budget_spent(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut error = Some(error);
+    while let Some(current) = error {
+        if current.is::<BudgetSpent>() {
+            return true;
+        }
+        error = current.source();
+    }
+    false
+}
+
+/// Starts counting model calls from zero, for a new request in the same process.
+pub fn reset_model_calls() {
+    MODEL_CALLS.store(0, Ordering::SeqCst);
+    ANSWERED.store(false, Ordering::SeqCst);
+}
+
+/// Notes that the model has answered a request for what to do, so the request is under way.
+pub fn record_answer() {
+    ANSWERED.store(true, Ordering::SeqCst);
+}
+
+/// Whether the model has answered a request for what to do since the request started. Until it
+/// has, nothing has been done, and the request can still be planned again without it.
+pub fn model_has_answered() -> bool {
+    ANSWERED.load(Ordering::SeqCst)
+}
+
+/// Whether the model call budget has been spent.
+pub fn model_calls_spent() -> bool {
+    MODEL_CALLS.load(Ordering::SeqCst) >= run_context().max_model_calls
+}
+
+/// Counts a model call against the budget, or refuses it if the budget has been spent.
+pub fn take_model_call() -> Result<(), BudgetSpent> {
+    let budget = run_context().max_model_calls;
+    MODEL_CALLS
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |calls| {
+            (calls < budget).then_some(calls + 1)
+        })
+        .map(|_| ())
+        .map_err(|_| BudgetSpent {
+            message: format!("Not asking the model, because {}.", spent()),
+        })
+}
+
+/// Says that the model call budget has been spent, to finish a sentence.
+pub fn spent() -> String {
+    format!(
+        "the budget of {} model calls for this request has been spent",
+        run_context().max_model_calls
+    )
+}
+
+/// Why no more model calls can be made, if they can't: the call budget is spent, or a call has
+/// been refused for the cost budget.
+pub fn budget_spent() -> Option<String> {
+    if model_calls_spent() {
+        Some(spent())
+    } else {
+        cost_refused()
+    }
+}
+
+/// Why `prompt_list` shouldn't run inside `plans_running`, if it shouldn't: it would be nested
+/// deeper than `--max-plan-depth` allows, it's the same as a plan it's running inside, or it hands
+/// back a step one of those plans already has, which is how a step ends up delegating itself
+/// over and over.
+pub fn refuse_plan(plans_running: &[Vec<String>], prompt_list: &[String]) -> Option<String> {
+    if plans_running.len() >= run_context().max_plan_depth {
+        return Some(format!(
+            "this plan would be nested {} deep, and plans can only be nested {} deep",
+            plans_running.len() + 1,
+            run_context().max_plan_depth
+        ));
+    }
+
+    let same = |a: &str, b: &str| a.trim().eq_ignore_ascii_case(b.trim());
+    for (depth, running) in plans_running.iter().enumerate() {
+        if running.len() == prompt_list.len()
+            && running.iter().zip(prompt_list).all(|(a, b)| same(a, b))
+        {
+            return Some(format!(
+                "this plan is the same as the one {} level{} up",
+                plans_running.len() - depth,
+                if plans_running.len() - depth == 1 {
+                    ""
+                } else {
+                    "s"
+                }
+            ));
+        }
+        for prompt in prompt_list {
+            if running.iter().any(|step| same(step, prompt)) {
+                return Some(format!(
+                    "\"{}\" is already a step of a plan this one is running inside",
+                    prompt
+                ));
+            }
+        }
+    }
+
+    None
+}
+
+/// Explains why a plan was abandoned, and what it had done by then.
+pub fn describe_abort(reason: &str, done: &[StepReport<'_>]) -> String {
+    let mut description = format!("Stopped, because {}.", reason);
+    if done.is_empty() {
+        description.push_str(" None of its steps had run.");
+        return description;
+    }
+
+    description.push_str(" Done so far:");
+    for step in done {
+        let result = step.result.unwrap_or("(no result)");
+        let result = match result.char_indices().nth(ABORT_RESULT_MAX_CHARS) {
+            Some((end, _)) => format!("{}...", &result[..end]),
+            None => result.to_string(),
+        };
+        description.push_str(&format!(
+            "\n- Step {}, \"{}\": {}",
+            step.number, step.prompt, result
+        ));
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(prompts: &[&str]) -> Vec<String> {
+        prompts.iter().map(|prompt| prompt.to_string()).collect()
+    }
+
+    #[test]
+    fn plans_can_only_be_nested_so_deep() {
+        let running: Vec<Vec<String>> = (0..DEFAULT_MAX_PLAN_DEPTH)
+            .map(|depth| steps(&[&format!("Step of plan {}", depth)]))
+            .collect();
+        let refusal = refuse_plan(&running, &steps(&["Something new"])).unwrap();
+        assert!(refusal.contains("nested 4 deep"), "{}", refusal);
+
+        assert_eq!(refuse_plan(&running[1..], &steps(&["Something new"])), None);
+    }
+
+    #[test]
+    fn a_plan_cant_run_inside_itself() {
+        let running = vec![
+            steps(&["Ignore case.", "Search for TODO."]),
+            steps(&["Only search Rust files."]),
+        ];
+        let refusal = refuse_plan(&running, &steps(&[" ignore case.", "search for todo. "]));
+        assert_eq!(
+            refusal.as_deref(),
+            Some("this plan is the same as the one 2 levels up")
+        );
+    }
+
+    #[test]
+    fn a_plan_cant_hand_back_a_step_it_runs_inside() {
+        let running = vec![steps(&["Ignore case.", "Search for TODO."])];
+        let refusal = refuse_plan(
+            &running,
+            &steps(&["Only search Rust files.", "Search for TODO."]),
+        );
+        assert_eq!(
+            refusal.as_deref(),
+            Some("\"Search for TODO.\" is already a step of a plan this one is running inside")
+        );
+    }
+
+    #[test]
+    fn budget_errors_are_found_behind_other_errors() {
+        #[derive(Debug)]
+        struct Wrapper(BudgetSpent);
+
+        impl fmt::Display for Wrapper {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "The step failed")
+            }
+        }
+
+        impl std::error::Error for Wrapper {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let spent = Wrapper(BudgetSpent {
+            message: String::from("Not asking the model."),
+        });
+        assert!(is_budget_spent(&spent));
+        assert!(!is_budget_spent(&fmt::Error));
+    }
+
+    #[test]
+    fn aborts_say_what_was_done() {
+        assert_eq!(
+            describe_abort("the plan went wrong", &[]),
+            "Stopped, because the plan went wrong. None of its steps had run."
+        );
+
+        let long = "x".repeat(ABORT_RESULT_MAX_CHARS + 1);
+        let done = [
+            StepReport {
+                number: 1,
+                prompt: "Ignore case.",
+                result: Some("Ok."),
+            },
+            StepReport {
+                number: 2,
+                prompt: "Search for TODO.",
+                result: Some(&long),
+            },
+        ];
+        assert_eq!(
+            describe_abort("the plan went wrong", &done),
+            format!(
+                "Stopped, because the plan went wrong. Done so far:\n- Step 1, \"Ignore case.\": \
+                 Ok.\n- Step 2, \"Search for TODO.\": {}...",
+                "x".repeat(ABORT_RESULT_MAX_CHARS)
+            )
+        );
+    }
+}
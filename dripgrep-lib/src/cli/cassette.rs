@@ -0,0 +1,275 @@
+use openai_func_enums::CommandError;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use tokio::sync::Mutex;
+
+static CASSETTE: OnceLock<Cassette> = OnceLock::new();
+
+/// Makes `cassette` the one every chat completion, embedding and search goes through.
+pub fn set_cassette(cassette: Cassette) {
+    let _ = CASSETTE.set(cassette);
+}
+
+/// The cassette set with [`set_cassette`], if there is one.
+pub fn cassette() -> Option<&'static Cassette> {
+    CASSETTE.get()
+}
+
+/// A single recorded interaction. `kind` is `chat`, `embedding` or `search`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CassetteEntry {
+    pub kind: String,
+    pub request: Value,
+    pub response: Value,
+}
+
+enum Mode {
+    Record {
+        dir: PathBuf,
+        next_index: AtomicUsize,
+    },
+    Replay {
+        /// Each recorded entry with the name of its file, until a request uses it.
+        entries: Mutex<Vec<Option<(String, CassetteEntry)>>>,
+    },
+}
+
+/// Records the traffic of a session to a directory, or replays a recorded session from one.
+///
+/// Each interaction is stored as its own numbered JSON file holding the request and the response.
+/// On replay, a request is answered with the first unused recorded entry whose request is
+/// identical. Requests are matched by content rather than position, so tool calls that run
+/// concurrently can't put a replay out of step, but any change to what dripgrep would have sent
+/// (a different tool set, a different prompt, different ripgrep arguments) is an error, and so is
+/// a recorded request that's never sent, which [`Cassette::finish`] reports.
+pub struct Cassette {
+    mode: Mode,
+}
+
+impl Cassette {
+    /// Starts recording into `dir`, creating it if needed. A directory that already has something
+    /// in it is refused, since a replay would mix whatever was there into this recording.
+    pub fn record(
+        dir: &Path,
+    ) -> Result<Cassette, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        std::fs::create_dir_all(dir)?;
+        if std::fs::read_dir(dir)?.next().is_some() {
+            return Err(Box::new(CommandError::new(&format!(
+                "{} isn't empty. Record into an empty directory, so nothing from an earlier \
+                 recording ends up in this one.",
+                dir.display()
+            ))));
+        }
+        Ok(Cassette {
+            mode: Mode::Record {
+                dir: dir.to_path_buf(),
+                next_index: AtomicUsize::new(0),
+            },
+        })
+    }
+
+    /// Loads every entry recorded in `dir` for replay.
+    pub fn replay(
+        dir: &Path,
+    ) -> Result<Cassette, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+
+        let mut entries = Vec::new();
+        for path in paths {
+            let contents = std::fs::read_to_string(&path)?;
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            entries.push(Some((
+                name,
+                serde_json::from_str::<CassetteEntry>(&contents)?,
+            )));
+        }
+
+        Ok(Cassette {
+            mode: Mode::Replay {
+                entries: Mutex::new(entries),
+            },
+        })
+    }
+
+    /// Runs `live` and records what it returned, or, when replaying, returns the recorded
+    /// response for `request` without running `live` at all.
+    pub async fn exchange<Req, Resp, F, Fut>(
+        &self,
+        kind: &str,
+        request: &Req,
+        live: F,
+    ) -> Result<Resp, Box<dyn std::error::Error + Send + Sync + 'static>>
+    where
+        Req: Serialize,
+        Resp: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Resp, Box<dyn std::error::Error + Send + Sync + 'static>>>,
+    {
+        let request = serde_json::to_value(request)?;
+
+        match &self.mode {
+            Mode::Record { dir, next_index } => {
+                let response = live().await?;
+                let entry = CassetteEntry {
+                    kind: kind.to_string(),
+                    request,
+                    response: serde_json::to_value(&response)?,
+                };
+
+                let index = next_index.fetch_add(1, Ordering::SeqCst);
+                let path = dir.join(format!("{:04}-{}.json", index, kind));
+                tokio::fs::write(path, serde_json::to_string_pretty(&entry)?).await?;
+
+                Ok(response)
+            }
+
+            Mode::Replay { entries } => {
+                let mut entries = entries.lock().await;
+                let position = entries.iter().position(|entry| {
+                    entry
+                        .as_ref()
+                        .is_some_and(|(_, entry)| entry.kind == kind && entry.request == request)
+                });
+
+                match position.and_then(|position| entries[position].take()) {
+                    Some((_, entry)) => Ok(serde_json::from_value(entry.response)?),
+                    None => Err(Box::new(CommandError::new(&format!(
+                        "The cassette has no unused {} request matching: {}",
+                        kind, request
+                    )))),
+                }
+            }
+        }
+    }
+
+    /// Fails a replay that left recorded requests unused, since the run didn't do everything the
+    /// recorded one did. A recording has nothing to check.
+    pub async fn finish(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let Mode::Replay { entries } = &self.mode else {
+            return Ok(());
+        };
+        let unused: Vec<String> = entries
+            .lock()
+            .await
+            .iter()
+            .flatten()
+            .map(|(name, _)| name.clone())
+            .collect();
+        if unused.is_empty() {
+            return Ok(());
+        }
+        Err(Box::new(CommandError::new(&format!(
+            "The replay never sent {} of the recorded requests: {}",
+            unused.len(),
+            unused.join(", ")
+        ))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for `test`.
+    fn empty_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("dripgrep-cassette-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    async fn record_search(cassette: &Cassette, args: &[&str], output: &str) -> String {
+        let output = output.to_string();
+        cassette
+            .exchange("search", &args, || async { Ok(output) })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn replay_answers_with_what_was_recorded() {
+        let dir = empty_dir("replay");
+        let recording = Cassette::record(&dir).unwrap();
+        record_search(&recording, &["-e", "first"], "first matches").await;
+        record_search(&recording, &["-e", "second"], "second matches").await;
+        recording.finish().await.unwrap();
+
+        let replay = Cassette::replay(&dir).unwrap();
+        // Only the request decides the answer, so the searches can come back in any order, and
+        // nothing is searched again.
+        let second: String = replay
+            .exchange("search", &["-e", "second"], || async {
+                panic!("a replay searched")
+            })
+            .await
+            .unwrap();
+        assert_eq!(second, "second matches");
+        let first: String = replay
+            .exchange("search", &["-e", "first"], || async {
+                panic!("a replay searched")
+            })
+            .await
+            .unwrap();
+        assert_eq!(first, "first matches");
+        replay.finish().await.unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn replay_fails_on_requests_that_were_not_recorded() {
+        let dir = empty_dir("unrecorded");
+        let recording = Cassette::record(&dir).unwrap();
+        record_search(&recording, &["-e", "first"], "first matches").await;
+
+        let replay = Cassette::replay(&dir).unwrap();
+        let result: Result<String, _> = replay
+            .exchange("search", &["-e", "other"], || async {
+                panic!("a replay searched")
+            })
+            .await;
+        assert!(result.is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn replay_that_leaves_entries_unused_fails() {
+        let dir = empty_dir("unused");
+        let recording = Cassette::record(&dir).unwrap();
+        record_search(&recording, &["-e", "first"], "first matches").await;
+        record_search(&recording, &["-e", "second"], "second matches").await;
+
+        let replay = Cassette::replay(&dir).unwrap();
+        record_search(&replay, &["-e", "first"], "unused").await;
+        let error = replay.finish().await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The replay never sent 1 of the recorded requests: 0001-search.json"
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn recording_into_a_directory_with_something_in_it_is_refused() {
+        let dir = empty_dir("not-empty");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("0007-chat.json"), "{}").unwrap();
+
+        assert!(Cassette::record(&dir).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,461 @@
+use super::call_context::{CallContext, LastSearch};
+use super::call_guard::{model_has_answered, reset_model_calls};
+use super::command_funcs::{gpt_with_functions, rank_functions};
+use super::config::config;
+use super::context_refresh::{classify, ContextChange};
+use super::dry_run::{command_line, shell_quote};
+use super::offline_planner::{offline, run_offline, set_offline};
+use super::provider::is_unreachable;
+use super::search_plan::SearchPlan;
+use openai_func_enums::{logger_task, Logger, ToolCallExecutionStrategy};
+use std::io::Write;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+
+/// The most of the system message a chat will spend on earlier requests and their results.
+pub const CHAT_CONTEXT_MAX_TOKENS: usize = 1500;
+
+/// One request made during a chat, and the result it ended with.
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    pub prompt: String,
+    pub result: String,
+}
+
+/// Everything a chat carries from one request to the next.
+///
+/// Each request starts from the settings the last search ran with, so a follow-up like "now only
+/// in Rust files" only has to change what's different, unless it turns out to be about something
+/// else entirely. The model also sees the earlier requests,
+/// the last search and its results, within [`CHAT_CONTEXT_MAX_TOKENS`].
+#[derive(Clone, Debug, Default)]
+pub struct ChatSession {
+    pub history: Vec<Exchange>,
+    pub plan: SearchPlan,
+    pub last_search: Option<LastSearch>,
+    /// The function ranking the model was offered tools from for the last request.
+    pub functions: Vec<String>,
+}
+
+impl ChatSession {
+    /// Runs `prompt` the same way `dripgrep gpt` would, with what came before in the system
+    /// message, and returns its result.
+    ///
+    /// Once there's something to follow on from, the request is classified first. A new subject
+    /// starts the session over and ranks functions for the new prompt alone. A deeper follow-up
+    /// keeps the settings and offers the functions ranked for this prompt alongside the ones
+    /// offered before. Anything else reuses the functions offered last time as they were.
+    ///
+    /// Like `dripgrep gpt`, a request is planned without the model when `--offline` is on or the
+    /// model can't be reached before it has answered, and a follow-up then always keeps the
+    /// settings. The next request tries the model again.
+    pub async fn send(
+        &mut self,
+        prompt: &str,
+        execution_strategy: ToolCallExecutionStrategy,
+        logger: Arc<Logger>,
+        system_message: Option<(String, usize)>,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        // The model call budget is for each request, not the whole session, and a request that
+        // couldn't reach the model doesn't stop the next one from trying.
+        reset_model_calls();
+        set_offline(false);
+
+        let change = if self.history.is_empty() {
+            ContextChange::NewSubject
+        } else if offline() {
+            ContextChange::DeeperFollowUp
+        } else {
+            match classify(&config().model, &self.context()?, prompt).await {
+                Ok(change) => change,
+                Err(e) if is_unreachable(&*e) && !model_has_answered() => {
+                    plan_without_model(&logger, &*e).await;
+                    ContextChange::DeeperFollowUp
+                }
+                // Keeping everything and adding to it is the choice least likely to lose
+                // something the request needed.
+                Err(e) => {
+                    logger
+                        .log(format!("Couldn't classify the request: {}", e))
+                        .await;
+                    ContextChange::DeeperFollowUp
+                }
+            }
+        };
+        if !self.history.is_empty() {
+            logger.log(format!("\nTreating this as {}.", change)).await;
+        }
+
+        match change {
+            ContextChange::NewSubject => {
+                *self = ChatSession {
+                    functions: ranked_functions(prompt, &logger).await?,
+                    ..ChatSession::default()
+                };
+            }
+            ContextChange::DeeperFollowUp => {
+                let ranked = ranked_functions(prompt, &logger).await?;
+                self.functions = interleave(&ranked, &self.functions);
+            }
+            ContextChange::NoChange => {}
+        }
+
+        let system_message = Some(self.system_message(system_message)?);
+        let arguments = CallContext::new(self.plan.clone()).into_arguments();
+        let (result, arguments) = if offline() {
+            run_offline(
+                execution_strategy,
+                logger,
+                system_message,
+                prompt,
+                arguments,
+            )
+            .await?
+        } else {
+            match gpt_with_functions(
+                execution_strategy.clone(),
+                logger.clone(),
+                &config().model,
+                system_message.clone(),
+                &prompt.to_string(),
+                arguments.clone(),
+                self.functions.clone(),
+            )
+            .await
+            {
+                // Nothing has been done yet if the model hasn't answered anything, so the request
+                // can be planned again from the start.
+                Err(e) if is_unreachable(&*e) && !model_has_answered() => {
+                    plan_without_model(&logger, &*e).await;
+                    run_offline(
+                        execution_strategy,
+                        logger,
+                        system_message,
+                        prompt,
+                        arguments,
+                    )
+                    .await?
+                }
+                result => result?,
+            }
+        };
+
+        let context = CallContext::from_arguments(arguments)?;
+        self.plan = match context.last_search {
+            Some(last_search) => {
+                let settings = last_search.plan.settings();
+                self.last_search = Some(*last_search);
+                settings
+            }
+            None => context.plan,
+        };
+
+        let result = result.unwrap_or_else(|| String::from("Ok."));
+        self.history.push(Exchange {
+            prompt: prompt.to_string(),
+            result: result.clone(),
+        });
+        Ok(result)
+    }
+
+    /// `system_message` with the session so far added to the end, and its token count.
+    fn system_message(
+        &self,
+        system_message: Option<(String, usize)>,
+    ) -> Result<(String, usize), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let (message, tokens) = system_message
+            .unwrap_or_else(|| (String::from("You are a helpful function calling bot."), 7));
+
+        let context = self.context()?;
+        if context.is_empty() {
+            return Ok((message, tokens));
+        }
+
+        let context_tokens = tiktoken_rs::cl100k_base()?.encode_ordinary(&context).len();
+        Ok((
+            format!("{}\n\n{}", message, context),
+            tokens + context_tokens + 1,
+        ))
+    }
+
+    /// Describes the session so far for the model. The settings and the last search always fit;
+    /// earlier requests get a quarter of what's left, newest first, and the last results get the
+    /// rest.
+    fn context(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        if self.history.is_empty() {
+            return Ok(String::new());
+        }
+
+        let bpe = tiktoken_rs::cl100k_base()?;
+        let tokens = |text: &str| bpe.encode_ordinary(text).len();
+
+        let mut context = String::from(
+            "This is an interactive session. Treat the next request as a follow-up when it refers \
+             to earlier ones, repeating the last search's pattern and paths with whatever it asks \
+             to change.\n",
+        );
+
+        let settings: Vec<String> = self
+            .plan
+            .to_args()
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect();
+        if !settings.is_empty() {
+            context.push_str(&format!(
+                "These settings from earlier requests are already applied: {}\n",
+                settings.join(" ")
+            ));
+        }
+        if let Some(last_search) = &self.last_search {
+            context.push_str(&format!(
+                "The last search ran: {}\n",
+                command_line(&last_search.plan, &last_search.pattern)
+            ));
+        }
+
+        let requests_heading = "Requests so far, oldest first:\n";
+        let result_heading = "The last request ended with:\n";
+        let mut budget = CHAT_CONTEXT_MAX_TOKENS
+            .saturating_sub(tokens(&context) + tokens(requests_heading) + tokens(result_heading));
+
+        let (last, earlier) = self.history.split_last().expect("history isn't empty");
+        let mut requests = Vec::new();
+        let mut request_budget = budget / 4;
+        for exchange in earlier.iter().rev() {
+            let request = format!("- {}\n", exchange.prompt);
+            let request_tokens = tokens(&request);
+            if request_tokens > request_budget {
+                break;
+            }
+            request_budget -= request_tokens;
+            budget -= request_tokens;
+            requests.push(request);
+        }
+        let last_request = format!("- {}\n", last.prompt);
+        budget = budget.saturating_sub(tokens(&last_request));
+        requests.reverse();
+        requests.push(last_request);
+
+        context.push_str(requests_heading);
+        context.push_str(&requests.concat());
+
+        context.push_str(result_heading);
+        let lines: Vec<(String, usize)> = last
+            .result
+            .lines()
+            .map(|line| {
+                let line = format!("{}\n", line);
+                let line_tokens = tokens(&line);
+                (line, line_tokens)
+            })
+            .collect();
+        let truncated =
+            |shown: usize| format!("(Truncated: showing {} of {} lines.)\n", shown, lines.len());
+        // Room is kept for saying the result was cut short, if it's going to be.
+        if lines
+            .iter()
+            .map(|(_, line_tokens)| line_tokens)
+            .sum::<usize>()
+            > budget
+        {
+            budget = budget.saturating_sub(tokens(&truncated(lines.len())));
+        }
+        for (shown, (line, line_tokens)) in lines.iter().enumerate() {
+            if *line_tokens > budget {
+                context.push_str(&truncated(shown));
+                break;
+            }
+            budget -= line_tokens;
+            context.push_str(line);
+        }
+
+        Ok(context)
+    }
+}
+
+/// Ranks the functions for `prompt`, or ranks none once requests are planned without the model,
+/// since the offline planner ranks them itself.
+async fn ranked_functions(
+    prompt: &str,
+    logger: &Logger,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    if offline() {
+        return Ok(Vec::new());
+    }
+    match rank_functions(prompt).await {
+        Err(e) if is_unreachable(&*e) && !model_has_answered() => {
+            plan_without_model(logger, &*e).await;
+            Ok(Vec::new())
+        }
+        result => result,
+    }
+}
+
+/// Says the model couldn't be reached and plans the rest of this request without it.
+async fn plan_without_model(logger: &Logger, error: &(dyn std::error::Error + 'static)) {
+    logger
+        .log(format!(
+            "Couldn't reach the model, so planning without it: {}",
+            error
+        ))
+        .await;
+    set_offline(true);
+}
+
+/// Merges two function rankings by taking from each in turn, so the top of both gets offered
+/// before the tail of either.
+fn interleave(first: &[String], second: &[String]) -> Vec<String> {
+    let mut merged: Vec<String> = Vec::with_capacity(first.len().max(second.len()));
+    for index in 0..first.len().max(second.len()) {
+        for ranking in [first, second] {
+            if let Some(name) = ranking.get(index) {
+                if !merged.contains(name) {
+                    merged.push(name.clone());
+                }
+            }
+        }
+    }
+    merged
+}
+
+/// Reads requests from stdin until it ends or the user types `exit`, running each one in the same
+/// [`ChatSession`]. `/reset` starts a new session.
+///
+/// Each line gets a logger of its own, and the next prompt isn't shown until everything logged
+/// through it has been printed.
+pub async fn chat(
+    execution_strategy: ToolCallExecutionStrategy,
+    system_message: Option<(String, usize)>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut session = ChatSession::default();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+
+        let line = match lines.next_line().await? {
+            Some(line) => line,
+            None => {
+                println!();
+                return Ok(());
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel(100);
+        let printer = tokio::spawn(logger_task(receiver));
+        let logger = Arc::new(Logger { sender });
+
+        match line.trim() {
+            "" => {}
+            "exit" | "quit" => return Ok(()),
+            "/reset" => {
+                session = ChatSession::default();
+                logger.log(String::from("Started a new session.")).await;
+            }
+            prompt => {
+                // A request that fails shouldn't end the session, so it's reported and forgotten.
+                if let Err(e) = session
+                    .send(
+                        prompt,
+                        execution_strategy.clone(),
+                        logger.clone(),
+                        system_message.clone(),
+                    )
+                    .await
+                {
+                    logger.log(format!("Request failed: {}", e)).await;
+                }
+            }
+        }
+
+        drop(logger);
+        let _ = printer.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn exchange(prompt: &str, result: &str) -> Exchange {
+        Exchange {
+            prompt: prompt.to_string(),
+            result: result.to_string(),
+        }
+    }
+
+    #[test]
+    fn rankings_are_taken_from_in_turn() {
+        assert_eq!(
+            interleave(
+                &names(&["Search", "CaseFilter", "Threads"]),
+                &names(&["FileTypeFilter", "Search"])
+            ),
+            names(&["Search", "FileTypeFilter", "CaseFilter", "Threads"])
+        );
+        assert_eq!(interleave(&[], &names(&["Search"])), names(&["Search"]));
+    }
+
+    #[test]
+    fn a_new_session_has_no_context() {
+        assert_eq!(ChatSession::default().context().unwrap(), "");
+    }
+
+    #[test]
+    fn context_shows_the_settings_requests_and_last_result() {
+        let mut session = ChatSession::default();
+        session.plan.statistics = Some(true);
+        session.history = vec![
+            exchange("find TODO", "3 matches"),
+            exchange("only in Rust files", "1 match\nsrc/main.rs"),
+        ];
+
+        let context = session.context().unwrap();
+        assert!(
+            context.contains("already applied: --stats\n"),
+            "{}",
+            context
+        );
+        assert!(
+            context.ends_with(
+                "Requests so far, oldest first:\n- find TODO\n- only in Rust files\nThe last \
+                 request ended with:\n1 match\nsrc/main.rs\n"
+            ),
+            "{}",
+            context
+        );
+    }
+
+    #[test]
+    fn context_stays_within_its_budget() {
+        let mut session = ChatSession {
+            history: (0..200)
+                .map(|index| exchange(&format!("request number {}", index), "Ok."))
+                .collect(),
+            ..ChatSession::default()
+        };
+        let result: Vec<String> = (0..1000)
+            .map(|index| format!("src/file_{}.rs:1:match", index))
+            .collect();
+        session
+            .history
+            .push(exchange("find match", &result.join("\n")));
+
+        let context = session.context().unwrap();
+        let bpe = tiktoken_rs::cl100k_base().unwrap();
+        assert!(bpe.encode_ordinary(&context).len() <= CHAT_CONTEXT_MAX_TOKENS);
+
+        // The oldest requests are the ones left out, and the last one always stays.
+        assert!(!context.contains("- request number 0\n"));
+        assert!(context.contains("- request number 199\n- find match\n"));
+        assert!(context.contains("src/file_0.rs:1:match\n"));
+        assert!(context.contains(" of 1000 lines.)\n"), "{}", context);
+    }
+}
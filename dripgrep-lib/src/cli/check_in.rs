@@ -0,0 +1,200 @@
+use super::model_client::call_function;
+use serde::Deserialize;
+use serde_json::json;
+
+/// How many steps a plan runs between check-ins unless `--check-in-every` says otherwise.
+pub const DEFAULT_CHECK_IN_EVERY: usize = 5;
+
+/// The most of each completed step's result a check-in shows the model.
+const CHECK_IN_RESULT_MAX_CHARS: usize = 600;
+
+/// The only function offered when checking in, so the model has to answer with it.
+const CHECK_IN_FUNCTION: &str = "CheckIn";
+
+/// What the model decided to do with the rest of a plan.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CheckInDecision {
+    /// Run the remaining steps as planned.
+    Continue,
+    /// Replace the remaining steps with these, run one after another.
+    Revise(Vec<String>),
+    /// Don't run anything else, for this reason.
+    Stop(String),
+}
+
+#[derive(Deserialize)]
+struct Answer {
+    decision: Decision,
+    revised_steps: Vec<String>,
+    reason: String,
+}
+
+#[derive(Deserialize)]
+enum Decision {
+    Continue,
+    Revise,
+    Stop,
+}
+
+/// A step of the plan as the check-in shows it: its prompt, and its result once it has run.
+pub struct StepReport<'a> {
+    pub number: usize,
+    pub prompt: &'a str,
+    pub result: Option<&'a str>,
+}
+
+/// Shows the model the original request and how the plan is going, and asks whether to carry on,
+/// change the steps that haven't run yet, or stop.
+pub async fn check_in(
+    model_name: &str,
+    request: Option<&str>,
+    completed: &[StepReport<'_>],
+    remaining: &[StepReport<'_>],
+) -> Result<CheckInDecision, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let system_message = format!(
+        "You're checking in on a multi-step plan for a file search tool partway through. Call {} \
+         with Continue if the remaining steps still make sense, Revise with the steps that should \
+         replace them if the results so far call for something different, or Stop if the plan \
+         has gone wrong or has already done what was asked. Give the reason either way.",
+        CHECK_IN_FUNCTION
+    );
+
+    let arguments = call_function(
+        model_name,
+        system_message,
+        &progress(request, completed, remaining),
+        CHECK_IN_FUNCTION,
+        "Decides what happens to the rest of the plan",
+        json!({
+            "type": "object",
+            "properties": {
+                "decision": {
+                    "type": "string",
+                    "enum": ["Continue", "Revise", "Stop"],
+                },
+                "revised_steps": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "The steps to run instead of the remaining ones, if revising",
+                },
+                "reason": { "type": "string" },
+            },
+            "required": ["decision", "revised_steps", "reason"],
+        }),
+    )
+    .await?;
+
+    Ok(decision(&arguments)?)
+}
+
+/// The request and the plan's steps so far as the model is shown them, with long results cut
+/// short.
+fn progress(
+    request: Option<&str>,
+    completed: &[StepReport<'_>],
+    remaining: &[StepReport<'_>],
+) -> String {
+    let mut progress = format!(
+        "Original request: {}\n\nCompleted steps:\n",
+        request.unwrap_or("(not known, the plan was started directly)")
+    );
+    for step in completed {
+        progress.push_str(&format!("{}. {}\n", step.number, step.prompt));
+        let result = step.result.unwrap_or("(no result)");
+        match result.char_indices().nth(CHECK_IN_RESULT_MAX_CHARS) {
+            Some((end, _)) => {
+                progress.push_str(&format!("   Result: {}... (truncated)\n", &result[..end]))
+            }
+            None => progress.push_str(&format!("   Result: {}\n", result)),
+        }
+    }
+    progress.push_str("\nRemaining steps:\n");
+    for step in remaining {
+        progress.push_str(&format!("{}. {}\n", step.number, step.prompt));
+    }
+    progress
+}
+
+/// Reads the model's answer to a check-in.
+fn decision(arguments: &str) -> Result<CheckInDecision, serde_json::Error> {
+    let answer: Answer = serde_json::from_str(arguments)?;
+    Ok(match answer.decision {
+        Decision::Continue => CheckInDecision::Continue,
+        // Revising to nothing is the same as stopping, but says why.
+        Decision::Revise if answer.revised_steps.is_empty() => CheckInDecision::Stop(answer.reason),
+        Decision::Revise => CheckInDecision::Revise(answer.revised_steps),
+        Decision::Stop => CheckInDecision::Stop(answer.reason),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_become_decisions() {
+        let answer = |decision: &str, revised_steps: &[&str], reason: &str| {
+            json!({
+                "decision": decision,
+                "revised_steps": revised_steps,
+                "reason": reason,
+            })
+            .to_string()
+        };
+
+        assert_eq!(
+            decision(&answer("Continue", &[], "On track.")).unwrap(),
+            CheckInDecision::Continue
+        );
+        assert_eq!(
+            decision(&answer(
+                "Revise",
+                &["Search for FIXME."],
+                "TODO found nothing."
+            ))
+            .unwrap(),
+            CheckInDecision::Revise(vec![String::from("Search for FIXME.")])
+        );
+        assert_eq!(
+            decision(&answer("Revise", &[], "Nothing left to do.")).unwrap(),
+            CheckInDecision::Stop(String::from("Nothing left to do."))
+        );
+        assert_eq!(
+            decision(&answer("Stop", &["Ignored."], "It went wrong.")).unwrap(),
+            CheckInDecision::Stop(String::from("It went wrong."))
+        );
+        assert!(decision(&answer("Retry", &[], "")).is_err());
+    }
+
+    #[test]
+    fn progress_shows_results_so_far_and_the_steps_left() {
+        let long = "x".repeat(CHECK_IN_RESULT_MAX_CHARS + 1);
+        let completed = [
+            StepReport {
+                number: 1,
+                prompt: "Ignore case.",
+                result: None,
+            },
+            StepReport {
+                number: 2,
+                prompt: "Search for TODO.",
+                result: Some(&long),
+            },
+        ];
+        let remaining = [StepReport {
+            number: 3,
+            prompt: "Search for FIXME.",
+            result: None,
+        }];
+
+        assert_eq!(
+            progress(None, &completed, &remaining),
+            format!(
+                "Original request: (not known, the plan was started directly)\n\nCompleted \
+                 steps:\n1. Ignore case.\n   Result: (no result)\n2. Search for TODO.\n   \
+                 Result: {}... (truncated)\n\nRemaining steps:\n3. Search for FIXME.\n",
+                "x".repeat(CHECK_IN_RESULT_MAX_CHARS)
+            )
+        );
+    }
+}
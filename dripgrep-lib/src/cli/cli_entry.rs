@@ -1,10 +1,293 @@
+use super::call_guard::{DEFAULT_MAX_MODEL_CALLS, DEFAULT_MAX_PLAN_DEPTH};
+use super::check_in::DEFAULT_CHECK_IN_EVERY;
 use super::commands::Commands;
-use clap::Parser;
+use super::config::{ConfigLayer, ProviderKind};
+use super::run_context::RunContext;
+use super::usage::Pricing;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 #[clap(propagate_version = true)]
 pub struct Cli {
     #[clap(subcommand)]
-    pub command: Commands,
+    pub command: CliCommand,
+
+    /// Send chat and embedding requests to this API base instead of OpenAI's
+    #[clap(long, global = true, env = "DRIPGREP_BASE_URL")]
+    pub base_url: Option<String>,
+
+    /// Answer chat and embedding requests from a local mock server scripted by this fixture file
+    #[clap(long, global = true)]
+    pub mock: Option<PathBuf>,
+
+    /// Record every chat completion, embedding and search made during the command into this directory
+    #[clap(long, global = true, conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+
+    /// Replay a session recorded with --record from this directory instead of calling the API
+    #[clap(long, global = true, conflicts_with = "mock")]
+    pub replay: Option<PathBuf>,
+
+    /// Plan the search as usual, but print the equivalent ripgrep command instead of running it
+    #[clap(long, global = true)]
+    pub dry_run: bool,
+
+    /// Plan searches from keywords in the request and the stored function embeddings, without
+    /// calling the model. This also happens on its own when the model can't be reached
+    #[clap(long, global = true)]
+    pub offline: bool,
+
+    /// Show the prepared search and ask before running it
+    #[clap(long, global = true, conflicts_with = "dry_run")]
+    pub confirm: bool,
+
+    /// Check in with the model after this many steps of a longer multi-step plan, to carry on,
+    /// revise the rest or stop (0 never checks in)
+    #[clap(long, global = true, default_value_t = DEFAULT_CHECK_IN_EVERY)]
+    pub check_in_every: usize,
+
+    /// How many multi-step plans can run inside each other before a nested one is refused
+    #[clap(long, global = true, default_value_t = DEFAULT_MAX_PLAN_DEPTH)]
+    pub max_plan_depth: usize,
+
+    /// How many model calls a request can make before whatever plan is running is stopped
+    #[clap(long, global = true, default_value_t = DEFAULT_MAX_MODEL_CALLS)]
+    pub max_model_calls: usize,
+
+    /// Read model prices, in dollars per million tokens, from this TOML file instead of using the
+    /// built-in ones
+    #[clap(long, global = true, env = "DRIPGREP_PRICING", value_name = "PRICING")]
+    pub pricing: Option<String>,
+
+    /// Refuse any model call that could take the session's cost past this many dollars
+    #[clap(long, global = true)]
+    pub max_cost: Option<f64>,
+
+    /// Read this config file instead of the nearest .dripgrep.toml
+    #[clap(long, global = true, env = "DRIPGREP_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// The chat model to send requests to
+    #[clap(long, global = true, env = "DRIPGREP_MODEL")]
+    pub model: Option<String>,
+
+    /// The model to embed prompts with when ranking functions
+    #[clap(long, global = true, env = "DRIPGREP_EMBED_MODEL")]
+    pub embed_model: Option<String>,
+
+    /// The most tokens a request's system message, prompt and tools can add up to
+    #[clap(
+        long,
+        global = true,
+        env = "DRIPGREP_MAX_REQUEST_TOKENS",
+        value_name = "TOKENS"
+    )]
+    pub max_request_tokens: Option<String>,
+
+    /// The most tokens the model can respond with
+    #[clap(
+        long,
+        global = true,
+        env = "DRIPGREP_MAX_RESPONSE_TOKENS",
+        value_name = "TOKENS"
+    )]
+    pub max_response_tokens: Option<String>,
+
+    /// The most tokens the functions offered with a request can add up to
+    #[clap(
+        long,
+        global = true,
+        env = "DRIPGREP_MAX_FUNC_TOKENS",
+        value_name = "TOKENS"
+    )]
+    pub max_func_tokens: Option<String>,
+
+    /// The system message every request starts with
+    #[clap(long, global = true, env = "DRIPGREP_SYSTEM_PROMPT")]
+    pub system_prompt: Option<String>,
+
+    /// The kind of server to send chat and embedding requests to: openai or openai-compatible
+    #[clap(
+        long,
+        global = true,
+        env = "DRIPGREP_PROVIDER",
+        value_name = "PROVIDER",
+        value_parser = provider_name
+    )]
+    pub provider: Option<String>,
+
+    /// The environment variable holding the provider's API key
+    #[clap(long, global = true, env = "DRIPGREP_API_KEY_ENV")]
+    pub api_key_env: Option<String>,
+}
+
+impl Cli {
+    /// The switches and limits this run goes by.
+    pub fn run_context(&self) -> RunContext {
+        RunContext {
+            dry_run: self.dry_run,
+            offline: self.offline,
+            confirm: self.confirm,
+            check_in_every: self.check_in_every,
+            max_plan_depth: self.max_plan_depth,
+            max_model_calls: self.max_model_calls,
+            max_cost: self.max_cost,
+            // The mock server's model is a stand-in, and a replay answers from a recording, so
+            // nothing either of them answers is spent.
+            unbilled: self.mock.is_some() || self.replay.is_some(),
+        }
+    }
+
+    /// What the environment and command line set, as the last layer of the config. An empty
+    /// string, like an environment variable that's set to nothing, doesn't set anything, so
+    /// these are only parsed once the empty ones are left out.
+    pub fn config_overrides(
+        &self,
+    ) -> Result<ConfigLayer, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        // The base URL can come from the environment, so it's only at odds with a stand-in for
+        // the API once an empty one has been left out.
+        let base_url = non_empty(&self.base_url);
+        if base_url.is_some() {
+            if self.mock.is_some() {
+                return Err("--mock can't be used with --base-url".into());
+            }
+            if self.replay.is_some() {
+                return Err("--replay can't be used with --base-url".into());
+            }
+        }
+
+        let pricing = match non_empty(&self.pricing) {
+            Some(path) => Some(
+                Pricing::from_file(Path::new(&path))
+                    .map_err(|e| format!("Failed to load pricing from {}: {}", path, e))?,
+            ),
+            None => None,
+        };
+        let provider = match non_empty(&self.provider) {
+            Some(name) => Some(ProviderKind::from_str(&name, true)?),
+            None => None,
+        };
+
+        Ok(ConfigLayer {
+            model: non_empty(&self.model),
+            embed_model: non_empty(&self.embed_model),
+            max_request_tokens: parse_non_empty(&self.max_request_tokens, "--max-request-tokens")?,
+            max_response_tokens: parse_non_empty(
+                &self.max_response_tokens,
+                "--max-response-tokens",
+            )?,
+            max_func_tokens: parse_non_empty(&self.max_func_tokens, "--max-func-tokens")?,
+            system_prompt: non_empty(&self.system_prompt),
+            pricing,
+            provider,
+            base_url,
+            api_key_env: non_empty(&self.api_key_env),
+        })
+    }
+}
+
+fn non_empty(value: &Option<String>) -> Option<String> {
+    value.clone().filter(|value| !value.is_empty())
+}
+
+/// `value` parsed, unless it's missing or empty.
+fn parse_non_empty<T>(value: &Option<String>, flag: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    non_empty(value)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| format!("Invalid value '{}' for {}: {}", value, flag, e))
+        })
+        .transpose()
+}
+
+/// Checks a provider name while the command line is parsed, so a wrong one is reported with the
+/// possible values, but lets an empty one through to be ignored.
+fn provider_name(value: &str) -> Result<String, String> {
+    if !value.is_empty() {
+        ProviderKind::from_str(value, true)?;
+    }
+    Ok(value.to_string())
+}
+
+#[derive(Subcommand)]
+pub enum CliCommand {
+    /// Starts an interactive chat that remembers earlier requests, search settings and results
+    Chat,
+
+    /// Shows how every function ranks against a prompt, what each costs in tokens, and which the model would be offered
+    Rank {
+        /// The prompt to rank functions against
+        prompt: String,
+    },
+
+    #[clap(flatten)]
+    Run(Commands),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(["dripgrep"].iter().chain(args).chain(&["rank", "x"])).unwrap()
+    }
+
+    #[test]
+    fn empty_values_set_nothing() {
+        let cli = parse(&[
+            "--max-request-tokens",
+            "",
+            "--max-response-tokens",
+            "",
+            "--max-func-tokens",
+            "",
+            "--provider",
+            "",
+            "--pricing",
+            "",
+            "--model",
+            "",
+        ]);
+        let overrides = cli.config_overrides().unwrap();
+        assert!(overrides.max_request_tokens.is_none());
+        assert!(overrides.max_response_tokens.is_none());
+        assert!(overrides.max_func_tokens.is_none());
+        assert!(overrides.provider.is_none());
+        assert!(overrides.pricing.is_none());
+        assert!(overrides.model.is_none());
+
+        let cli = parse(&["--base-url", "", "--mock", "fixture.json"]);
+        assert!(cli.config_overrides().unwrap().base_url.is_none());
+        let cli = parse(&["--base-url", "http://localhost:1", "--mock", "fixture.json"]);
+        assert!(cli.config_overrides().is_err());
+    }
+
+    #[test]
+    fn values_are_parsed_once_empty_ones_are_left_out() {
+        let cli = parse(&[
+            "--max-request-tokens",
+            "3000",
+            "--max-response-tokens",
+            "500",
+            "--provider",
+            "openai-compatible",
+        ]);
+        let overrides = cli.config_overrides().unwrap();
+        assert_eq!(overrides.max_request_tokens, Some(3000));
+        assert_eq!(overrides.max_response_tokens, Some(500));
+        assert_eq!(overrides.provider, Some(ProviderKind::OpenAiCompatible));
+
+        let cli = parse(&["--max-response-tokens", "lots"]);
+        assert!(cli.config_overrides().is_err());
+        assert!(Cli::try_parse_from(["dripgrep", "--provider", "elsewhere", "rank", "x"]).is_err());
+    }
 }
@@ -1,66 +1,78 @@
+#[cfg(feature = "embedded_search")]
+use super::embedded_search;
 use super::{
     args::{
         CaseSensitivity, Enabled, FileDisplayMode, FileType, Restriction, SortBy, SortOrdering,
     },
-    commands::{
-        CommandsGPT, FUNC_ENUMS_EMBED_MODEL, FUNC_ENUMS_EMBED_PATH, FUNC_ENUMS_MAX_REQUEST_TOKENS,
-        FUNC_ENUMS_MAX_RESPONSE_TOKENS,
-    },
-};
-use openai_func_enums::{
-    get_ranked_function_names, single_embedding, Logger, ToolCallExecutionStrategy,
+    call_context::CallContext,
+    call_guard::{budget_spent, describe_abort, is_budget_spent, refuse_plan},
+    cassette::cassette,
+    check_in::{check_in, CheckInDecision, StepReport},
+    config::config,
+    function_embeddings::FunctionEmbeddings,
+    model_client::{embedding, offered_functions, run_tools},
+    multi_step::{describe_rounds, describe_skipped, SkippedStep, StepGraph},
+    plan_verification::{describe_unsupported, verify_plan},
+    run_context::run_context,
+    search_plan::{describe_conflicts, update, ContextLines, SearchPlan, Sort},
+    search_results::SearchResults,
 };
-use std::path::Path;
+#[cfg(not(feature = "embedded_search"))]
+use super::{error::DripgrepError, search_results::OutputFormat};
+use openai_func_enums::{CommandError, Logger, ToolCallExecutionStrategy};
+#[cfg(not(feature = "embedded_search"))]
+use serde::{Deserialize, Serialize};
+#[cfg(not(feature = "embedded_search"))]
+use std::process::Stdio;
 use std::sync::Arc;
+#[cfg(not(feature = "embedded_search"))]
 use tokio::process::Command;
 use tokio::sync::Mutex;
 
-/// Prepares a command-line argument list with case-sensitivity settings.
+/// Sets the case-sensitivity mode on the search plan.
 ///
-/// This asynchronous function takes optional command-line arguments and a case sensitivity setting,
-/// then appends the appropriate flag for case sensitivity to the arguments. It returns a result containing
-/// a success message and the possibly modified list of arguments, or an error if one occurs.
+/// This asynchronous function takes the search plan being built up over the course of a
+/// conversation and a case sensitivity setting, then records that setting on the plan. Nothing is
+/// rendered as a ripgrep flag until the search actually runs.
 ///
 /// # Parameters
-/// - `arguments`: An `Option<Vec<String>>` representing command-line arguments. If `Some`, the function
-/// extends this list with a case sensitivity flag; if `None`, it simply adds the case sensitivity flag
-/// to an empty list.
+/// - `plan`: A mutable reference to the `SearchPlan` accumulated so far. The case sensitivity
+///   setting replaces whatever mode the plan had before; the most recent call always wins.
 /// - `case_sensitivity`: A reference to an enum representing the case sensitivity setting. The enum
 ///   `CaseSensitivity` has variants for ignoring case, being case-sensitive, and employing smart case logic.
 ///   The "SmartCase" variant will treat search patters as case-insensitive, unless a capital
 ///   character is present.
-///  
 ///
 /// # Returns
-/// A `Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>`. On success,
-/// it returns a tuple containing an `Option<String>` with a success message and an `Option<Vec<String>>` with the
-/// possibly extended list of command-line arguments including the case sensitivity flag. On failure, it returns
-/// an error boxed to allow for any type of error that implements the `std::error::Error` trait, along with `Send`,
-/// `Sync`, and a static lifetime.
+/// A `Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>>`. On success, it
+/// returns an `Option<String>` with a message that is handed back to the model as the tool result.
+/// The message is "Ok." unless the plan already had a case sensitivity mode, in which case it
+/// describes the conflict and how it was resolved.
+/// On failure, it returns an error boxed to allow for any type of error that implements the
+/// `std::error::Error` trait, along with `Send`, `Sync`, and a static lifetime.
 ///
 /// # Behavior
-/// - The function first checks if the `arguments` parameter is `Some` or `None`. If `Some`, it clones the vector
-/// of arguments provided; if `None`, it initializes an empty vector.
-/// - Based on the `case_sensitivity` parameter, the function appends a specific flag to the arguments vector:
-///     - For `CaseSensitivity::IgnoreCase`, it appends `--ignore-case`.
-///     - For `CaseSensitivity::CaseSensitive`, it appends `--case-sensitive`.
-///     - For `CaseSensitivity::SmartCase`, it appends `--smart-case`.
-/// - After processing, the function constructs a success message and returns both the message and the updated
-/// list of arguments within an `Ok` variant of the `Result`.
+/// - The `case_sensitivity` value is stored on the plan. When the plan is rendered:
+///     - `CaseSensitivity::IgnoreCase` becomes `--ignore-case`.
+///     - `CaseSensitivity::CaseSensitive` becomes `--case-sensitive`.
+///     - `CaseSensitivity::SmartCase` becomes `--smart-case`.
+/// - After updating the plan, the function returns a success message within an `Ok` variant of
+///   the `Result`.
 ///
 /// # Example Usage
 /// ```
 /// use dripgrep_lib::cli::args::CaseSensitivity;
 /// use dripgrep_lib::cli::command_funcs::case_filter;
+/// use dripgrep_lib::cli::search_plan::SearchPlan;
 ///
 /// async fn example_usage() {
-///     let arguments = Some(vec![String::from("--pretty"), String::from("--type"), String::from("rust")]);
+///     let mut plan = SearchPlan::default();
 ///     let case_sensitivity = CaseSensitivity::IgnoreCase;
 ///
-///     match case_filter(arguments, &case_sensitivity).await {
-///         Ok((Some(result), Some(args))) => {
+///     match case_filter(&mut plan, &case_sensitivity).await {
+///         Ok(Some(result)) => {
 ///             println!("Result: {}", result);
-///             println!("Arguments: {:?}", args);
+///             println!("Arguments: {:?}", plan.to_args());
 ///         }
 ///         Err(e) => println!("An error occurred: {}", e),
 ///         _ => println!("Unexpected result."),
@@ -68,379 +80,277 @@ use tokio::sync::Mutex;
 /// }
 /// ```
 ///
-/// Note: Ensure that the `CaseSensitivity` enum is defined in your codebase with the variants `IgnoreCase`,
-/// `CaseSensitive`, and `SmartCase` as used by the `case_filter` function. The function is asynchronous and
-/// must be awaited when called.
+/// Note: The function is asynchronous and must be awaited when called.
 pub async fn case_filter(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     case_sensitivity: &CaseSensitivity,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
-
-    match case_sensitivity {
-        CaseSensitivity::IgnoreCase => args.push(String::from("--ignore-case")),
-        CaseSensitivity::CaseSensitive => args.push(String::from("--case-sensitive")),
-        CaseSensitivity::SmartCase => args.push(String::from("--smart-case")),
-    }
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = update(
+        &mut plan.case_sensitivity,
+        case_sensitivity.clone(),
+        "CaseFilter",
+    );
+
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn context_lines(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     lines_before: &i32,
     lines_after: &i32,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
-
-    args.push(String::from("--before_context"));
-    args.push(lines_before.to_string());
-    args.push(String::from("--after-context"));
-    args.push(lines_after.to_string());
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    // The model occasionally sends negative counts. Treat those as no context.
+    let context = ContextLines {
+        before: u32::try_from(*lines_before).unwrap_or(0),
+        after: u32::try_from(*lines_after).unwrap_or(0),
+    };
+    let conflicts = update(&mut plan.context, context, "ContextLines");
+
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn crlf(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     enabled: &Enabled,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = update(&mut plan.crlf, enabled.into(), "CRLF");
 
-    match enabled {
-        Enabled::Yes => args.push(String::from("--crlf")),
-        Enabled::No => args.push(String::from("--no-crlf")),
-    }
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn debug(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     enabled: &Enabled,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args = arguments.unwrap_or_default();
-
-    if let Enabled::Yes = enabled {
-        args.push(String::from("--debug"));
-    }
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = update(&mut plan.debug, enabled.into(), "Debug");
 
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn dot_all(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     enabled: &Enabled,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = plan.set_dot_all(enabled.into());
 
-    match enabled {
-        Enabled::Yes => {
-            let multiline = String::from("--multiline");
-            if !args.iter().any(|p| p == &multiline) {
-                args.push(multiline);
-            }
-            args.push(String::from("--multiline-dotall"));
-        }
-        Enabled::No => args.push(String::from("--no-multiline-dotall")),
-    }
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn file_type_filter(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     enabled: &Enabled,
     file_type: &FileType,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
-
-    match enabled {
-        Enabled::Yes => args.push(String::from("--type")),
-        Enabled::No => args.push(String::from("--type-not")),
-    }
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = plan.set_file_type(file_type.clone(), enabled.into());
 
-    args.push(file_type.flag_value());
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn files(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     file_display_mode: &FileDisplayMode,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
-
-    match file_display_mode {
-        FileDisplayMode::FilesIncluded => {
-            args.push(String::from("--files"));
-            args.push(String::from("."));
-        }
-        FileDisplayMode::FilesWithMatch => args.push(String::from("--files-with-matches")),
-        FileDisplayMode::FilesWithoutMatch => args.push(String::from("--files-without-match")),
-    }
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = update(
+        &mut plan.file_display_mode,
+        file_display_mode.clone(),
+        "Files",
+    );
+
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn fixed_strings(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     enabled: &Enabled,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = update(&mut plan.fixed_strings, enabled.into(), "FixedStrings");
 
-    match enabled {
-        Enabled::Yes => args.push(String::from("--fixed-strings")),
-        Enabled::No => args.push(String::from("--no-fixed-strings")),
-    }
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn multiline(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     enabled: &Enabled,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
-
-    match enabled {
-        Enabled::Yes => args.push(String::from("--multiline")),
-        Enabled::No => args.push(String::from("--no-multiline")),
-    }
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = plan.set_multiline(enabled.into());
 
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn include_zip(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     enabled: &Enabled,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
-
-    match enabled {
-        Enabled::Yes => args.push(String::from("--search-zip")),
-        Enabled::No => args.push(String::from("--no-search-zip")),
-    }
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = update(&mut plan.include_zip, enabled.into(), "IncludeZip");
 
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn replace(
-    arguments: Option<Vec<String>>,
-    replacement_text: &String,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
+    plan: &mut SearchPlan,
+    replacement_text: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = update(&mut plan.replace, replacement_text.to_string(), "Replace");
 
-    args.push(String::from("--replace"));
-    let quoted_replacement_text = format!("\"{}\"", replacement_text);
-    // args.push(replacement_text.to_string());
-    args.push(quoted_replacement_text);
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn restriction_level(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     restriction: &Restriction,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
-
-    match restriction {
-        Restriction::Default => {}
-        Restriction::IncludeIgnored => args.push(String::from("-u")),
-        Restriction::IncludeIgnoredHidden => args.push(String::from("-uu")),
-        Restriction::IncludeIgnoredHiddenBinary => args.push(String::from("-uuu")),
-    }
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = update(
+        &mut plan.restriction,
+        restriction.clone(),
+        "RestrictionLevel",
+    );
+
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn sort_results_by(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     sort_by: &SortBy,
     sort_ordering: &SortOrdering,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
-
-    match sort_ordering {
-        SortOrdering::Ascending => args.push(String::from("--sort")),
-        SortOrdering::Descending => args.push(String::from("--sortr")),
-    }
-
-    args.push(sort_by.flag_value());
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let sort = Sort {
+        sort_by: sort_by.clone(),
+        sort_ordering: sort_ordering.clone(),
+    };
+    let conflicts = update(&mut plan.sort, sort, "SortResultsBy");
+
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn statistics(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     enabled: &Enabled,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
-
-    match enabled {
-        Enabled::Yes => args.push(String::from("--stats")),
-        Enabled::No => args.push(String::from("--no-stats")),
-    }
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = update(&mut plan.statistics, enabled.into(), "Statistics");
 
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn threads(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     thread_count: &i32,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
-
-    // If thread_count is zero we don't need to do anything.
-    if thread_count > &0_i32 {
-        args.push(String::from("--threads"));
-        args.push(thread_count.to_string());
-    }
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    // Anything below one means "let ripgrep decide".
+    let conflicts = update(
+        &mut plan.threads,
+        u32::try_from(*thread_count).unwrap_or(0),
+        "Threads",
+    );
+
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn trace_data(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     enabled: &Enabled,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args = arguments.unwrap_or_default();
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = update(&mut plan.trace, enabled.into(), "TraceData");
 
-    if let Enabled::Yes = enabled {
-        args.push(String::from("--trace"));
-    }
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn treat_binary_as_text(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     enabled: &Enabled,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
-
-    match enabled {
-        Enabled::Yes => args.push(String::from("--text")),
-        Enabled::No => args.push(String::from("--no-text")),
-    }
-
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = update(
+        &mut plan.treat_binary_as_text,
+        enabled.into(),
+        "TreatBinaryAsText",
+    );
+
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
 }
 
 pub async fn trim_whitespace(
-    arguments: Option<Vec<String>>,
+    plan: &mut SearchPlan,
     enabled: &Enabled,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut args: Vec<String> = vec![];
-    if let Some(arguments) = arguments {
-        args.extend(arguments.to_vec());
-    }
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let conflicts = update(&mut plan.trim_whitespace, enabled.into(), "TrimWhitespace");
 
-    match enabled {
-        Enabled::Yes => args.push(String::from("--trim")),
-        Enabled::No => args.push(String::from("--no-trim")),
+    let result = describe_conflicts(&conflicts);
+    Ok(Some(result))
+}
+
+/// Runs the search the plan describes in-process. See [`embedded_search::search`].
+#[cfg(feature = "embedded_search")]
+pub async fn search(
+    plan: SearchPlan,
+    logger: Arc<Logger>,
+    pattern: &str,
+) -> Result<SearchResults, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut recorded_args = vec![];
+    if !plan.lists_files() {
+        recorded_args.extend([String::from("-e"), pattern.to_string()]);
     }
+    recorded_args.extend(plan.to_args());
+
+    let _ = logger
+        .sender
+        .send(String::from("\nSearching in-process with args:\n"))
+        .await;
+    let message = format!("{:#?}", recorded_args);
+
+    let _ = logger.sender.send(message).await;
+    let _ = logger.sender.send(String::from("")).await;
 
-    let result = String::from("Ok.");
-    Ok((Some(result), Some(args)))
+    // A replayed session gets the results that were recorded instead of searching again.
+    match cassette() {
+        Some(cassette) => {
+            cassette
+                .exchange("search", &recorded_args, || {
+                    embedded_search::search(&plan, pattern)
+                })
+                .await
+        }
+        None => embedded_search::search(&plan, pattern).await,
+    }
 }
 
+/// Runs the search the plan describes with the `rg` binary.
+#[cfg(not(feature = "embedded_search"))]
 pub async fn search(
-    arguments: Option<Vec<String>>,
+    mut plan: SearchPlan,
     logger: Arc<Logger>,
-    pattern: &String,
-) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
-{
-    let mut command = Command::new("rg");
-    command.arg(pattern);
-    command.arg("--pretty");
+    pattern: &str,
+) -> Result<SearchResults, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let format = OutputFormat::for_plan(&plan);
+    if !format.supports_stats() {
+        plan.statistics = None;
+    }
 
-    if let Some(arguments) = arguments {
-        for arg in arguments {
-            command.arg(arg);
-        }
+    let mut command = Command::new("rg");
+    if !plan.lists_files() {
+        // After -e, a pattern that starts with a dash isn't taken for a flag.
+        command.arg("-e").arg(pattern);
     }
+    command.args(format.args());
+    command.args(plan.to_args());
+    // With no paths, ripgrep searches stdin rather than the working directory whenever stdin
+    // isn't a terminal, and in a chat stdin is where the requests come from.
+    command.stdin(Stdio::null());
 
     let _ = logger
         .sender
@@ -451,25 +361,56 @@ pub async fn search(
     let _ = logger.sender.send(message).await;
     let _ = logger.sender.send(String::from("")).await;
 
-    let output = command.output().await.expect("Failed to execute command");
+    // Recording the arguments lets a replayed session catch changes to how plans are rendered,
+    // and recording what rg printed lets it replay the results without searching again.
+    let output = match cassette() {
+        Some(cassette) => {
+            let recorded_args: Vec<String> = command
+                .as_std()
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect();
+            cassette
+                .exchange("search", &recorded_args, || run_rg(command))
+                .await?
+        }
+        None => run_rg(command).await?,
+    };
+
+    Ok(SearchResults::from_rg_output(
+        format,
+        pattern,
+        output.code,
+        output.stdout.as_bytes(),
+        output.stderr.as_bytes(),
+    )?)
+}
 
-    if output.status.success() {
-        let stdout = std::str::from_utf8(&output.stdout).expect("Failed to parse stdout as UTF-8");
-        let log_message = format!("Found matches:\n{}", stdout);
-        let _ = logger.sender.send(log_message).await;
-    } else {
-        // There is some sort of error but there are still likely results
-        let stderr = std::str::from_utf8(&output.stderr).expect("Failed to parse stderr as UTF-8");
-        let log_message = format!("Command failed with error:\n{}", stderr);
-        let _ = logger.sender.send(log_message).await;
-
-        let stdout = std::str::from_utf8(&output.stdout).expect("Failed to parse stdout as UTF-8");
-        let log_message = format!("Found matches:\n{}", stdout);
-        let _ = logger.sender.send(log_message).await;
-    }
+/// What a run of `rg` printed, and the code it exited with.
+#[cfg(not(feature = "embedded_search"))]
+#[derive(Deserialize, Serialize)]
+struct RgOutput {
+    code: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
 
-    let result = String::from("Ok.");
-    Ok((Some(result), None))
+#[cfg(not(feature = "embedded_search"))]
+async fn run_rg(
+    mut command: Command,
+) -> Result<RgOutput, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let output = command
+        .output()
+        .await
+        .map_err(|source| DripgrepError::Spawn {
+            program: String::from("rg"),
+            source,
+        })?;
+    Ok(RgOutput {
+        code: output.status.code(),
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    })
 }
 
 pub async fn call_multi_step(
@@ -478,6 +419,8 @@ pub async fn call_multi_step(
     model_name: &str,
     system_meessage: Option<(String, usize)>,
     prompt_list: &Vec<String>,
+    depends_on: &[String],
+    arguments: Option<Vec<String>>,
 ) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
 {
     let _ = logger
@@ -487,68 +430,423 @@ pub async fn call_multi_step(
     let message = format!("{:#?}", prompt_list);
     let _ = logger.sender.send(message).await;
 
-    let prior_result = Arc::new(Mutex::new(None));
+    // The model is always offered CallMultiStep, so a step can start a plan of its own, and that
+    // plan's steps can too. Each level spends model calls, so plans that go too deep or go round
+    // in circles aren't run.
+    let mut context = CallContext::from_arguments(arguments.clone())?;
+    if let Some(reason) = refuse_plan(&context.plans_running, prompt_list) {
+        let message = describe_abort(&reason, &[]);
+        logger.log(format!("\n{}", message)).await;
+        return Ok((Some(message), arguments));
+    }
+
+    let graph = match StepGraph::parse(prompt_list.len(), depends_on) {
+        Ok(graph) => graph,
+        Err(e) => {
+            let message = format!("Nothing was done, because the plan isn't valid: {}", e);
+            logger.log(format!("\n{}", message)).await;
+            return Ok((Some(message), arguments));
+        }
+    };
+
+    // A step no function can do would otherwise be dropped without a word, leaving the rest of
+    // the plan to do part of what was asked. Nothing runs unless every step has a function. A
+    // nested plan stands in for a step of a plan that was already verified, so checking it again
+    // would only spend another model call.
+    let verified = if context.plans_running.is_empty() {
+        verify_plan(model_name, prompt_list).await
+    } else {
+        Ok(Vec::new())
+    };
+    match verified {
+        Ok(unsupported) if !unsupported.is_empty() => {
+            let report = describe_unsupported(&unsupported);
+            logger.log(format!("\n{}", report)).await;
+            return Ok((Some(report), arguments));
+        }
+        Ok(_) => {}
+        Err(e) => {
+            logger
+                .log(format!(
+                    "Couldn't verify the plan, so running it as is: {}",
+                    e
+                ))
+                .await;
+        }
+    }
 
-    let command_args_list: Vec<String> = Vec::new();
-    let command_args = Arc::new(Mutex::new(Some(command_args_list)));
-    for (i, prompt) in prompt_list.iter().enumerate() {
-        let prior_result_clone = prior_result.clone();
-        let command_args_clone = command_args.clone();
-        let logger_clone = logger.clone();
-
-        let prompt_embedding = single_embedding(prompt, FUNC_ENUMS_EMBED_MODEL).await?;
-        let embed_path = Path::new(FUNC_ENUMS_EMBED_PATH);
-        let ranked_func_names = get_ranked_function_names(prompt_embedding, embed_path).await?;
-
-        let required_func_names = vec![String::from("CallMultiStep")];
-
-        match i {
-            0 => {
-                CommandsGPT::run(
-                    &prompt.to_string(),
-                    model_name,
-                    Some(FUNC_ENUMS_MAX_REQUEST_TOKENS.into()),
-                    Some(FUNC_ENUMS_MAX_RESPONSE_TOKENS),
-                    system_meessage.clone(),
-                    prior_result_clone,
-                    execution_strategy.clone(),
-                    command_args_clone,
-                    Some(ranked_func_names),
-                    Some(required_func_names),
-                    logger_clone,
-                )
-                .await?
+    logger
+        .log(format!("\n{}", describe_rounds(&graph.rounds())))
+        .await;
+
+    context.plans_running.push(prompt_list.clone());
+    let command_args = Arc::new(Mutex::new(context.into_arguments()));
+    let mut graph = graph;
+    let mut prompts = prompt_list.clone();
+    let mut results: Vec<Option<String>> = vec![None; prompts.len()];
+    // A step is finished once nothing more will happen to it, whether it ran, was skipped or was
+    // dropped at a check-in. Only the ones that ran count towards the result.
+    let mut finished = vec![false; prompts.len()];
+    let mut ran = vec![false; prompts.len()];
+    let mut skipped: Vec<SkippedStep> = Vec::new();
+    let mut run_since_check_in = 0;
+    let mut stopped: Option<String> = None;
+    let mut aborted: Option<String> = None;
+    let mut out_of_calls = false;
+
+    loop {
+        let round = graph.ready(&finished);
+        if let Some(reason) = budget_spent().filter(|_| out_of_calls || !round.is_empty()) {
+            let done = reports(
+                (0..prompts.len()).filter(|step| results[*step].is_some()),
+                &prompts,
+                &results,
+            );
+            let message = describe_abort(&reason, &done);
+            logger.log(format!("\n{}", message)).await;
+            aborted = Some(message);
+            break;
+        }
+        if round.is_empty() {
+            break;
+        }
+
+        let mut steps = Vec::new();
+        for step in round {
+            finished[step] = true;
+            // A step whose dependencies came back with nothing has nothing to go on.
+            if let Some(dependency) = graph.dependencies[step]
+                .iter()
+                .find(|dependency| results[**dependency].is_none())
+            {
+                let skip = SkippedStep {
+                    step: step + 1,
+                    prompt: prompts[step].clone(),
+                    dependency: dependency + 1,
+                };
+                logger
+                    .log(format!(
+                        "\n{}",
+                        describe_skipped(std::slice::from_ref(&skip))
+                    ))
+                    .await;
+                skipped.push(skip);
+                continue;
+            }
+            ran[step] = true;
+            steps.push(PlanStep {
+                number: step + 1,
+                of: prompts.len(),
+                prompt: prompts[step].clone(),
+                prior_results: graph.dependencies[step]
+                    .iter()
+                    .filter_map(|dependency| results[*dependency].clone())
+                    .collect(),
+            });
+        }
+        run_since_check_in += steps.len();
+
+        // Each step merges only what it changed back into the plan, so steps in the same round
+        // can't lose each other's settings, whichever way they're run.
+        let mut outcomes = Vec::new();
+        match execution_strategy {
+            ToolCallExecutionStrategy::Async => {
+                let mut tasks = Vec::new();
+                for step in steps {
+                    let number = step.number;
+                    tasks.push((
+                        number,
+                        tokio::spawn(run_plan_step(
+                            step,
+                            execution_strategy.clone(),
+                            logger.clone(),
+                            model_name.to_string(),
+                            system_meessage.clone(),
+                            command_args.clone(),
+                        )),
+                    ));
+                }
+                for (number, task) in tasks {
+                    outcomes.push((number, task.await?));
+                }
             }
 
-            _ => {
-                let prior_result_guard = prior_result.lock().await;
-                if let Some(prior) = &*prior_result_guard {
-                    let new_prompt = format!("The prior result was: {}. {}", prior.clone(), prompt);
-                    drop(prior_result_guard);
-
-                    CommandsGPT::run(
-                        &new_prompt,
-                        model_name,
-                        Some(FUNC_ENUMS_MAX_REQUEST_TOKENS.into()),
-                        Some(FUNC_ENUMS_MAX_RESPONSE_TOKENS),
-                        system_meessage.clone(),
-                        prior_result_clone,
+            ToolCallExecutionStrategy::Synchronous => {
+                for step in steps {
+                    let number = step.number;
+                    let outcome = run_plan_step(
+                        step,
                         execution_strategy.clone(),
-                        command_args_clone,
-                        None,
-                        None,
-                        logger_clone,
+                        logger.clone(),
+                        model_name.to_string(),
+                        system_meessage.clone(),
+                        command_args.clone(),
                     )
-                    .await?
-                } else {
-                    *prior_result.lock().await = None;
+                    .await;
+                    outcomes.push((number, outcome));
+                }
+            }
+
+            ToolCallExecutionStrategy::Parallel => {
+                let mut handles = Vec::new();
+                for step in steps {
+                    let number = step.number;
+                    let logger = logger.clone();
+                    let model_name = model_name.to_string();
+                    let system_message = system_meessage.clone();
+                    let command_args = command_args.clone();
+
+                    // As with tool calls, only the steps get their own threads. Anything they
+                    // start themselves runs as async tasks on that thread's runtime.
+                    handles.push((
+                        number,
+                        std::thread::spawn(move || {
+                            let rt = tokio::runtime::Runtime::new()?;
+                            rt.block_on(run_plan_step(
+                                step,
+                                ToolCallExecutionStrategy::Async,
+                                logger,
+                                model_name,
+                                system_message,
+                                command_args,
+                            ))
+                        }),
+                    ));
+                }
+                for (number, handle) in handles {
+                    let outcome = handle
+                        .join()
+                        .map_err(|_| CommandError::new("Plan step thread panicked"))?;
+                    outcomes.push((number, outcome));
+                }
+            }
+        }
+
+        for (number, outcome) in outcomes {
+            match outcome {
+                Ok(result) => results[number - 1] = result,
+                // A step that ran out of model calls is reported with the rest of the plan.
+                Err(e) if is_budget_spent(&*e) => out_of_calls = true,
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Long plans are written before any of their results are known, so every so often the
+        // model gets to look at how it's going and change course.
+        let every = run_context().check_in_every;
+        let pending: Vec<usize> = (0..prompts.len()).filter(|step| !finished[*step]).collect();
+        if every == 0
+            || prompt_list.len() <= every
+            || run_since_check_in < every
+            || pending.is_empty()
+        {
+            continue;
+        }
+        run_since_check_in = 0;
+
+        let request = CallContext::from_arguments(command_args.lock().await.clone())?.request;
+        let completed = reports(
+            (0..prompts.len()).filter(|step| ran[*step]),
+            &prompts,
+            &results,
+        );
+        let remaining = reports(pending.iter().copied(), &prompts, &results);
+
+        let decision = match check_in(model_name, request.as_deref(), &completed, &remaining).await
+        {
+            Ok(decision) => decision,
+            Err(e) => {
+                logger
+                    .log(format!("Couldn't check in, so carrying on: {}", e))
+                    .await;
+                continue;
+            }
+        };
+
+        match decision {
+            CheckInDecision::Continue => {
+                logger
+                    .log(String::from("\nChecked in: carrying on with the plan."))
+                    .await;
+            }
+            CheckInDecision::Stop(reason) => {
+                let message = format!(
+                    "Stopped at a check-in with {} steps left to run: {}",
+                    pending.len(),
+                    reason
+                );
+                logger.log(format!("\n{}", message)).await;
+                stopped = Some(message);
+                break;
+            }
+            CheckInDecision::Revise(revised) => {
+                // Revised steps get the same check as the plan they replace.
+                if let Ok(unsupported) = verify_plan(model_name, &revised).await {
+                    if !unsupported.is_empty() {
+                        let message = format!(
+                            "Stopped at a check-in, because the revised steps can't run. {}",
+                            describe_unsupported(&unsupported)
+                        );
+                        logger.log(format!("\n{}", message)).await;
+                        stopped = Some(message);
+                        break;
+                    }
+                }
+
+                logger
+                    .log(format!(
+                        "\nChecked in: revised the remaining steps to:\n{:#?}",
+                        revised
+                    ))
+                    .await;
+
+                for step in pending {
+                    finished[step] = true;
+                }
+                // The new steps pick up from whatever the plan had got to, one after another.
+                let mut waits_on = graph.final_steps(&ran);
+                for prompt in revised {
+                    waits_on = vec![graph.push(waits_on)];
+                    prompts.push(prompt);
+                    results.push(None);
+                    finished.push(false);
+                    ran.push(false);
                 }
             }
         }
     }
 
-    let result = String::from("Ok.");
-    Ok((Some(result), None))
+    // Hand the final steps' results back, so a plan nested inside another one can be chained on
+    // too.
+    let final_results: Vec<String> = graph
+        .final_steps(&ran)
+        .into_iter()
+        .filter_map(|step| results[step].clone())
+        .collect();
+    let mut result = if final_results.is_empty() {
+        String::from("Ok.")
+    } else {
+        final_results.join("\n")
+    };
+    if let Some(message) = stopped {
+        result = format!("{}\n{}", message, result);
+    }
+    // An abort already lists everything that was done.
+    if let Some(message) = aborted {
+        result = message;
+    }
+    if !skipped.is_empty() {
+        result = format!("{}\n{}", result, describe_skipped(&skipped));
+    }
+
+    let mut context = CallContext::from_arguments(command_args.lock().await.clone())?;
+    context.plans_running.pop();
+    Ok((Some(result), context.into_arguments()))
+}
+
+/// The steps in `steps` as a check-in or an abort message shows them, with whatever result each
+/// has.
+fn reports<'a>(
+    steps: impl Iterator<Item = usize>,
+    prompts: &'a [String],
+    results: &'a [Option<String>],
+) -> Vec<StepReport<'a>> {
+    steps
+        .map(|step| StepReport {
+            number: step + 1,
+            prompt: &prompts[step],
+            result: results[step].as_deref(),
+        })
+        .collect()
+}
+
+/// A step of a multi-step plan that's ready to run.
+struct PlanStep {
+    /// The step's position in the plan, counting from 1.
+    number: usize,
+    of: usize,
+    prompt: String,
+    /// The results of the steps it depends on.
+    prior_results: Vec<String>,
+}
+
+/// Runs one step of a multi-step plan and returns its result.
+async fn run_plan_step(
+    step: PlanStep,
+    execution_strategy: ToolCallExecutionStrategy,
+    logger: Arc<Logger>,
+    model_name: String,
+    system_message: Option<(String, usize)>,
+    command_args: Arc<Mutex<Option<Vec<String>>>>,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let step_prompt = match step.prior_results.as_slice() {
+        [] => step.prompt.clone(),
+        [prior] => format!("The prior result was:\n{}\n{}", prior, step.prompt),
+        priors => format!(
+            "The prior results were:\n{}\n{}",
+            priors.join("\n"),
+            step.prompt
+        ),
+    };
+
+    // Each step is ranked on its own prompt, without the prior results, so the functions it's
+    // offered are the ones that step describes.
+    let ranked_func_names = rank_functions(&step.prompt).await?;
+    let required_func_names = required_functions();
+
+    let (offered, offered_tokens) = offered_functions(
+        Some(ranked_func_names.clone()),
+        Some(required_func_names.clone()),
+    )?;
+    logger
+        .log(format!(
+            "\nStep {} of {} is offered {} functions in {} of {} tokens: {}",
+            step.number,
+            step.of,
+            offered.len(),
+            offered_tokens,
+            config().max_func_tokens,
+            offered.join(", ")
+        ))
+        .await;
+
+    let prior_result = Arc::new(Mutex::new(None));
+    run_tools(
+        &step_prompt,
+        &model_name,
+        Some(config().max_request_tokens),
+        Some(config().max_response_tokens),
+        system_message,
+        prior_result.clone(),
+        execution_strategy,
+        command_args,
+        Some(ranked_func_names),
+        Some(required_func_names),
+        logger,
+    )
+    .await?;
+
+    let result = prior_result.lock().await.clone();
+    Ok(result)
+}
+
+/// The functions the model is offered however a prompt ranks them. `CallMultiStep` is what lets
+/// it split a request into steps, and `Search` is the only function that searches, so a request
+/// can't be carried out without it.
+pub fn required_functions() -> Vec<String> {
+    vec![String::from("CallMultiStep"), String::from("Search")]
+}
+
+/// Ranks every function by how similar its stored description embedding is to `prompt`, most
+/// similar first. The stored embeddings have to have been made with the configured embedding
+/// model.
+pub async fn rank_functions(
+    prompt: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let embed_model = &config().embed_model;
+    let function_embeddings = FunctionEmbeddings::load(embed_model)?;
+    let prompt_embedding = embedding(prompt, embed_model).await?;
+    Ok(function_embeddings.rank(&prompt_embedding))
 }
 
 pub async fn gpt(
@@ -557,34 +855,64 @@ pub async fn gpt(
     model_name: &str,
     system_message: Option<(String, usize)>,
     prompt: &String,
+    arguments: Option<Vec<String>>,
+) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
+{
+    let ranked_func_names = rank_functions(prompt).await?;
+
+    gpt_with_functions(
+        execution_strategy,
+        logger,
+        model_name,
+        system_message,
+        prompt,
+        arguments,
+        ranked_func_names,
+    )
+    .await
+}
+
+/// Does what [`gpt`] does, but offers the model functions in the order of `ranked_func_names`
+/// instead of ranking them against `prompt`.
+pub async fn gpt_with_functions(
+    execution_strategy: ToolCallExecutionStrategy,
+    logger: Arc<Logger>,
+    model_name: &str,
+    system_message: Option<(String, usize)>,
+    prompt: &String,
+    arguments: Option<Vec<String>>,
+    ranked_func_names: Vec<String>,
 ) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
 {
-    let prompt_embedding = single_embedding(prompt, FUNC_ENUMS_EMBED_MODEL).await?;
+    let mut context = CallContext::from_arguments(arguments)?;
+    context.request = Some(prompt.to_string());
 
     let prior_result = Arc::new(Mutex::new(None));
-    let command_args = Arc::new(Mutex::new(None));
+    let command_args = Arc::new(Mutex::new(context.into_arguments()));
     let logger_clone = logger.clone();
 
-    let embed_path = Path::new(FUNC_ENUMS_EMBED_PATH);
-
-    let ranked_func_names = get_ranked_function_names(prompt_embedding, embed_path).await?;
-    let required_func_names = vec![String::from("CallMultiStep")];
+    let required_func_names = required_functions();
 
-    CommandsGPT::run(
+    run_tools(
         prompt,
         model_name,
-        Some(FUNC_ENUMS_MAX_REQUEST_TOKENS.into()),
-        Some(FUNC_ENUMS_MAX_RESPONSE_TOKENS),
+        Some(config().max_request_tokens),
+        Some(config().max_response_tokens),
         system_message.clone(),
-        prior_result,
+        prior_result.clone(),
         execution_strategy.clone(),
-        command_args,
+        command_args.clone(),
         Some(ranked_func_names),
         Some(required_func_names),
         logger_clone,
     )
     .await?;
 
-    let result = String::from("Ok.");
-    Ok((Some(result), None))
+    let result = prior_result
+        .lock()
+        .await
+        .clone()
+        .unwrap_or_else(|| String::from("Ok."));
+    let plan = command_args.lock().await.clone();
+    Ok((Some(result), plan))
 }
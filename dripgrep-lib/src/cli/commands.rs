@@ -1,8 +1,19 @@
 use super::{
     args::{
-        CaseSensitivity, Enabled, FileDisplayMode, FileType, Restriction, SortBy, SortOrdering,
+        CaseSensitivity, Enabled, FileDisplayMode, FileType, GlobCaseSensitivity, Restriction,
+        SortBy, SortOrdering,
     },
+    call_context::CallContext,
+    call_guard::model_has_answered,
     command_funcs::*,
+    config::config,
+    confirm::ask,
+    dry_run::{command_line, explain},
+    error::DripgrepError,
+    offline_planner::{offline, run_offline, set_offline},
+    provider::is_unreachable,
+    run_context::run_context,
+    search_results::SEARCH_RESULT_MAX_TOKENS,
 };
 use clap::Subcommand;
 use openai_func_enums::{Logger, RunCommand, ToolCallExecutionStrategy, ToolSet};
@@ -103,14 +114,28 @@ pub enum Commands {
         enabled: Enabled,
     },
 
-    /// Searches for a pattern
+    /// Searches paths, or the current directory, for a pattern in files matching the include globs and not the exclude globs
     Search {
         pattern: String,
+        paths: Vec<String>,
+        #[clap(long = "glob")]
+        include_globs: Vec<String>,
+        #[clap(long = "exclude-glob")]
+        exclude_globs: Vec<String>,
+        #[clap(long, value_enum, default_value_t = GlobCaseSensitivity::CaseSensitive)]
+        glob_case_sensitivity: GlobCaseSensitivity,
     },
 
-    /// CallMultiStep is designed to efficiently process complex, multi-step user requests. It takes an array of text prompts, each detailing a specific step in a sequential task. This function is crucial for handling requests where the output of one step forms the input of the next. When constructing the prompt list, consider the dependency and order of tasks. Independent tasks within the same step should be consolidated into a single prompt to leverage parallel processing capabilities. This function ensures that multi-step tasks are executed in the correct sequence and that all dependencies are respected, thus faithfully representing and fulfilling the user's request."
+    /// Splits a request into steps, one prompt each. depends_on has an entry per step: the earlier step numbers it needs, comma separated, or "" for none; a search needs the steps that prepare it
     CallMultiStep {
+        /// One prompt per step, in order
         prompt_list: Vec<String>,
+        /// For each step, the numbers of the earlier steps (from 1) it needs, comma separated, or
+        /// an empty string for none. Steps that don't depend on each other run at the same time,
+        /// so a search must depend on every step that prepares its settings, and a step that
+        /// uses another step's results must depend on it
+        #[clap(long)]
+        depends_on: Vec<String>,
     },
 
     GPT {
@@ -118,6 +143,32 @@ pub enum Commands {
     },
 }
 
+/// What calling a `Commands` variant does.
+///
+/// The model builds a search up over several tool calls, so the settings have to be in place
+/// before the call that searches with them. Tool calls from one response all run before the
+/// next request, and within a response every preparation call finishes before any action starts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandKind {
+    /// Changes a setting in the search plan without searching.
+    Preparation,
+    /// Searches with the plan the preparation calls built up.
+    Action,
+    /// Starts a conversation of its own with the model.
+    Conversation,
+}
+
+impl Commands {
+    /// Whether this command prepares the search, runs it, or hands off to the model.
+    pub fn kind(&self) -> CommandKind {
+        match self {
+            Commands::Search { .. } => CommandKind::Action,
+            Commands::CallMultiStep { .. } | Commands::GPT { .. } => CommandKind::Conversation,
+            _ => CommandKind::Preparation,
+        }
+    }
+}
+
 #[async_trait]
 impl RunCommand for Commands {
     async fn run(
@@ -130,93 +181,141 @@ impl RunCommand for Commands {
         (Option<String>, Option<Vec<String>>),
         Box<dyn std::error::Error + Send + Sync + 'static>,
     > {
-        let model_name = "gpt-4-1106-preview";
+        let model_name = config().model.as_str();
 
-        match self {
+        let mut context = CallContext::from_arguments(arguments)?;
+        let plan = &mut context.plan;
+
+        let result = match self {
             Commands::CaseFilter { case_sensitivity } => {
-                return case_filter(arguments, case_sensitivity).await;
+                case_filter(plan, case_sensitivity).await?
             }
 
             Commands::ContextLines {
                 lines_before,
                 lines_after,
-            } => {
-                return context_lines(arguments, lines_before, lines_after).await;
-            }
+            } => context_lines(plan, lines_before, lines_after).await?,
 
-            Commands::CRLF { enabled } => {
-                return crlf(arguments, enabled).await;
-            }
+            Commands::CRLF { enabled } => crlf(plan, enabled).await?,
 
-            Commands::Debug { enabled } => {
-                return debug(arguments, enabled).await;
-            }
+            Commands::Debug { enabled } => debug(plan, enabled).await?,
 
-            Commands::DotAll { enabled } => {
-                return dot_all(arguments, enabled).await;
-            }
+            Commands::DotAll { enabled } => dot_all(plan, enabled).await?,
 
             Commands::FileTypeFilter { enabled, file_type } => {
-                return file_type_filter(arguments, enabled, file_type).await;
+                file_type_filter(plan, enabled, file_type).await?
             }
 
-            Commands::Files { file_display_mode } => {
-                return files(arguments, file_display_mode).await;
-            }
+            Commands::Files { file_display_mode } => files(plan, file_display_mode).await?,
 
-            Commands::FixedStrings { enabled } => {
-                return fixed_strings(arguments, enabled).await;
-            }
+            Commands::FixedStrings { enabled } => fixed_strings(plan, enabled).await?,
 
-            Commands::Multiline { enabled } => {
-                return multiline(arguments, enabled).await;
-            }
+            Commands::Multiline { enabled } => multiline(plan, enabled).await?,
 
-            Commands::IncludeZip { enabled } => {
-                return include_zip(arguments, enabled).await;
-            }
+            Commands::IncludeZip { enabled } => include_zip(plan, enabled).await?,
 
-            Commands::Replace { replacement_text } => {
-                return replace(arguments, replacement_text).await;
-            }
+            Commands::Replace { replacement_text } => replace(plan, replacement_text).await?,
 
             Commands::RestrictionLevel { restriction } => {
-                return restriction_level(arguments, restriction).await;
+                restriction_level(plan, restriction).await?
             }
 
             Commands::SortResultsBy {
                 sort_by,
                 sort_ordering,
-            } => {
-                return sort_results_by(arguments, sort_by, sort_ordering).await;
-            }
+            } => sort_results_by(plan, sort_by, sort_ordering).await?,
 
-            Commands::Statistics { enabled } => {
-                return statistics(arguments, enabled).await;
-            }
+            Commands::Statistics { enabled } => statistics(plan, enabled).await?,
 
-            Commands::Threads { thread_count } => {
-                return threads(arguments, thread_count).await;
-            }
+            Commands::Threads { thread_count } => threads(plan, thread_count).await?,
 
-            Commands::TraceData { enabled } => {
-                return trace_data(arguments, enabled).await;
-            }
+            Commands::TraceData { enabled } => trace_data(plan, enabled).await?,
 
-            Commands::TreatBinaryAsText { enabled } => {
-                return treat_binary_as_text(arguments, enabled).await;
-            }
+            Commands::TreatBinaryAsText { enabled } => treat_binary_as_text(plan, enabled).await?,
 
-            Commands::TrimWhitespace { enabled } => {
-                return trim_whitespace(arguments, enabled).await;
-            }
+            Commands::TrimWhitespace { enabled } => trim_whitespace(plan, enabled).await?,
 
-            Commands::Search { pattern } => {
+            Commands::Search {
+                pattern,
+                paths,
+                include_globs,
+                exclude_globs,
+                glob_case_sensitivity,
+            } => {
                 let logger_clone = logger.clone();
-                return search(arguments, logger_clone, pattern).await;
+                plan.paths = paths.clone();
+                let ignore_case = *glob_case_sensitivity == GlobCaseSensitivity::IgnoreCase;
+                let mut conflicts = Vec::new();
+                for glob in include_globs {
+                    conflicts.extend(plan.add_glob(glob, true, ignore_case));
+                }
+                for glob in exclude_globs {
+                    conflicts.extend(plan.add_glob(glob, false, ignore_case));
+                }
+                let conflicts: Vec<String> = conflicts.iter().map(ToString::to_string).collect();
+                if run_context().dry_run {
+                    logger
+                        .log(format!(
+                            "\nDry run, the search was not executed:\n{}",
+                            explain(plan, pattern)
+                        ))
+                        .await;
+                    let mut message = format!(
+                        "Dry run, so nothing was searched. The search would have run: {}",
+                        command_line(plan, pattern)
+                    );
+                    for conflict in &conflicts {
+                        message.push_str(&format!("\nWarning: {}", conflict));
+                    }
+                    return Ok((Some(message), context.into_arguments()));
+                }
+
+                let check = plan.check(pattern);
+                if !check.is_complete() {
+                    let message =
+                        format!("The search plan isn't ready: {}", check.missing.join(" "));
+                    logger.log(format!("Search not run:\n{}", message)).await;
+                    return Ok((Some(message), context.into_arguments()));
+                }
+                if run_context().confirm && !ask(plan, pattern, &check).await? {
+                    logger.log(String::from("Search cancelled.")).await;
+                    return Ok((
+                        Some(String::from(
+                            "The user declined to run this search, so nothing was searched.",
+                        )),
+                        context.into_arguments(),
+                    ));
+                }
+                let include_stats = plan.statistics == Some(true);
+                let searched = context.clone().searched(pattern).into_arguments();
+                let mut results = match search(context.plan, logger_clone, pattern).await {
+                    Ok(results) => results,
+                    // The model can do something about these, so they go back to it rather than
+                    // ending the conversation.
+                    Err(error) => match error.downcast::<DripgrepError>() {
+                        Ok(error) => {
+                            logger.log(format!("Search failed:\n{}", error)).await;
+                            return Ok((Some(error.to_string()), searched));
+                        }
+                        Err(error) => return Err(error),
+                    },
+                };
+                results.warnings.extend(conflicts);
+                results.warnings.extend(check.ignored);
+
+                for warning in &results.warnings {
+                    logger.log(format!("Warning: {}", warning)).await;
+                }
+                logger
+                    .log(format!("Found matches:\n{}", results.pretty(include_stats)))
+                    .await;
+                return Ok((Some(results.summarize(SEARCH_RESULT_MAX_TOKENS)?), searched));
             }
 
-            Commands::CallMultiStep { prompt_list } => {
+            Commands::CallMultiStep {
+                prompt_list,
+                depends_on,
+            } => {
                 let logger_clone = logger.clone();
 
                 return call_multi_step(
@@ -225,22 +324,61 @@ impl RunCommand for Commands {
                     model_name,
                     system_message,
                     prompt_list,
+                    depends_on,
+                    context.into_arguments(),
                 )
                 .await;
             }
 
             Commands::GPT { prompt } => {
                 let logger_clone = logger.clone();
-
-                return gpt(
+                let arguments = context.into_arguments();
+
+                if offline() {
+                    return run_offline(
+                        execution_strategy.clone(),
+                        logger_clone,
+                        system_message,
+                        prompt,
+                        arguments,
+                    )
+                    .await;
+                }
+
+                return match gpt(
                     execution_strategy.clone(),
                     logger_clone,
                     model_name,
-                    system_message,
+                    system_message.clone(),
                     prompt,
+                    arguments.clone(),
                 )
-                .await;
+                .await
+                {
+                    // Nothing has been done yet if the model hasn't answered anything, so the
+                    // request can be planned again from the start.
+                    Err(error) if is_unreachable(&*error) && !model_has_answered() => {
+                        logger
+                            .log(format!(
+                                "Couldn't reach the model, so planning without it: {}",
+                                error
+                            ))
+                            .await;
+                        set_offline(true);
+                        run_offline(
+                            execution_strategy.clone(),
+                            logger.clone(),
+                            system_message,
+                            prompt,
+                            arguments,
+                        )
+                        .await
+                    }
+                    result => result,
+                };
             }
         };
+
+        Ok((result, context.into_arguments()))
     }
 }
@@ -0,0 +1,258 @@
+use super::commands::{
+    FUNC_ENUMS_EMBED_MODEL, FUNC_ENUMS_MAX_FUNC_TOKENS, FUNC_ENUMS_MAX_REQUEST_TOKENS,
+    FUNC_ENUMS_MAX_RESPONSE_TOKENS,
+};
+use super::usage::{count_tokens, Pricing};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// The chat model requests go to unless the config says otherwise.
+pub const DEFAULT_MODEL: &str = "gpt-4-1106-preview";
+
+/// The name of a project's config file, looked for in the current directory and every one above
+/// it.
+pub const PROJECT_CONFIG_FILE: &str = ".dripgrep.toml";
+
+/// The system message every request starts with unless the config says otherwise.
+pub const DEFAULT_SYSTEM_PROMPT: &str =
+    "You are a highly capable function-calling bot, trained to process complex, \
+     multi-step requests from users. Your main function is to oversee an application \
+     for conducting searches within file systems, accommodating a range of optional \
+     settings as specified by users. For requests that involve multiple sequential steps, \
+     initiate the process with the CallMultiStep function. This function requires an array \
+     of text prompts, each delineating a distinct step in the task sequence. The essence of \
+     CallMultiStep is to ensure that multi-step tasks are executed in an orderly fashion, \
+     maintaining the correct sequence and respecting the dependencies between steps. \
+     Always prioritize setting adjustments before the search action in your prompts. \
+     For example, to search for 'fast' within markdown files, CallMultiStep should receive \
+     two prompts: the first to activate a filetype filter for markdown files, and the second \
+     to command the search for 'fast'. Importantly, if a request includes translating a phrase \
+     prior to search, translate it using your internal knowledge before incorporating it \
+     into the CallMultiStep prompts. This ensures that all steps, from option setting to \
+     translation and search, are methodically organized and executed according to user instructions.";
+
+/// Which kind of server chat and embedding requests go to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, clap::ValueEnum)]
+pub enum ProviderKind {
+    /// The OpenAI API, with the key from `OPENAI_API_KEY`.
+    #[serde(rename = "openai")]
+    #[value(name = "openai")]
+    OpenAi,
+    /// Any server with OpenAI's endpoints, like llama.cpp or Ollama, at `base_url`.
+    #[serde(rename = "openai-compatible")]
+    #[value(name = "openai-compatible")]
+    OpenAiCompatible,
+}
+
+/// Settings that used to be compiled in, as they apply to this run.
+///
+/// `build.rs` still sets the embedding model and token limits the `ToolSet` macro works with, and
+/// those are the defaults here. Each of these layers then replaces whatever it sets, in order:
+///
+/// 1. The global config file (see [`global_config_path`]).
+/// 2. The project's `.dripgrep.toml`, or the file given with `--config`.
+/// 3. `DRIPGREP_*` environment variables.
+/// 4. Command line flags.
+///
+/// A config file sets any of these keys, and can add to the model prices with a table per model
+/// under `pricing.models`. `provider`, `base_url` and `api_key_env` pick where requests go (see
+/// [`from_config`](super::provider::from_config)):
+///
+/// ```
+/// use dripgrep_lib::cli::config::{Config, ConfigLayer, ProviderKind};
+///
+/// let layer = ConfigLayer::from_toml(
+///     r#"
+///     model = "gpt-4-turbo"
+///     provider = "openai-compatible"
+///     base_url = "http://localhost:11434/v1"
+///     max_response_tokens = 800
+///     system_prompt = "You search files."
+///
+///     [pricing.models.gpt-4-turbo]
+///     input = 10.0
+///     output = 30.0
+///     "#,
+/// )
+/// .unwrap();
+///
+/// let mut config = Config::default();
+/// config.apply(layer);
+/// assert_eq!(config.model, "gpt-4-turbo");
+/// assert_eq!(config.max_response_tokens, 800);
+/// assert_eq!(config.provider, Some(ProviderKind::OpenAiCompatible));
+/// assert_eq!(config.system_message(), (String::from("You search files."), 4));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub model: String,
+    pub embed_model: String,
+    /// The most tokens a request's system message, prompt and tools can add up to.
+    pub max_request_tokens: usize,
+    pub max_response_tokens: u16,
+    /// The most tokens the functions offered with a request can add up to.
+    pub max_func_tokens: u16,
+    pub system_prompt: String,
+    pub pricing: Pricing,
+    /// Where requests go. Left unset, it's worked out from `base_url`.
+    pub provider: Option<ProviderKind>,
+    /// The API base requests go to, like `http://localhost:11434/v1`.
+    pub base_url: Option<String>,
+    /// The environment variable holding the provider's API key.
+    pub api_key_env: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            model: String::from(DEFAULT_MODEL),
+            embed_model: String::from(FUNC_ENUMS_EMBED_MODEL),
+            max_request_tokens: FUNC_ENUMS_MAX_REQUEST_TOKENS,
+            max_response_tokens: FUNC_ENUMS_MAX_RESPONSE_TOKENS,
+            max_func_tokens: FUNC_ENUMS_MAX_FUNC_TOKENS,
+            system_prompt: String::from(DEFAULT_SYSTEM_PROMPT),
+            pricing: Pricing::builtin(),
+            provider: None,
+            base_url: None,
+            api_key_env: None,
+        }
+    }
+}
+
+/// Whatever one config file, or the environment and command line, sets. Anything left out is
+/// left as the layers before it had it.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+    pub model: Option<String>,
+    pub embed_model: Option<String>,
+    pub max_request_tokens: Option<usize>,
+    pub max_response_tokens: Option<u16>,
+    pub max_func_tokens: Option<u16>,
+    pub system_prompt: Option<String>,
+    pub pricing: Option<Pricing>,
+    pub provider: Option<ProviderKind>,
+    pub base_url: Option<String>,
+    pub api_key_env: Option<String>,
+}
+
+impl ConfigLayer {
+    pub fn from_toml(
+        text: &str,
+    ) -> Result<ConfigLayer, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<ConfigLayer, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        ConfigLayer::from_toml(&text)
+            .map_err(|e| format!("{} isn't a valid config file: {}", path.display(), e).into())
+    }
+}
+
+impl Config {
+    /// Builds the config from the defaults, the global and project config files, and then
+    /// `overrides`, which is what the environment and command line set. `config_file` takes the
+    /// place of the project's `.dripgrep.toml` when it's given.
+    pub fn load(
+        config_file: Option<&Path>,
+        overrides: ConfigLayer,
+    ) -> Result<Config, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let mut config = Config::default();
+
+        if let Some(path) = global_config_path().filter(|path| path.is_file()) {
+            config.apply(ConfigLayer::from_file(path)?);
+        }
+
+        // A file that was asked for by name has to be there; the others are optional.
+        match config_file {
+            Some(path) => config.apply(ConfigLayer::from_file(path)?),
+            None => {
+                if let Some(path) = project_config_path() {
+                    config.apply(ConfigLayer::from_file(path)?);
+                }
+            }
+        }
+
+        config.apply(overrides);
+        Ok(config)
+    }
+
+    /// Replaces whatever `layer` sets. Prices are added to rather than replaced, model by model.
+    pub fn apply(&mut self, layer: ConfigLayer) {
+        if let Some(model) = layer.model {
+            self.model = model;
+        }
+        if let Some(embed_model) = layer.embed_model {
+            self.embed_model = embed_model;
+        }
+        if let Some(max_request_tokens) = layer.max_request_tokens {
+            self.max_request_tokens = max_request_tokens;
+        }
+        if let Some(max_response_tokens) = layer.max_response_tokens {
+            self.max_response_tokens = max_response_tokens;
+        }
+        if let Some(max_func_tokens) = layer.max_func_tokens {
+            self.max_func_tokens = max_func_tokens;
+        }
+        if let Some(system_prompt) = layer.system_prompt {
+            self.system_prompt = system_prompt;
+        }
+        if let Some(pricing) = layer.pricing {
+            self.pricing.extend(pricing);
+        }
+        if let Some(provider) = layer.provider {
+            self.provider = Some(provider);
+        }
+        if let Some(base_url) = layer.base_url {
+            self.base_url = Some(base_url);
+        }
+        if let Some(api_key_env) = layer.api_key_env {
+            self.api_key_env = Some(api_key_env);
+        }
+    }
+
+    /// The system prompt and its token count, the way requests take it.
+    pub fn system_message(&self) -> (String, usize) {
+        (
+            self.system_prompt.clone(),
+            count_tokens(&self.system_prompt),
+        )
+    }
+}
+
+/// `dripgrep/config.toml` in `$XDG_CONFIG_HOME`, or in `~/.config` if that isn't set, or in
+/// `%APPDATA%` if `HOME` isn't set either, as on most Windows systems.
+pub fn global_config_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(config_dir.join("dripgrep").join("config.toml"))
+}
+
+/// The nearest `.dripgrep.toml`, starting in the current directory and working up.
+pub fn project_config_path() -> Option<PathBuf> {
+    let current_dir = std::env::current_dir().ok()?;
+    current_dir
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Makes `config` the one every request uses.
+pub fn set_config(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+/// The config set with [`set_config`], or the defaults.
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
@@ -0,0 +1,35 @@
+use super::dry_run::explain;
+use super::search_plan::{PlanCheck, SearchPlan};
+use std::io::{BufRead, Write};
+use tokio::sync::Mutex;
+
+/// Searches started at the same time would otherwise ask over the top of each other.
+static PROMPT: Mutex<()> = Mutex::const_new(());
+
+/// Prints the plan for searching `pattern`, with anything from `check` the search will ignore,
+/// and asks whether to run it. Anything other than "y" or "yes" is a no, including end of input.
+pub async fn ask(
+    plan: &SearchPlan,
+    pattern: &str,
+    check: &PlanCheck,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut question = format!("\nReady to search:\n{}", explain(plan, pattern));
+    for ignored in &check.ignored {
+        question.push_str(&format!("Note: {}\n", ignored));
+    }
+    question.push_str("Run this search? [y/N] ");
+
+    let _prompt = PROMPT.lock().await;
+    let answer = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(question.as_bytes())?;
+        stdout.flush()?;
+
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer)?;
+        Ok(answer)
+    })
+    .await??;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
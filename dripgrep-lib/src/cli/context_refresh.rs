@@ -0,0 +1,107 @@
+use super::model_client::call_function;
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt;
+
+/// The only function offered when classifying, so the model has to answer with it.
+const CLASSIFY_FUNCTION: &str = "ClassifyRequest";
+
+/// How a new request in a chat relates to the ones before it.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum ContextChange {
+    /// The request is about something else. Earlier settings, results and tools don't apply.
+    NewSubject,
+    /// The request digs further into the same subject, and may need tools that weren't offered
+    /// before.
+    DeeperFollowUp,
+    /// The request can be handled with the same settings and tools, like a rewording or a
+    /// question about the last results.
+    NoChange,
+}
+
+impl fmt::Display for ContextChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContextChange::NewSubject => write!(f, "a new subject"),
+            ContextChange::DeeperFollowUp => write!(f, "a deeper follow-up"),
+            ContextChange::NoChange => write!(f, "a follow-up that needs no new context"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Classification {
+    context_change: ContextChange,
+}
+
+/// Asks the model how `prompt` relates to a session described by `session_context`, before
+/// anything is ranked or run for it.
+pub async fn classify(
+    model_name: &str,
+    session_context: &str,
+    prompt: &str,
+) -> Result<ContextChange, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let system_message = format!(
+        "You look at how a new request in a file search session relates to the session so far, \
+         before anything is done about it. Call {} with NewSubject if the request is about \
+         something unrelated and earlier settings shouldn't carry over, DeeperFollowUp if it \
+         builds on earlier requests and may need settings or actions they didn't, or NoChange if \
+         it can be handled the same way, like a rewording or a question about the results.\n\n{}",
+        CLASSIFY_FUNCTION, session_context
+    );
+
+    let arguments = call_function(
+        model_name,
+        system_message,
+        prompt,
+        CLASSIFY_FUNCTION,
+        "Records how the new request relates to the session so far",
+        json!({
+            "type": "object",
+            "properties": {
+                "context_change": {
+                    "type": "string",
+                    "enum": ["NewSubject", "DeeperFollowUp", "NoChange"],
+                },
+            },
+            "required": ["context_change"],
+        }),
+    )
+    .await?;
+
+    Ok(context_change(&arguments)?)
+}
+
+/// Reads the model's classification of a request.
+fn context_change(arguments: &str) -> Result<ContextChange, serde_json::Error> {
+    let classification: Classification = serde_json::from_str(arguments)?;
+    Ok(classification.context_change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifications_are_read_from_the_answer() {
+        for (answer, change) in [
+            ("NewSubject", ContextChange::NewSubject),
+            ("DeeperFollowUp", ContextChange::DeeperFollowUp),
+            ("NoChange", ContextChange::NoChange),
+        ] {
+            let arguments = json!({ "context_change": answer }).to_string();
+            assert_eq!(context_change(&arguments).unwrap(), change);
+        }
+
+        assert!(context_change(r#"{"context_change": "Unrelated"}"#).is_err());
+        assert!(context_change("{}").is_err());
+    }
+
+    #[test]
+    fn classifications_finish_a_sentence() {
+        assert_eq!(
+            format!("Treating this as {}.", ContextChange::DeeperFollowUp),
+            "Treating this as a deeper follow-up."
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use super::search_plan::{PlanArgs, SearchPlan};
+
+/// Quotes `arg` for a POSIX shell, leaving it alone if it doesn't need quoting.
+///
+/// ```
+/// use dripgrep_lib::cli::dry_run::shell_quote;
+///
+/// assert_eq!(shell_quote("--type"), "--type");
+/// assert_eq!(shell_quote("The first thing"), "'The first thing'");
+/// assert_eq!(shell_quote("it's"), "'it'\\''s'");
+/// assert_eq!(shell_quote("!testing_03.txt"), "'!testing_03.txt'");
+/// ```
+pub fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        return arg.to_string();
+    }
+
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// The arguments a search would run with, starting with the pattern unless the plan only lists
+/// files. The pattern goes after `-e`, so one that starts with a dash isn't taken for a flag.
+fn search_args(plan: &SearchPlan, pattern: &str) -> Vec<PlanArgs> {
+    let mut args = Vec::new();
+    if !plan.lists_files() {
+        args.push(PlanArgs {
+            setting: "Search",
+            args: vec![String::from("-e"), pattern.to_string()],
+        });
+    }
+    args.extend(plan.annotated_args());
+    args
+}
+
+/// The ripgrep command line equivalent to searching for `pattern` with `plan`, quoted so it can
+/// be pasted into a shell.
+///
+/// ```
+/// use dripgrep_lib::cli::dry_run::command_line;
+/// use dripgrep_lib::cli::search_plan::SearchPlan;
+///
+/// let plan = SearchPlan {
+///     paths: vec![String::from("-notes"), String::from("src")],
+///     ..SearchPlan::default()
+/// };
+/// assert_eq!(command_line(&plan, "-foo"), "rg -e -foo -- -notes src");
+/// ```
+pub fn command_line(plan: &SearchPlan, pattern: &str) -> String {
+    let mut command_line = String::from("rg");
+    for arg in search_args(plan, pattern).iter().flat_map(|arg| &arg.args) {
+        command_line.push(' ');
+        command_line.push_str(&shell_quote(arg));
+    }
+    command_line
+}
+
+/// The command line from [`command_line`], followed by each of its arguments next to the
+/// `Commands` variant that produced it.
+pub fn explain(plan: &SearchPlan, pattern: &str) -> String {
+    let rows: Vec<(String, &str)> = search_args(plan, pattern)
+        .iter()
+        .map(|arg| {
+            let quoted: Vec<String> = arg.args.iter().map(|arg| shell_quote(arg)).collect();
+            (quoted.join(" "), arg.setting)
+        })
+        .collect();
+
+    let width = rows.iter().map(|(args, _)| args.len()).max().unwrap_or(0);
+
+    let mut explanation = command_line(plan, pattern);
+    explanation.push_str("\n\n");
+    for (args, setting) in rows {
+        explanation.push_str(&format!("  {:<width$}  {}\n", args, setting, width = width));
+    }
+    explanation
+}
@@ -0,0 +1,534 @@
+use super::args::{CaseSensitivity, FileDisplayMode, Restriction, SortBy, SortOrdering};
+use super::error::DripgrepError;
+use super::search_plan::SearchPlan;
+use super::search_results::{FileSummary, SearchMatch, SearchResults, Stats, SubMatch};
+use grep_matcher::{Captures, LineTerminator, Matcher};
+use grep_regex::{RegexMatcher, RegexMatcherBuilder};
+use grep_searcher::{BinaryDetection, Searcher, SearcherBuilder, Sink, SinkContext, SinkMatch};
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::types::{Types, TypesBuilder};
+use ignore::WalkBuilder;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+
+/// Runs the search a plan describes in-process, with the same library crates ripgrep is built
+/// on, instead of starting an `rg` process.
+///
+/// Files are walked and searched on a single thread, in the order the plan's sort asks for, so
+/// `threads` has no effect here, and neither do `debug` and `trace`. Searching compressed files
+/// needs the external decompression tools ripgrep uses, so a plan that turns on `include_zip`
+/// is rejected.
+pub async fn search(
+    plan: &SearchPlan,
+    pattern: &str,
+) -> Result<SearchResults, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    if let Some(true) = plan.include_zip {
+        return Err(Box::new(DripgrepError::Unsupported(String::from(
+            "Searching compressed files isn't supported by the embedded search engine.",
+        ))));
+    }
+
+    let plan = plan.clone();
+    let pattern = pattern.to_string();
+    Ok(tokio::task::spawn_blocking(move || run(&plan, &pattern)).await??)
+}
+
+fn run(plan: &SearchPlan, pattern: &str) -> Result<SearchResults, DripgrepError> {
+    let started = Instant::now();
+    let mut results = SearchResults::default();
+
+    // Like ripgrep, files that can't be read are reported and skipped rather than ending the
+    // search.
+    let paths = walk(plan, &mut results.warnings)?;
+
+    if plan.lists_files() {
+        results.files = paths
+            .iter()
+            .map(|path| FileSummary {
+                path: display_path(plan, path),
+                matched_lines: 0,
+                matches: 0,
+            })
+            .collect();
+        return Ok(results);
+    }
+
+    let matcher = matcher(plan, pattern)?;
+    let mut searcher = searcher(plan);
+    let mut lossy_lines = 0;
+    let mut stats = Stats {
+        searches: 0,
+        searches_with_match: 0,
+        bytes_searched: 0,
+        matched_lines: 0,
+        matches: 0,
+        elapsed_seconds: 0.0,
+    };
+
+    for path in &paths {
+        let mut collector = Collector {
+            matcher: &matcher,
+            path: display_path(plan, path),
+            replace: plan.replace.as_deref(),
+            trim: plan.trim_whitespace == Some(true),
+            lines: Vec::new(),
+            matched_lines: 0,
+            matches: 0,
+            lossy_lines: 0,
+        };
+
+        if let Err(error) = searcher.search_path(&matcher, path, &mut collector) {
+            results
+                .warnings
+                .push(format!("{}: {}", path.display(), error));
+            continue;
+        }
+
+        stats.searches += 1;
+        stats.bytes_searched += path.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        stats.matched_lines += collector.matched_lines;
+        stats.matches += collector.matches;
+        if collector.matched_lines > 0 {
+            stats.searches_with_match += 1;
+        }
+
+        let file = FileSummary {
+            path: collector.path,
+            matched_lines: collector.matched_lines,
+            matches: collector.matches,
+        };
+
+        match plan.file_display_mode {
+            Some(FileDisplayMode::FilesWithMatch) if file.matched_lines > 0 => {
+                results.files.push(file)
+            }
+            Some(FileDisplayMode::FilesWithoutMatch) if file.matched_lines == 0 => {
+                results.files.push(file)
+            }
+            None if file.matched_lines > 0 => {
+                lossy_lines += collector.lossy_lines;
+                results.matches.extend(collector.lines);
+                results.files.push(file);
+            }
+            _ => {}
+        }
+    }
+
+    // Same as ripgrep's exit code 2: errors and nothing else to show for it.
+    if results.files.is_empty() && !results.warnings.is_empty() {
+        return Err(DripgrepError::Failed {
+            code: None,
+            stderr: results.warnings.join("\n"),
+        });
+    }
+
+    if lossy_lines > 0 {
+        results.warn_lossy(Some(lossy_lines));
+    }
+
+    stats.elapsed_seconds = started.elapsed().as_secs_f64();
+    results.stats = Some(stats);
+
+    Ok(results)
+}
+
+/// Collects the files to search under the plan's paths, honoring its ignore rules, file types,
+/// globs and sort.
+fn walk(plan: &SearchPlan, errors: &mut Vec<String>) -> Result<Vec<PathBuf>, DripgrepError> {
+    let roots: Vec<&str> = match plan.paths.is_empty() {
+        true => vec!["./"],
+        false => plan.paths.iter().map(|path| path.as_str()).collect(),
+    };
+
+    let mut builder = WalkBuilder::new(roots[0]);
+    for root in &roots[1..] {
+        builder.add(root);
+    }
+
+    match &plan.restriction {
+        Some(Restriction::IncludeIgnored) => {
+            builder.standard_filters(false).hidden(true);
+        }
+        Some(Restriction::IncludeIgnoredHidden) | Some(Restriction::IncludeIgnoredHiddenBinary) => {
+            builder.standard_filters(false);
+        }
+        Some(Restriction::Default) | None => {}
+    }
+
+    builder.types(types(plan).map_err(failed)?);
+    builder.overrides(overrides(plan).map_err(failed)?);
+
+    let mut paths = Vec::new();
+    for entry in builder.build() {
+        match entry {
+            Ok(entry)
+                if entry
+                    .file_type()
+                    .is_some_and(|file_type| file_type.is_file()) =>
+            {
+                paths.push(entry.into_path())
+            }
+            Ok(_) => {}
+            Err(error) => errors.push(error.to_string()),
+        }
+    }
+
+    if let Some(sort) = &plan.sort {
+        match sort.sort_by {
+            SortBy::None => {}
+            SortBy::Path => paths.sort(),
+            SortBy::Modified => paths.sort_by_key(|path| file_time(path, |m| m.modified())),
+            SortBy::Accessed => paths.sort_by_key(|path| file_time(path, |m| m.accessed())),
+            SortBy::Created => paths.sort_by_key(|path| file_time(path, |m| m.created())),
+        }
+        if sort.sort_ordering == SortOrdering::Descending && sort.sort_by != SortBy::None {
+            paths.reverse();
+        }
+    }
+
+    Ok(paths)
+}
+
+fn file_time(
+    path: &Path,
+    time: impl Fn(&std::fs::Metadata) -> std::io::Result<SystemTime>,
+) -> Option<SystemTime> {
+    path.metadata().and_then(|metadata| time(&metadata)).ok()
+}
+
+fn failed(error: ignore::Error) -> DripgrepError {
+    DripgrepError::Failed {
+        code: None,
+        stderr: error.to_string(),
+    }
+}
+
+fn types(plan: &SearchPlan) -> Result<Types, ignore::Error> {
+    let mut builder = TypesBuilder::new();
+    builder.add_defaults();
+    for filter in &plan.file_types {
+        match filter.include {
+            true => builder.select(&filter.file_type.flag_value()),
+            false => builder.negate(&filter.file_type.flag_value()),
+        };
+    }
+    builder.build()
+}
+
+fn overrides(plan: &SearchPlan) -> Result<Override, ignore::Error> {
+    // Like ripgrep, globs are relative to the working directory, whatever paths are searched.
+    let mut builder = OverrideBuilder::new(".");
+    for filter in &plan.globs {
+        builder.case_insensitive(filter.ignore_case)?;
+        match filter.include {
+            true => builder.add(&filter.glob)?,
+            false => builder.add(&format!("!{}", filter.glob))?,
+        };
+    }
+    builder.build()
+}
+
+fn matcher(plan: &SearchPlan, pattern: &str) -> Result<RegexMatcher, DripgrepError> {
+    let multiline = plan.multiline == Some(true) || plan.dot_all == Some(true);
+
+    let mut builder = RegexMatcherBuilder::new();
+    match &plan.case_sensitivity {
+        Some(CaseSensitivity::IgnoreCase) => {
+            builder.case_insensitive(true);
+        }
+        Some(CaseSensitivity::SmartCase) => {
+            builder.case_smart(true);
+        }
+        Some(CaseSensitivity::CaseSensitive) | None => {}
+    }
+
+    // Same as ripgrep: `^` and `$` always match at line boundaries, and outside of multiline mode
+    // a match can never span a line terminator.
+    builder
+        .multi_line(true)
+        .dot_matches_new_line(plan.dot_all == Some(true))
+        .crlf(plan.crlf == Some(true));
+    if !multiline {
+        builder.line_terminator(Some(b'\n'));
+    }
+
+    let matcher = match plan.fixed_strings {
+        Some(true) => builder.build_literals(&[pattern]),
+        _ => builder.build(pattern),
+    };
+
+    matcher.map_err(|e| DripgrepError::InvalidPattern {
+        pattern: pattern.to_string(),
+        message: e.to_string(),
+    })
+}
+
+fn searcher(plan: &SearchPlan) -> Searcher {
+    let mut builder = SearcherBuilder::new();
+    builder
+        .line_number(true)
+        .multi_line(plan.multiline == Some(true) || plan.dot_all == Some(true));
+
+    if let Some(context) = &plan.context {
+        builder
+            .before_context(context.before as usize)
+            .after_context(context.after as usize);
+    }
+
+    if plan.crlf == Some(true) {
+        builder.line_terminator(LineTerminator::crlf());
+    }
+
+    let text = plan.treat_binary_as_text == Some(true)
+        || plan.restriction == Some(Restriction::IncludeIgnoredHiddenBinary);
+    builder.binary_detection(match text {
+        true => BinaryDetection::none(),
+        false => BinaryDetection::quit(b'\x00'),
+    });
+
+    builder.build()
+}
+
+/// Paths are shown the way ripgrep shows them: relative to the working directory, without a
+/// leading `./`, when no paths were given, and as given otherwise.
+fn display_path(plan: &SearchPlan, path: &Path) -> String {
+    let path = match plan.paths.is_empty() {
+        true => path.strip_prefix("./").unwrap_or(path),
+        false => path,
+    };
+    path.to_string_lossy().into_owned()
+}
+
+struct Collector<'a> {
+    matcher: &'a RegexMatcher,
+    path: String,
+    replace: Option<&'a str>,
+    trim: bool,
+    lines: Vec<SearchMatch>,
+    matched_lines: u64,
+    matches: u64,
+    lossy_lines: u64,
+}
+
+impl Collector<'_> {
+    /// Records a line of results and returns how many matches were found in it.
+    fn push(
+        &mut self,
+        bytes: &[u8],
+        line_number: Option<u64>,
+        context: bool,
+    ) -> Result<usize, std::io::Error> {
+        let trimmed = match self.trim {
+            true => bytes.iter().take_while(|b| b.is_ascii_whitespace()).count(),
+            false => 0,
+        };
+        let bytes = &bytes[trimmed..];
+        if std::str::from_utf8(bytes).is_err() {
+            self.lossy_lines += 1;
+        }
+
+        let mut submatches = Vec::new();
+        if !context {
+            self.matcher
+                .find_iter(bytes, |m| {
+                    submatches.push(SubMatch {
+                        text: String::from_utf8_lossy(&bytes[m]).into_owned(),
+                        start: m.start(),
+                        end: m.end(),
+                    });
+                    true
+                })
+                .map_err(std::io::Error::other)?;
+        }
+
+        let matches = submatches.len();
+        let text = match (self.replace, context) {
+            (Some(replacement), false) => {
+                // Offsets into the original line don't mean anything in the replaced one.
+                submatches.clear();
+                String::from_utf8_lossy(&self.replaced(bytes, replacement)?).into_owned()
+            }
+            _ => String::from_utf8_lossy(bytes).into_owned(),
+        };
+
+        self.lines.push(SearchMatch {
+            path: self.path.clone(),
+            line_number,
+            text,
+            submatches,
+            context,
+        });
+
+        Ok(matches)
+    }
+
+    fn replaced(&self, bytes: &[u8], replacement: &str) -> Result<Vec<u8>, std::io::Error> {
+        let mut captures = self.matcher.new_captures().map_err(std::io::Error::other)?;
+        let mut replaced = Vec::new();
+        self.matcher
+            .replace_with_captures(bytes, &mut captures, &mut replaced, |captures, dst| {
+                captures.interpolate(
+                    |name| self.matcher.capture_index(name),
+                    bytes,
+                    replacement.as_bytes(),
+                    dst,
+                );
+                true
+            })
+            .map_err(std::io::Error::other)?;
+        Ok(replaced)
+    }
+}
+
+impl Sink for Collector<'_> {
+    type Error = std::io::Error;
+
+    fn matched(&mut self, _searcher: &Searcher, mat: &SinkMatch<'_>) -> Result<bool, Self::Error> {
+        self.matched_lines += mat.lines().count() as u64;
+        self.matches += self.push(mat.bytes(), mat.line_number(), false)?.max(1) as u64;
+        Ok(true)
+    }
+
+    fn context(
+        &mut self,
+        _searcher: &Searcher,
+        context: &SinkContext<'_>,
+    ) -> Result<bool, Self::Error> {
+        self.push(context.bytes(), context.line_number(), true)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::args::FileType;
+    use crate::cli::search_plan::Sort;
+
+    /// A directory of files that all mention TODO, removed again when it's dropped.
+    struct Tree(PathBuf);
+
+    impl Tree {
+        fn new(name: &str) -> Tree {
+            let root = std::env::temp_dir().join(format!(
+                "dripgrep-embedded-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_dir_all(&root);
+            for (path, text) in [
+                ("main.rs", "// TODO: parse the arguments\nfn main() {}\n"),
+                ("notes.txt", "todo\nTODO: write the docs\n"),
+                ("tests/cli.rs", "// TODO: test the flags\n"),
+            ] {
+                let path = root.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, text).unwrap();
+            }
+            Tree(root)
+        }
+
+        fn plan(&self) -> SearchPlan {
+            SearchPlan {
+                paths: vec![self.0.to_string_lossy().into_owned()],
+                sort: Some(Sort {
+                    sort_by: SortBy::Path,
+                    sort_ordering: SortOrdering::Ascending,
+                }),
+                ..SearchPlan::default()
+            }
+        }
+
+        /// The files `plan` finds TODO in, relative to the tree.
+        async fn files(&self, plan: &SearchPlan) -> Vec<String> {
+            search(plan, "TODO")
+                .await
+                .unwrap()
+                .files
+                .iter()
+                .map(|file| {
+                    Path::new(&file.path)
+                        .strip_prefix(&self.0)
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect()
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn file_types_are_included_and_excluded() {
+        let tree = Tree::new("types");
+
+        let mut plan = tree.plan();
+        plan.set_file_type(FileType::Rust, true);
+        assert_eq!(tree.files(&plan).await, ["main.rs", "tests/cli.rs"]);
+
+        let mut plan = tree.plan();
+        plan.set_file_type(FileType::Rust, false);
+        assert_eq!(tree.files(&plan).await, ["notes.txt"]);
+    }
+
+    #[tokio::test]
+    async fn globs_are_included_and_excluded() {
+        let tree = Tree::new("globs");
+
+        let mut plan = tree.plan();
+        plan.add_glob("tests/", false, false);
+        assert_eq!(tree.files(&plan).await, ["main.rs", "notes.txt"]);
+
+        let mut plan = tree.plan();
+        plan.add_glob("*.TXT", true, true);
+        assert_eq!(tree.files(&plan).await, ["notes.txt"]);
+    }
+
+    #[tokio::test]
+    async fn matches_follow_the_case_and_sort() {
+        let tree = Tree::new("matches");
+
+        let mut plan = tree.plan();
+        plan.case_sensitivity = Some(CaseSensitivity::IgnoreCase);
+        plan.sort = Some(Sort {
+            sort_by: SortBy::Path,
+            sort_ordering: SortOrdering::Descending,
+        });
+        let results = search(&plan, "TODO").await.unwrap();
+        let lines: Vec<(&str, Option<u64>)> = results
+            .matches
+            .iter()
+            .map(|line| {
+                let file = Path::new(&line.path).file_name().unwrap().to_str().unwrap();
+                (file, line.line_number)
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                ("cli.rs", Some(1)),
+                ("notes.txt", Some(1)),
+                ("notes.txt", Some(2)),
+                ("main.rs", Some(1)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_files_arent_supported() {
+        let plan = SearchPlan {
+            include_zip: Some(true),
+            ..SearchPlan::default()
+        };
+        let error = search(&plan, "TODO").await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<DripgrepError>(),
+            Some(DripgrepError::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+// This file is also built into build.rs, which writes the local model's embeddings file and checks
+// the `ToolSet` macro's, so it can't depend on anything else in the crate.
+use rkyv::{Archive, Deserialize, Serialize};
+
+/// One function's description and its embedding.
+#[derive(Archive, Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[archive(check_bytes)]
+pub struct StoredEmbedding {
+    pub name: String,
+    pub description: String,
+    pub embedding: Vec<f32>,
+}
+
+/// The contents of an embeddings file that records which model made it.
+#[derive(Archive, Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[archive(check_bytes)]
+pub struct EmbeddingFile {
+    pub model: String,
+    pub functions: Vec<StoredEmbedding>,
+}
+
+/// A hash of a function's description, ignoring the whitespace around it. An embedding is only
+/// good for the description it was made from, so when this differs, the embedding is stale.
+pub fn description_hash(description: &str) -> u64 {
+    fnv1a(description.trim().as_bytes())
+}
+
+/// The 64-bit FNV-1a hash, which is the same everywhere, unlike the standard library's hasher.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// How the functions in an embeddings file differ from the commands there are now.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Staleness {
+    /// Functions whose description changed after they were embedded.
+    pub changed: Vec<String>,
+    /// Functions with a description but no embedding.
+    pub missing: Vec<String>,
+    /// Embedded functions that aren't commands anymore.
+    pub removed: Vec<String>,
+}
+
+impl Staleness {
+    /// Compares the names and descriptions of the `stored` functions with the `current` ones.
+    /// Functions without a description are never embedded, so they aren't missing.
+    ///
+    /// ```
+    /// use dripgrep_lib::cli::embedding_file::Staleness;
+    ///
+    /// let stored = [("Threads", " Sets threads"), ("Sort", " Sorts results")];
+    /// let current = [
+    ///     ("Threads", "Sets threads"),
+    ///     ("Sort", "Sorts results by the given method"),
+    ///     ("Replace", "Replaces matches"),
+    ///     ("GPT", ""),
+    /// ];
+    /// let staleness = Staleness::compare(&stored, &current);
+    /// assert_eq!(staleness.changed, ["Sort"]);
+    /// assert_eq!(staleness.missing, ["Replace"]);
+    /// assert!(staleness.removed.is_empty());
+    /// ```
+    pub fn compare<S: AsRef<str>>(stored: &[(S, S)], current: &[(S, S)]) -> Staleness {
+        let mut staleness = Staleness::default();
+        for (name, description) in current {
+            let (name, description) = (name.as_ref(), description.as_ref());
+            let stored_description = stored
+                .iter()
+                .find(|(stored_name, _)| stored_name.as_ref() == name)
+                .map(|(_, stored_description)| stored_description.as_ref());
+            match stored_description {
+                Some(stored_description)
+                    if description_hash(stored_description) != description_hash(description) =>
+                {
+                    staleness.changed.push(name.to_string())
+                }
+                None if !description.trim().is_empty() => staleness.missing.push(name.to_string()),
+                _ => {}
+            }
+        }
+        for (name, _) in stored {
+            let name = name.as_ref();
+            if !current
+                .iter()
+                .any(|(current_name, _)| current_name.as_ref() == name)
+            {
+                staleness.removed.push(name.to_string());
+            }
+        }
+        staleness
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.missing.is_empty() && self.removed.is_empty()
+    }
+
+    /// Says what's stale in the embeddings file at `path`, and how to fix it with `remedy`.
+    pub fn describe(&self, path: &str, remedy: &str) -> String {
+        let mut problems = Vec::new();
+        if !self.changed.is_empty() {
+            problems.push(format!(
+                "described differently since they were embedded: {}",
+                self.changed.join(", ")
+            ));
+        }
+        if !self.missing.is_empty() {
+            problems.push(format!("not embedded: {}", self.missing.join(", ")));
+        }
+        if !self.removed.is_empty() {
+            problems.push(format!("not commands anymore: {}", self.removed.join(", ")));
+        }
+        format!(
+            "The function embeddings in {} are stale ({}). {}",
+            path,
+            problems.join("; "),
+            remedy
+        )
+    }
+}
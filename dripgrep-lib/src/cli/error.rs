@@ -0,0 +1,118 @@
+use std::fmt;
+
+/// Something that stopped a search from producing results.
+///
+/// None of these end the conversation. `Search` hands the message back to the model as its tool
+/// result, so it can fix the plan or the pattern and try again.
+#[derive(Debug)]
+pub enum DripgrepError {
+    /// The search process couldn't be started at all.
+    Spawn {
+        program: String,
+        source: std::io::Error,
+    },
+    /// The pattern isn't a valid regular expression.
+    InvalidPattern { pattern: String, message: String },
+    /// The search ran, but failed without finding anything. `code` is ripgrep's exit code when it
+    /// ran as its own process, which is 2 for errors. Exit code 1 only means nothing matched, and
+    /// isn't an error.
+    Failed { code: Option<i32>, stderr: String },
+    /// The search produced output that couldn't be understood.
+    InvalidOutput(String),
+    /// The plan asks for something this search backend can't do.
+    Unsupported(String),
+}
+
+impl fmt::Display for DripgrepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DripgrepError::Spawn { program, source }
+                if source.kind() == std::io::ErrorKind::NotFound =>
+            {
+                write!(
+                    f,
+                    "Couldn't run {}, it isn't installed or isn't on the PATH.",
+                    program
+                )
+            }
+            DripgrepError::Spawn { program, source } => {
+                write!(f, "Couldn't run {}: {}", program, source)
+            }
+            DripgrepError::InvalidPattern { pattern, message } => write!(
+                f,
+                "The pattern {:?} isn't a valid regular expression: {}",
+                pattern, message
+            ),
+            DripgrepError::Failed {
+                code: Some(code),
+                stderr,
+            } => write!(f, "The search failed with exit code {}: {}", code, stderr),
+            DripgrepError::Failed { code: None, stderr } => {
+                write!(f, "The search failed: {}", stderr)
+            }
+            DripgrepError::InvalidOutput(message) => {
+                write!(f, "Couldn't read the search output: {}", message)
+            }
+            DripgrepError::Unsupported(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for DripgrepError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DripgrepError::Spawn { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn a_missing_program_says_so() {
+        let error = DripgrepError::Spawn {
+            program: String::from("rg"),
+            source: std::io::Error::from(std::io::ErrorKind::NotFound),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Couldn't run rg, it isn't installed or isn't on the PATH."
+        );
+        assert!(error.source().is_some());
+    }
+
+    #[test]
+    fn failures_include_the_exit_code_when_there_is_one() {
+        let error = DripgrepError::Failed {
+            code: Some(2),
+            stderr: String::from("rg: bad flag"),
+        };
+        assert_eq!(
+            error.to_string(),
+            "The search failed with exit code 2: rg: bad flag"
+        );
+
+        let error = DripgrepError::Failed {
+            code: None,
+            stderr: String::from("killed"),
+        };
+        assert_eq!(error.to_string(), "The search failed: killed");
+    }
+
+    #[test]
+    fn invalid_patterns_quote_the_pattern() {
+        let error = DripgrepError::InvalidPattern {
+            pattern: String::from("(foo"),
+            message: String::from("unclosed group"),
+        };
+        assert_eq!(
+            error.to_string(),
+            "The pattern \"(foo\" isn't a valid regular expression: unclosed group"
+        );
+        assert!(error.source().is_none());
+    }
+}
@@ -0,0 +1,209 @@
+use super::commands::{CommandsGPT, FUNC_ENUMS_EMBED_MODEL, FUNC_ENUMS_EMBED_PATH};
+use super::embedding_file::{EmbeddingFile, Staleness, StoredEmbedding};
+use super::local_embedding::LOCAL_EMBED_MODEL;
+use openai_func_enums::{cosine_similarity, FuncEmbedding};
+use rkyv::AlignedVec;
+use std::path::Path;
+
+/// The local model's function embeddings, which build.rs makes whenever a command changes.
+const LOCAL_EMBEDDINGS: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/local_function_embeddings.bin"));
+
+/// Where the local model's function embeddings come from, for messages about them.
+const LOCAL_EMBED_SOURCE: &str = "this build of dripgrep";
+
+/// Whether `embed_model` is the local model, which embeds without a network or a provider.
+pub fn is_local_embed_model(embed_model: &str) -> bool {
+    embed_model == LOCAL_EMBED_MODEL
+}
+
+/// Reads `path` into memory aligned the way rkyv needs to read an archive in place.
+fn read_aligned(
+    path: &Path,
+) -> Result<AlignedVec, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let bytes =
+        std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    Ok(aligned(&bytes))
+}
+
+/// Copies `bytes` into memory aligned the way rkyv needs to read an archive in place.
+fn aligned(bytes: &[u8]) -> AlignedVec {
+    let mut aligned = AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(bytes);
+    aligned
+}
+
+/// The name and description of every function there is now, as the model is offered them.
+pub fn current_functions() -> Vec<(String, String)> {
+    let (functions, _) = CommandsGPT::all_function_jsons();
+    functions
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|function| {
+            (
+                function["name"].as_str().unwrap_or_default().to_string(),
+                function["description"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            )
+        })
+        .collect()
+}
+
+/// Every function's stored embedding, and the model that made them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionEmbeddings {
+    pub model: String,
+    pub functions: Vec<StoredEmbedding>,
+}
+
+impl FunctionEmbeddings {
+    /// Loads the function embeddings prompts embedded with `embed_model` are compared against,
+    /// and rejects them if they were made with a different model, since vectors from two models
+    /// can't be compared.
+    ///
+    /// The local model's file records the model. The file the `ToolSet` macro writes doesn't
+    /// have room for it, so it's taken to be whatever build.rs told the macro to use.
+    pub fn load(
+        embed_model: &str,
+    ) -> Result<FunctionEmbeddings, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let (path, embeddings) = if is_local_embed_model(embed_model) {
+            (
+                LOCAL_EMBED_SOURCE,
+                FunctionEmbeddings::from_bytes(LOCAL_EMBEDDINGS, LOCAL_EMBED_SOURCE)?,
+            )
+        } else {
+            (
+                FUNC_ENUMS_EMBED_PATH,
+                FunctionEmbeddings::from_func_enums_file(
+                    FUNC_ENUMS_EMBED_PATH,
+                    FUNC_ENUMS_EMBED_MODEL,
+                )?,
+            )
+        };
+
+        if embeddings.model != embed_model {
+            return Err(format!(
+                "The function embeddings in {} were made with {}, so they can't be compared with \
+                 prompts embedded with {}. Set embed_model to {}, or make the embeddings again \
+                 with {}.",
+                path, embeddings.model, embed_model, embeddings.model, embed_model
+            )
+            .into());
+        }
+        Ok(embeddings)
+    }
+
+    /// Loads the function embeddings like [`FunctionEmbeddings::load`], and says what's stale
+    /// about them, if anything. Stale embeddings still work, since [`FunctionEmbeddings::rank`]
+    /// works around them, but rankings are only as good as the embeddings.
+    pub fn check(
+        embed_model: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let staleness = FunctionEmbeddings::load(embed_model)?.staleness();
+        if staleness.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(if is_local_embed_model(embed_model) {
+            staleness.describe(
+                LOCAL_EMBED_SOURCE,
+                "Build dripgrep again to make them again.",
+            )
+        } else {
+            staleness.describe(
+                FUNC_ENUMS_EMBED_PATH,
+                "Build dripgrep again with the compile_embeddings_update feature to embed them \
+                 again.",
+            )
+        }))
+    }
+
+    /// How the stored functions differ from the functions there are now.
+    pub fn staleness(&self) -> Staleness {
+        let stored: Vec<(String, String)> = self
+            .functions
+            .iter()
+            .map(|function| (function.name.clone(), function.description.clone()))
+            .collect();
+        Staleness::compare(&stored, &current_functions())
+    }
+
+    /// Reads a file written by build.rs, which records the model that made it.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<FunctionEmbeddings, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let path = path.as_ref();
+        let bytes =
+            std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        FunctionEmbeddings::from_bytes(&bytes, &path.display().to_string())
+    }
+
+    /// Reads the contents of a file written by build.rs, which came from `source`.
+    pub fn from_bytes(
+        bytes: &[u8],
+        source: &str,
+    ) -> Result<FunctionEmbeddings, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let file: EmbeddingFile = rkyv::from_bytes(&aligned(bytes))
+            .map_err(|e| format!("The embeddings in {} aren't valid: {}", source, e))?;
+        Ok(FunctionEmbeddings {
+            model: file.model,
+            functions: file.functions,
+        })
+    }
+
+    /// Reads a file written by the `ToolSet` macro, which was made with `model`.
+    pub fn from_func_enums_file<P: AsRef<Path>>(
+        path: P,
+        model: &str,
+    ) -> Result<FunctionEmbeddings, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let path = path.as_ref();
+        let bytes = read_aligned(path)?;
+        let functions: Vec<FuncEmbedding> = rkyv::from_bytes(&bytes)
+            .map_err(|e| format!("{} isn't a valid embeddings file: {}", path.display(), e))?;
+        Ok(FunctionEmbeddings {
+            model: model.to_string(),
+            functions: functions
+                .into_iter()
+                .map(|function| StoredEmbedding {
+                    name: function.name,
+                    description: function.description,
+                    embedding: function.embedding,
+                })
+                .collect(),
+        })
+    }
+
+    /// Every function's name and how similar its embedding is to `prompt_embedding`, most
+    /// similar first.
+    pub fn similarities(&self, prompt_embedding: &[f32]) -> Vec<(String, f32)> {
+        let mut similarities: Vec<(String, f32)> = self
+            .functions
+            .iter()
+            .map(|function| {
+                (
+                    function.name.clone(),
+                    cosine_similarity(&function.embedding, prompt_embedding),
+                )
+            })
+            .collect();
+        similarities.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        similarities
+    }
+
+    /// Every function's name, most similar to `prompt_embedding` first.
+    ///
+    /// Functions that aren't commands anymore are left out. Functions with no embedding can't be
+    /// ranked, so they come last, where they're still offered if there's room for them.
+    /// Functions whose description changed are ranked by their old one.
+    pub fn rank(&self, prompt_embedding: &[f32]) -> Vec<String> {
+        let staleness = self.staleness();
+        self.similarities(prompt_embedding)
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| !staleness.removed.contains(name))
+            .chain(staleness.missing)
+            .collect()
+    }
+}
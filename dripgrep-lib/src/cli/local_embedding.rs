@@ -0,0 +1,114 @@
+// This file is also built into build.rs, which makes the function embeddings for the local model,
+// so it can't depend on anything in the crate but `embedding_file`, which build.rs builds too.
+use super::embedding_file::fnv1a;
+use std::collections::BTreeMap;
+
+/// The name of the local embedding model, as it goes in `embed_model` and is recorded in the
+/// embeddings file. Anything that changes the vectors [`embed`] makes needs a new name, so that
+/// embeddings made the old way are rejected rather than compared with new ones.
+pub const LOCAL_EMBED_MODEL: &str = "local-ngram-512";
+
+/// How many numbers are in a local embedding.
+pub const LOCAL_EMBED_DIMENSIONS: usize = 512;
+
+/// Words too common to say anything about which function a prompt wants.
+const STOP_WORDS: [&str; 24] = [
+    "a", "an", "and", "are", "as", "be", "by", "for", "from", "i", "in", "is", "it", "me", "of",
+    "on", "or", "the", "to", "what", "whether", "which", "with", "want",
+];
+
+/// The text a function is embedded from: its name split into words, then its description.
+///
+/// ```
+/// use dripgrep_lib::cli::local_embedding::function_text;
+///
+/// assert_eq!(function_text("SortResultsBy", "Sorts results"), "Sort Results By Sorts results");
+/// assert_eq!(function_text("CRLF", ""), "CRLF");
+/// ```
+pub fn function_text(name: &str, description: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut words = String::new();
+    for (index, c) in chars.iter().enumerate() {
+        let after_lowercase = index > 0 && chars[index - 1].is_lowercase();
+        let starts_word_after_acronym = index > 0
+            && chars[index - 1].is_uppercase()
+            && chars.get(index + 1).is_some_and(|next| next.is_lowercase());
+        if c.is_uppercase() && (after_lowercase || starts_word_after_acronym) {
+            words.push(' ');
+        }
+        words.push(*c);
+    }
+
+    if description.trim().is_empty() {
+        words
+    } else {
+        format!("{} {}", words, description.trim())
+    }
+}
+
+/// Embeds `text` without a model: every word, pair of neighbouring words and three-letter piece
+/// of a word is hashed to one of [`LOCAL_EMBED_DIMENSIONS`] places, and the vector is scaled to a
+/// length of one. Texts that share words, or parts of words like "sort" and "sorted", point the
+/// same way. Each feature counts once however often it comes up, so a description that repeats a
+/// word doesn't outweigh one that uses it once, and plurals count as the word itself.
+///
+/// ```
+/// use dripgrep_lib::cli::local_embedding::{embed, function_text};
+/// use openai_func_enums::cosine_similarity;
+///
+/// let prompt = embed("sort the results by when they were modified");
+/// let sort = embed(&function_text("SortResultsBy", "Sorts results by the given method"));
+/// let threads = embed(&function_text("Threads", "Sets the number of threads to use"));
+/// assert!(cosine_similarity(&prompt, &sort) > cosine_similarity(&prompt, &threads));
+/// assert_eq!(prompt, embed("Sort the results by when they were modified."));
+/// ```
+pub fn embed(text: &str) -> Vec<f32> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+        .map(|word| match word.strip_suffix('s') {
+            Some(singular) if singular.len() > 2 && !singular.ends_with('s') => {
+                singular.to_string()
+            }
+            _ => word,
+        })
+        .collect();
+
+    let mut features: BTreeMap<String, f32> = BTreeMap::new();
+    for word in &words {
+        features.insert(format!("w:{}", word), 1.0);
+
+        let padded: Vec<char> = format!("<{}>", word).chars().collect();
+        for piece in padded.windows(3) {
+            let piece: String = piece.iter().collect();
+            features.insert(format!("c:{}", piece), 0.5);
+        }
+    }
+    for pair in words.windows(2) {
+        features.insert(format!("b:{} {}", pair[0], pair[1]), 0.5);
+    }
+
+    let mut vector = vec![0.0_f32; LOCAL_EMBED_DIMENSIONS];
+    for (feature, weight) in &features {
+        add_feature(&mut vector, feature, *weight);
+    }
+
+    let length = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if length > 0.0 {
+        for x in &mut vector {
+            *x /= length;
+        }
+    }
+    vector
+}
+
+/// Adds `weight` to the place `feature` hashes to, or takes it away, depending on the hash, so
+/// that features that land in the same place tend to cancel out rather than pile up.
+fn add_feature(vector: &mut [f32], feature: &str, weight: f32) {
+    let hash = fnv1a(feature.as_bytes());
+    let index = (hash % LOCAL_EMBED_DIMENSIONS as u64) as usize;
+    let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+    vector[index] += sign * weight;
+}
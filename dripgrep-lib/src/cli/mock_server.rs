@@ -0,0 +1,354 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// A scripted tool call the mock server will answer a chat completion request with.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MockToolCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: Value,
+}
+
+/// One scripted chat completion response. A response with no tool calls ends the conversation
+/// the way a model answering in plain text would.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MockChatResponse {
+    /// Only answer a request whose latest user message contains this text. Steps that run at
+    /// the same time send their requests in no particular order, so their replies need this to
+    /// go to the right step.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<MockToolCall>,
+}
+
+/// The script a mock server follows.
+///
+/// A chat completion request is answered with the first unused entry of `chat` whose `prompt`
+/// it matches, or else the first unused entry without a `prompt`. Embedding requests are
+/// answered with the entries of `embeddings` in order. Once there's nothing left to answer with,
+/// chat requests get a plain "Done." and embedding requests get a zero vector of
+/// `embedding_dimensions` values, which leaves the function ranking in its stored order.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MockFixture {
+    #[serde(default)]
+    pub chat: Vec<MockChatResponse>,
+    #[serde(default)]
+    pub embeddings: Vec<Vec<f32>>,
+    #[serde(default = "default_embedding_dimensions")]
+    pub embedding_dimensions: usize,
+}
+
+fn default_embedding_dimensions() -> usize {
+    // The size of text-embedding-3-small vectors, which is what the stored embeddings use.
+    1536
+}
+
+impl MockFixture {
+    pub fn from_file(
+        path: &Path,
+    ) -> Result<MockFixture, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    chat_used: Vec<bool>,
+    embedding_index: usize,
+}
+
+impl MockState {
+    /// Picks the scripted reply for `request` and marks it used, or `None` if there isn't one.
+    fn next_chat(&mut self, fixture: &MockFixture, request: &Value) -> Option<usize> {
+        self.chat_used.resize(fixture.chat.len(), false);
+        let latest_prompt = latest_user_message(request);
+        let unused = |index: &usize| !self.chat_used[*index];
+        let index = (0..fixture.chat.len())
+            .filter(unused)
+            .find(|index| {
+                fixture.chat[*index]
+                    .prompt
+                    .as_deref()
+                    .is_some_and(|prompt| latest_prompt.contains(prompt))
+            })
+            .or_else(|| {
+                (0..fixture.chat.len())
+                    .filter(unused)
+                    .find(|index| fixture.chat[*index].prompt.is_none())
+            })?;
+        self.chat_used[index] = true;
+        Some(index)
+    }
+}
+
+/// The text of the last user message in a chat completion request.
+fn latest_user_message(request: &Value) -> String {
+    request["messages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .rfind(|message| message["role"] == "user")
+        .map(|message| match &message["content"] {
+            Value::String(content) => content.clone(),
+            content => content.to_string(),
+        })
+        .unwrap_or_default()
+}
+
+/// A local stand-in for the OpenAI chat completions and embeddings endpoints.
+///
+/// Point dripgrep at [`MockServer::base_url`] (with `--base-url`, or in-process with `--mock`) to
+/// run a whole conversation without a network connection or an API key.
+///
+/// ```no_run
+/// use dripgrep_lib::cli::mock_server::{MockFixture, MockServer};
+/// use dripgrep_lib::cli::provider::set_api_base;
+/// use std::path::Path;
+///
+/// async fn example_usage() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+///     let fixture = MockFixture::from_file(Path::new("fixtures/text_file_search.json"))?;
+///     let server = MockServer::start(fixture, "127.0.0.1:0").await?;
+///     set_api_base(server.base_url());
+///     Ok(())
+/// }
+/// ```
+pub struct MockServer {
+    address: SocketAddr,
+    handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Binds to `address` and starts answering requests in the background. Use port 0 to let
+    /// the OS pick a free port.
+    pub async fn start(fixture: MockFixture, address: &str) -> std::io::Result<MockServer> {
+        let listener = TcpListener::bind(address).await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let fixture = Arc::new(fixture);
+
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let fixture = fixture.clone();
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let _ = handle_connection(stream, fixture, state).await;
+                });
+            }
+        });
+
+        Ok(MockServer { address, handle })
+    }
+
+    /// The API base to hand to [`set_api_base`](super::provider::set_api_base).
+    pub fn base_url(&self) -> String {
+        format!("http://{}/v1", self.address)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    fixture: Arc<MockFixture>,
+    state: Arc<Mutex<MockState>>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+
+    // The client keeps connections alive, so keep answering until it hangs up.
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).await? == 0 {
+            return Ok(());
+        }
+        let path = request_line
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or_default()
+            .to_string();
+
+        let mut content_length = 0_usize;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).await? == 0 {
+                return Ok(());
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+
+        let mut body = vec![0_u8; content_length];
+        reader.read_exact(&mut body).await?;
+        let request: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+
+        let (status, response) = {
+            let mut state = state.lock().await;
+
+            if path.ends_with("/chat/completions") {
+                let index = state.next_chat(&fixture, &request);
+                ("200 OK", chat_response(&fixture, index, &request))
+            } else if path.ends_with("/embeddings") {
+                let index = state.embedding_index;
+                state.embedding_index += 1;
+                ("200 OK", embedding_response(&fixture, index, &request))
+            } else {
+                (
+                    "404 Not Found",
+                    json!({ "error": { "message": format!("No mock for {}", path) } }),
+                )
+            }
+        };
+
+        let response = response.to_string();
+        let message = format!(
+            "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+            status,
+            response.len(),
+            response
+        );
+        reader.get_mut().write_all(message.as_bytes()).await?;
+    }
+}
+
+fn chat_response(fixture: &MockFixture, index: Option<usize>, request: &Value) -> Value {
+    let scripted = index
+        .map(|index| fixture.chat[index].clone())
+        .unwrap_or(MockChatResponse {
+            content: Some(String::from("Done.")),
+            ..MockChatResponse::default()
+        });
+    let id = match index {
+        Some(index) => index.to_string(),
+        None => String::from("done"),
+    };
+
+    let tool_calls: Vec<Value> = scripted
+        .tool_calls
+        .iter()
+        .enumerate()
+        .map(|(i, tool_call)| {
+            json!({
+                "id": format!("call_{}_{}", id, i),
+                "type": "function",
+                "function": {
+                    "name": tool_call.name,
+                    "arguments": tool_call.arguments.to_string(),
+                }
+            })
+        })
+        .collect();
+
+    let finish_reason = if tool_calls.is_empty() {
+        "stop"
+    } else {
+        "tool_calls"
+    };
+
+    json!({
+        "id": format!("mock-chat-{}", id),
+        "object": "chat.completion",
+        "created": 0,
+        "model": request["model"],
+        "choices": [{
+            "index": 0,
+            "message": {
+                "role": "assistant",
+                "content": scripted.content,
+                "tool_calls": if tool_calls.is_empty() { Value::Null } else { Value::Array(tool_calls) },
+            },
+            "finish_reason": finish_reason,
+        }],
+        "usage": { "prompt_tokens": 0, "completion_tokens": 0, "total_tokens": 0 },
+    })
+}
+
+fn embedding_response(fixture: &MockFixture, index: usize, request: &Value) -> Value {
+    let embedding = fixture
+        .embeddings
+        .get(index)
+        .cloned()
+        .unwrap_or_else(|| vec![0.0; fixture.embedding_dimensions]);
+
+    json!({
+        "object": "list",
+        "model": request["model"],
+        "data": [{ "index": 0, "object": "embedding", "embedding": embedding }],
+        "usage": { "prompt_tokens": 0, "total_tokens": 0 },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(prompt: Option<&str>, content: &str) -> MockChatResponse {
+        MockChatResponse {
+            prompt: prompt.map(String::from),
+            content: Some(content.to_string()),
+            tool_calls: Vec::new(),
+        }
+    }
+
+    fn request(prompt: &str) -> Value {
+        json!({ "messages": [
+            { "role": "system", "content": "Be helpful." },
+            { "role": "user", "content": prompt },
+            { "role": "tool", "content": "Ok." },
+        ] })
+    }
+
+    #[test]
+    fn replies_go_to_the_request_they_match() {
+        let fixture = MockFixture {
+            chat: vec![
+                reply(None, "first"),
+                reply(Some("Ignore case."), "case"),
+                reply(Some("Only search text files."), "types"),
+            ],
+            embeddings: Vec::new(),
+            embedding_dimensions: 4,
+        };
+        let mut state = MockState::default();
+
+        assert_eq!(state.next_chat(&fixture, &request("Plan this")), Some(0));
+        assert_eq!(
+            state.next_chat(&fixture, &request("Only search text files.")),
+            Some(2)
+        );
+        assert_eq!(state.next_chat(&fixture, &request("Ignore case.")), Some(1));
+        assert_eq!(state.next_chat(&fixture, &request("Ignore case.")), None);
+    }
+
+    #[test]
+    fn finished_scripts_answer_done() {
+        let fixture = MockFixture {
+            chat: Vec::new(),
+            embeddings: Vec::new(),
+            embedding_dimensions: 4,
+        };
+        let response = chat_response(&fixture, None, &request("Anything"));
+        assert_eq!(response["choices"][0]["message"]["content"], "Done.");
+        assert_eq!(response["choices"][0]["finish_reason"], "stop");
+    }
+}
@@ -1,5 +1,31 @@
+pub mod args;
+pub mod call_context;
+pub mod call_guard;
+pub mod cassette;
+pub mod chat;
+pub mod check_in;
 pub mod cli_entry;
 pub mod command_funcs;
 pub mod commands;
+pub mod config;
+pub mod confirm;
+pub mod context_refresh;
+pub mod dry_run;
+#[cfg(feature = "embedded_search")]
+pub mod embedded_search;
+pub mod embedding_file;
+pub mod error;
+pub mod function_embeddings;
+pub mod local_embedding;
 pub mod logging;
-pub mod args;
+pub mod mock_server;
+pub mod model_client;
+pub mod multi_step;
+pub mod offline_planner;
+pub mod plan_verification;
+pub mod provider;
+pub mod rank;
+pub mod run_context;
+pub mod search_plan;
+pub mod search_results;
+pub mod usage;
@@ -0,0 +1,575 @@
+use super::call_context::merge_arguments;
+use super::call_guard::{record_answer, take_model_call};
+use super::cassette::cassette;
+use super::commands::{CommandKind, Commands, CommandsGPT, FunctionResponse};
+use super::config::config;
+use super::function_embeddings::is_local_embed_model;
+use super::local_embedding;
+use super::provider::provider;
+use super::usage::{count_tokens, record, request_tokens, reserve_cost, response_tokens};
+use async_openai::types::{
+    ChatCompletionNamedToolChoice, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+    ChatCompletionTool, ChatCompletionToolArgs, ChatCompletionToolChoiceOption,
+    ChatCompletionToolType, CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
+    CreateChatCompletionResponse, CreateEmbeddingRequestArgs, FunctionCall, FunctionName,
+    FunctionObjectArgs,
+};
+use openai_func_enums::{
+    get_tool_chat_completion_args, get_tools_limited, CommandError, FuncEnumsError, Logger,
+    RunCommand, ToolCallExecutionStrategy,
+};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Gets an embedding vector for `text`. This does the same thing as
+/// `openai_func_enums::single_embedding`, but goes to the configured provider, or embeds `text`
+/// on the spot if `model` is the local one.
+pub async fn embedding(
+    text: &str,
+    model: &str,
+) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    if is_local_embed_model(model) {
+        return Ok(local_embedding::embed(text));
+    }
+
+    let request = CreateEmbeddingRequestArgs::default()
+        .model(model)
+        .input([text])
+        .build()?;
+
+    let prompt_tokens = count_tokens(text);
+    let _reservation = reserve_cost(model, prompt_tokens, 0)?;
+
+    let response = match cassette() {
+        Some(cassette) => {
+            cassette
+                .exchange("embedding", &request, || async {
+                    provider().embed(request.clone()).await
+                })
+                .await?
+        }
+        None => provider().embed(request).await?,
+    };
+
+    // Stand-in servers don't always count tokens, so the estimate covers for them.
+    let billed = response.usage.prompt_tokens as usize;
+    record(model, if billed > 0 { billed } else { prompt_tokens }, 0);
+
+    match response.data.first() {
+        Some(data) => Ok(data.embedding.to_owned()),
+        None => Err(Box::new(FuncEnumsError::OpenAIError(String::from(
+            "Didn't get embedding vector back.",
+        )))),
+    }
+}
+
+/// Sends a chat completion request to the configured provider, through the cassette if there is
+/// one. Every request counts against the model call budget and the cost budget, and isn't sent
+/// if it doesn't fit in either.
+pub async fn chat_completion(
+    request: CreateChatCompletionRequest,
+) -> Result<CreateChatCompletionResponse, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let model = request.model.clone();
+    let prompt_tokens = request_tokens(&request);
+    let _reservation = reserve_cost(
+        &model,
+        prompt_tokens,
+        request.max_tokens.unwrap_or(config().max_response_tokens) as usize,
+    )?;
+    take_model_call()?;
+
+    let response = match cassette() {
+        Some(cassette) => {
+            cassette
+                .exchange("chat", &request, || async {
+                    provider().chat(request.clone()).await
+                })
+                .await?
+        }
+        None => provider().chat(request).await?,
+    };
+
+    // Stand-in servers don't always count tokens, so the estimates cover for them.
+    match &response.usage {
+        Some(usage) if usage.total_tokens > 0 => record(
+            &model,
+            usage.prompt_tokens as usize,
+            usage.completion_tokens as usize,
+        ),
+        _ => record(&model, prompt_tokens, response_tokens(&response)),
+    }
+    Ok(response)
+}
+
+/// Makes the model answer `prompt` by calling a single function, described by `name`,
+/// `description` and the JSON schema in `parameters`, and returns the arguments it called it with.
+///
+/// This is for asking the model questions about a request rather than acting on it, so the
+/// answer is never run as a command.
+pub async fn call_function(
+    model_name: &str,
+    system_message: String,
+    prompt: &str,
+    name: &str,
+    description: &str,
+    parameters: serde_json::Value,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let tool = ChatCompletionToolArgs::default()
+        .r#type(ChatCompletionToolType::Function)
+        .function(
+            FunctionObjectArgs::default()
+                .name(name)
+                .description(description)
+                .parameters(parameters)
+                .build()?,
+        )
+        .build()?;
+
+    let request = CreateChatCompletionRequestArgs::default()
+        .max_tokens(config().max_response_tokens)
+        .model(model_name)
+        .temperature(0.0)
+        .messages([
+            ChatCompletionRequestMessage::System(
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(system_message)
+                    .build()?,
+            ),
+            ChatCompletionRequestMessage::User(
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(prompt.to_string())
+                    .build()?,
+            ),
+        ])
+        .tools([tool])
+        .tool_choice(ChatCompletionToolChoiceOption::Named(
+            ChatCompletionNamedToolChoice {
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionName {
+                    name: name.to_string(),
+                },
+            },
+        ))
+        .build()?;
+
+    let response = chat_completion(request).await?;
+
+    response
+        .choices
+        .first()
+        .and_then(|choice| choice.message.tool_calls.as_ref())
+        .and_then(|tool_calls| {
+            tool_calls
+                .iter()
+                .find(|tool_call| tool_call.function.name == name)
+        })
+        .map(|tool_call| tool_call.function.arguments.clone())
+        .ok_or_else(|| {
+            Box::new(CommandError::new(&format!(
+                "The model didn't call {}",
+                name
+            ))) as Box<dyn std::error::Error + Send + Sync + 'static>
+        })
+}
+
+/// Presents the tool set to the model with `prompt` and runs whatever tools it calls.
+///
+/// This mirrors the `CommandsGPT::run` that `ToolSet` generates, and takes the same arguments,
+/// but sends requests through [`provider`] so they can go somewhere other than the OpenAI API.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_tools(
+    prompt: &String,
+    model_name: &str,
+    request_token_limit: Option<usize>,
+    max_response_tokens: Option<u16>,
+    custom_system_message: Option<(String, usize)>,
+    prior_result: Arc<Mutex<Option<String>>>,
+    execution_strategy: ToolCallExecutionStrategy,
+    command: Arc<Mutex<Option<Vec<String>>>>,
+    allowed_functions: Option<Vec<String>>,
+    required_functions: Option<Vec<String>>,
+    logger: Arc<Logger>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let tool_args = tools(allowed_functions, required_functions)?;
+
+    let (system_message, system_message_tokens) = match custom_system_message.clone() {
+        Some((message, tokens)) => (message, tokens),
+        None => (String::from("You are a helpful function calling bot."), 7),
+    };
+
+    // Same estimate the generated run uses: word counts are close enough for short prompts.
+    let word_count = prompt.split_whitespace().count();
+    let prompt_tokens = if word_count < 200 {
+        (word_count as f64 / 0.75).round() as usize
+    } else {
+        tiktoken_rs::cl100k_base()?
+            .encode_ordinary(prompt.as_str())
+            .len()
+    };
+
+    let request_token_total = tool_args.1 + system_message_tokens + prompt_tokens;
+    if request_token_total > request_token_limit.unwrap_or(config().max_request_tokens) {
+        return Err(Box::new(CommandError::new(
+            "Request token count is too high",
+        )));
+    }
+
+    let request = CreateChatCompletionRequestArgs::default()
+        .max_tokens(max_response_tokens.unwrap_or(config().max_response_tokens))
+        .model(model_name)
+        .temperature(0.0)
+        .messages([
+            ChatCompletionRequestMessage::System(
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(system_message)
+                    .build()?,
+            ),
+            ChatCompletionRequestMessage::User(
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(prompt.to_string())
+                    .build()?,
+            ),
+        ])
+        .tools(tool_args.0)
+        .tool_choice("auto")
+        .build()?;
+
+    let response = chat_completion(request).await?;
+    record_answer();
+
+    let response_message = match response.choices.first() {
+        Some(choice) => choice.message.clone(),
+        None => return Err(Box::new(CommandError::new("Model returned no choices"))),
+    };
+
+    let tool_calls = match response_message.tool_calls {
+        Some(tool_calls) => tool_calls,
+        // The model answered in words instead of calling anything, so that answer is the result.
+        None => {
+            if let Some(content) = response_message.content {
+                logger.log(content.clone()).await;
+                *prior_result.lock().await = Some(content);
+            }
+            return Ok(());
+        }
+    };
+
+    if tool_calls.len() == 1 {
+        let function = tool_calls[0].function.clone();
+        return run_tool_call(
+            function,
+            execution_strategy,
+            prior_result,
+            command,
+            logger,
+            custom_system_message,
+        )
+        .await;
+    }
+
+    // Settings have to be in the plan before anything searches with it, however the calls are
+    // run, so every preparation call in the response finishes before the rest start.
+    let (preparation, rest): (Vec<FunctionCall>, Vec<FunctionCall>) = tool_calls
+        .into_iter()
+        .map(|tool_call| tool_call.function)
+        .partition(|function| kind(function) == CommandKind::Preparation);
+
+    for functions in [preparation, rest] {
+        run_tool_calls(
+            functions,
+            execution_strategy.clone(),
+            prior_result.clone(),
+            command.clone(),
+            logger.clone(),
+            custom_system_message.clone(),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Runs the tool calls from one response using `execution_strategy`, logging any that fail.
+async fn run_tool_calls(
+    functions: Vec<FunctionCall>,
+    execution_strategy: ToolCallExecutionStrategy,
+    prior_result: Arc<Mutex<Option<String>>>,
+    command: Arc<Mutex<Option<Vec<String>>>>,
+    logger: Arc<Logger>,
+    custom_system_message: Option<(String, usize)>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    match execution_strategy {
+        ToolCallExecutionStrategy::Async => {
+            let mut tasks = Vec::new();
+            for function in functions {
+                tasks.push(tokio::spawn(run_tool_call(
+                    function,
+                    execution_strategy.clone(),
+                    prior_result.clone(),
+                    command.clone(),
+                    logger.clone(),
+                    custom_system_message.clone(),
+                )));
+            }
+
+            for task in tasks {
+                log_failure(&logger, task.await?).await;
+            }
+        }
+
+        ToolCallExecutionStrategy::Synchronous => {
+            for function in functions {
+                let run_result = run_tool_call(
+                    function,
+                    execution_strategy.clone(),
+                    prior_result.clone(),
+                    command.clone(),
+                    logger.clone(),
+                    custom_system_message.clone(),
+                )
+                .await;
+                log_failure(&logger, run_result).await;
+            }
+        }
+
+        ToolCallExecutionStrategy::Parallel => {
+            let mut handles = Vec::new();
+            for function in functions {
+                let prior_result = prior_result.clone();
+                let command = command.clone();
+                let logger = logger.clone();
+                let custom_system_message = custom_system_message.clone();
+
+                // Only the tool calls in this response get their own threads. Anything they
+                // start themselves runs as async tasks on that thread's runtime.
+                handles.push(std::thread::spawn(move || {
+                    let rt = tokio::runtime::Runtime::new()?;
+                    rt.block_on(run_tool_call(
+                        function,
+                        ToolCallExecutionStrategy::Async,
+                        prior_result,
+                        command,
+                        logger,
+                        custom_system_message,
+                    ))
+                }));
+            }
+
+            for handle in handles {
+                let run_result = handle
+                    .join()
+                    .map_err(|_| CommandError::new("Tool call thread panicked"))?;
+                log_failure(&logger, run_result).await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The names of the functions [`run_tools`] would offer the model given `allowed_functions` and
+/// `required_functions`, in the order they'd be offered, and the tokens they'd take up.
+pub fn offered_functions(
+    allowed_functions: Option<Vec<String>>,
+    required_functions: Option<Vec<String>>,
+) -> Result<(Vec<String>, usize), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (tools, tokens) = tools(allowed_functions, required_functions)?;
+    let names = tools.into_iter().map(|tool| tool.function.name).collect();
+    Ok((names, tokens))
+}
+
+fn tools(
+    allowed_functions: Option<Vec<String>>,
+    required_functions: Option<Vec<String>>,
+) -> Result<(Vec<ChatCompletionTool>, usize), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let allowed_functions = match allowed_functions {
+        Some(allowed_functions) if !allowed_functions.is_empty() => allowed_functions,
+        _ => {
+            return Ok(get_tool_chat_completion_args(
+                CommandsGPT::all_function_jsons,
+            )?)
+        }
+    };
+
+    #[cfg(feature = "function_filtering")]
+    let tools = get_tools_limited(
+        function_jsons_with_required_under_limit,
+        allowed_functions,
+        required_functions,
+    )?;
+
+    #[cfg(not(feature = "function_filtering"))]
+    let tools = get_tools_limited(
+        CommandsGPT::function_jsons_allowed_with_required,
+        allowed_functions,
+        required_functions,
+    )?;
+
+    Ok(tools)
+}
+
+/// Does what the generated `CommandsGPT::function_jsons_with_required_under_limit` does, but with
+/// the configured `max_func_tokens` instead of the one compiled in: the required functions, then
+/// the ranked ones, for as long as they fit.
+#[cfg(feature = "function_filtering")]
+fn function_jsons_with_required_under_limit(
+    ranked_func_names: Vec<String>,
+    required_func_names: Option<Vec<String>>,
+) -> (serde_json::Value, usize) {
+    let required_func_names = required_func_names.unwrap_or_default();
+    let limit = config().max_func_tokens as usize;
+
+    let mut functions = Vec::new();
+    let mut total_tokens = 0;
+    for name in required_func_names.iter().chain(
+        ranked_func_names
+            .iter()
+            .filter(|name| !required_func_names.contains(name)),
+    ) {
+        let (function, tokens) =
+            CommandsGPT::function_jsons_allowed_with_required(vec![name.clone()], None);
+        let Some(function) = function.as_array().and_then(|array| array.first()) else {
+            continue;
+        };
+        if total_tokens + tokens <= limit {
+            functions.push(function.clone());
+            total_tokens += tokens;
+        }
+    }
+    (serde_json::Value::Array(functions), total_tokens)
+}
+
+/// Runs a single tool call against the shared state for this request.
+///
+/// The call starts from a snapshot of the shared plan, and only what it changed is merged back
+/// when it finishes, so calls from the same response, and steps from the same round of a plan,
+/// run at the same time without dropping each other's settings. `CallMultiStep` and `GPT` start
+/// their own conversations with plans of their own, which begin from the snapshot too.
+async fn run_tool_call(
+    function: FunctionCall,
+    execution_strategy: ToolCallExecutionStrategy,
+    prior_result: Arc<Mutex<Option<String>>>,
+    command: Arc<Mutex<Option<Vec<String>>>>,
+    logger: Arc<Logger>,
+    custom_system_message: Option<(String, usize)>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let tool = into_command(CommandsGPT::parse_gpt_function_call(&function)?);
+
+    let run_result = run_on_snapshot(&command, |arguments| {
+        tool.run(execution_strategy, arguments, logger, custom_system_message)
+    })
+    .await?;
+
+    *prior_result.lock().await = run_result;
+    Ok(())
+}
+
+/// Runs `run` on a copy of the arguments in `command`, without holding the lock, then merges
+/// what it changed back in.
+async fn run_on_snapshot<F, Fut>(
+    command: &Mutex<Option<Vec<String>>>,
+    run: F,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>>
+where
+    F: FnOnce(Option<Vec<String>>) -> Fut,
+    Fut: Future<
+        Output = Result<
+            (Option<String>, Option<Vec<String>>),
+            Box<dyn std::error::Error + Send + Sync + 'static>,
+        >,
+    >,
+{
+    let base = command.lock().await.clone();
+    let (run_result, arguments) = run(base.clone()).await?;
+
+    let mut command_lock = command.lock().await;
+    *command_lock = merge_arguments(command_lock.take(), &base, arguments);
+    Ok(run_result)
+}
+
+async fn log_failure(
+    logger: &Logger,
+    run_result: Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>,
+) {
+    if let Err(e) = run_result {
+        logger.log(format!("Tool call failed: {}", e)).await;
+    }
+}
+
+/// The kind of command `function` calls. A call that can't be parsed fails when it runs, so it's
+/// treated as an action and left until last.
+fn kind(function: &FunctionCall) -> CommandKind {
+    CommandsGPT::parse_gpt_function_call(function)
+        .map(|response| into_command(response).kind())
+        .unwrap_or(CommandKind::Action)
+}
+
+fn into_command(response: FunctionResponse) -> Commands {
+    match response {
+        FunctionResponse::CaseFilter(response) => response.execute_command(),
+        FunctionResponse::ContextLines(response) => response.execute_command(),
+        FunctionResponse::CRLF(response) => response.execute_command(),
+        FunctionResponse::Debug(response) => response.execute_command(),
+        FunctionResponse::DotAll(response) => response.execute_command(),
+        FunctionResponse::Files(response) => response.execute_command(),
+        FunctionResponse::FileTypeFilter(response) => response.execute_command(),
+        FunctionResponse::FixedStrings(response) => response.execute_command(),
+        FunctionResponse::IncludeZip(response) => response.execute_command(),
+        FunctionResponse::Multiline(response) => response.execute_command(),
+        FunctionResponse::Replace(response) => response.execute_command(),
+        FunctionResponse::RestrictionLevel(response) => response.execute_command(),
+        FunctionResponse::SortResultsBy(response) => response.execute_command(),
+        FunctionResponse::Statistics(response) => response.execute_command(),
+        FunctionResponse::Threads(response) => response.execute_command(),
+        FunctionResponse::TraceData(response) => response.execute_command(),
+        FunctionResponse::TreatBinaryAsText(response) => response.execute_command(),
+        FunctionResponse::TrimWhitespace(response) => response.execute_command(),
+        FunctionResponse::Search(response) => response.execute_command(),
+        FunctionResponse::CallMultiStep(response) => response.execute_command(),
+        FunctionResponse::GPT(response) => response.execute_command(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::call_context::CallContext;
+    use std::time::Duration;
+    use tokio::sync::Barrier;
+
+    #[tokio::test]
+    async fn calls_on_the_same_plan_run_at_the_same_time() {
+        let command = Arc::new(Mutex::new(CallContext::default().into_arguments()));
+        // Neither call can get past the barrier until the other has started, so this only
+        // finishes if the plan isn't held while they run.
+        let barrier = Arc::new(Barrier::new(2));
+
+        let call = |set: fn(&mut CallContext)| {
+            let command = command.clone();
+            let barrier = barrier.clone();
+            async move {
+                run_on_snapshot(&command, |arguments| async move {
+                    barrier.wait().await;
+                    let mut context = CallContext::from_arguments(arguments)?;
+                    set(&mut context);
+                    Ok((None, context.into_arguments()))
+                })
+                .await
+            }
+        };
+        let first = tokio::spawn(call(|context| context.plan.statistics = Some(true)));
+        let second = tokio::spawn(call(|context| context.plan.trim_whitespace = Some(true)));
+        let both = async { tokio::join!(first, second) };
+        let (first, second) = tokio::time::timeout(Duration::from_secs(5), both)
+            .await
+            .expect("the calls waited on each other");
+        first.unwrap().unwrap();
+        second.unwrap().unwrap();
+
+        let context = CallContext::from_arguments(command.lock().await.clone()).unwrap();
+        assert_eq!(context.plan.statistics, Some(true));
+        assert_eq!(context.plan.trim_whitespace, Some(true));
+    }
+}
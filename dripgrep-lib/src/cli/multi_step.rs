@@ -0,0 +1,253 @@
+/// Which steps of a `CallMultiStep` plan wait on which.
+///
+/// Steps can only depend on steps that come before them, so the graph can't have cycles, and
+/// running whatever is [`StepGraph::ready`] until nothing is always respects every dependency.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepGraph {
+    /// For each step, the steps it waits on, counting from 0.
+    pub dependencies: Vec<Vec<usize>>,
+}
+
+impl StepGraph {
+    /// Builds the graph for `step_count` steps from the model's `depends_on` list.
+    ///
+    /// Each entry lists the steps, counting from 1, that the step at the same position waits on,
+    /// separated by commas. An empty entry means the step waits on nothing. A step with no entry
+    /// at all waits on the step before it, so a plan without `depends_on` runs one step at a time.
+    ///
+    /// ```
+    /// use dripgrep_lib::cli::multi_step::StepGraph;
+    ///
+    /// let depends_on = vec![String::new(), String::new(), String::from("1, 2")];
+    /// let graph = StepGraph::parse(3, &depends_on).unwrap();
+    /// assert_eq!(graph.rounds(), vec![vec![0, 1], vec![2]]);
+    ///
+    /// let graph = StepGraph::parse(3, &[]).unwrap();
+    /// assert_eq!(graph.rounds(), vec![vec![0], vec![1], vec![2]]);
+    ///
+    /// assert!(StepGraph::parse(2, &[String::from("2")]).is_err());
+    /// ```
+    pub fn parse(step_count: usize, depends_on: &[String]) -> Result<StepGraph, String> {
+        if depends_on.len() > step_count {
+            return Err(format!(
+                "depends_on has {} entries, but there are only {} steps.",
+                depends_on.len(),
+                step_count
+            ));
+        }
+
+        let mut dependencies = Vec::with_capacity(step_count);
+        for step in 0..step_count {
+            let entry = match depends_on.get(step) {
+                Some(entry) => entry,
+                None => {
+                    dependencies.push(step.checked_sub(1).into_iter().collect());
+                    continue;
+                }
+            };
+
+            let mut waits_on = Vec::new();
+            for number in entry.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                let dependency = match number.parse::<usize>() {
+                    Ok(dependency) if dependency >= 1 && dependency <= step => dependency - 1,
+                    _ => {
+                        return Err(format!(
+                            "Step {} can only depend on earlier steps, numbered from 1, but \
+                             depends_on lists \"{}\".",
+                            step + 1,
+                            number
+                        ))
+                    }
+                };
+                if !waits_on.contains(&dependency) {
+                    waits_on.push(dependency);
+                }
+            }
+            dependencies.push(waits_on);
+        }
+
+        Ok(StepGraph { dependencies })
+    }
+
+    /// Groups the steps into rounds that can each run all at once. Every step is in the round
+    /// after the last of its dependencies.
+    pub fn rounds(&self) -> Vec<Vec<usize>> {
+        let mut round_of: Vec<usize> = Vec::with_capacity(self.dependencies.len());
+        let mut rounds: Vec<Vec<usize>> = Vec::new();
+
+        for (step, waits_on) in self.dependencies.iter().enumerate() {
+            let round = waits_on
+                .iter()
+                .map(|dependency| round_of[*dependency] + 1)
+                .max()
+                .unwrap_or(0);
+            round_of.push(round);
+
+            if rounds.len() <= round {
+                rounds.resize(round + 1, Vec::new());
+            }
+            rounds[round].push(step);
+        }
+
+        rounds
+    }
+
+    /// The steps that haven't finished and aren't waiting on anything that hasn't, in order.
+    /// These can all run at once.
+    pub fn ready(&self, finished: &[bool]) -> Vec<usize> {
+        (0..self.dependencies.len())
+            .filter(|step| !finished[*step])
+            .filter(|step| {
+                self.dependencies[*step]
+                    .iter()
+                    .all(|dependency| finished[*dependency])
+            })
+            .collect()
+    }
+
+    /// Adds a step that waits on `dependencies`, and returns its index.
+    pub fn push(&mut self, dependencies: Vec<usize>) -> usize {
+        self.dependencies.push(dependencies);
+        self.dependencies.len() - 1
+    }
+
+    /// Of the steps that `ran`, the ones no other step that ran waits on, in order. Their
+    /// results are the plan's result.
+    pub fn final_steps(&self, ran: &[bool]) -> Vec<usize> {
+        (0..self.dependencies.len())
+            .filter(|step| ran[*step])
+            .filter(|step| {
+                !self
+                    .dependencies
+                    .iter()
+                    .enumerate()
+                    .any(|(other, waits_on)| ran[other] && waits_on.contains(step))
+            })
+            .collect()
+    }
+}
+
+/// Describes the order `rounds` will run in, for the log.
+pub fn describe_rounds(rounds: &[Vec<usize>]) -> String {
+    let rounds: Vec<String> = rounds
+        .iter()
+        .map(|round| {
+            let steps: Vec<String> = round.iter().map(|step| (step + 1).to_string()).collect();
+            match steps.len() {
+                1 => format!("step {}", steps[0]),
+                _ => format!("steps {} together", steps.join(", ")),
+            }
+        })
+        .collect();
+    format!("Running {}.", rounds.join(", then "))
+}
+
+/// A step of a plan that didn't run, because a step it depends on came back with nothing.
+#[derive(Clone, Debug)]
+pub struct SkippedStep {
+    /// The step's position in the plan, counting from 1.
+    pub step: usize,
+    pub prompt: String,
+    /// The step it depends on that produced no result, counting from 1.
+    pub dependency: usize,
+}
+
+/// Lists the steps in `steps`, and why each was skipped.
+pub fn describe_skipped(steps: &[SkippedStep]) -> String {
+    let mut description = String::from("These steps were skipped:");
+    for step in steps {
+        description.push_str(&format!(
+            "\n- Step {}, \"{}\": step {}, which it depends on, produced no result",
+            step.step, step.prompt, step.dependency
+        ));
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(depends_on: &[&str]) -> Vec<String> {
+        depends_on.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn step_depending_on_itself_is_rejected() {
+        let error = StepGraph::parse(2, &entries(&["", "2"])).unwrap_err();
+        assert!(error.starts_with("Step 2 can only depend on earlier steps"));
+    }
+
+    #[test]
+    fn cycle_through_a_later_step_is_rejected() {
+        assert!(StepGraph::parse(2, &entries(&["2", "1"])).is_err());
+        assert!(StepGraph::parse(3, &entries(&["", "3", "2"])).is_err());
+    }
+
+    #[test]
+    fn dependency_on_a_step_that_does_not_exist_is_rejected() {
+        assert!(StepGraph::parse(2, &entries(&["", "0"])).is_err());
+        assert!(StepGraph::parse(2, &entries(&["", "5"])).is_err());
+        assert!(StepGraph::parse(2, &entries(&["", "first"])).is_err());
+    }
+
+    #[test]
+    fn more_entries_than_steps_is_rejected() {
+        let error = StepGraph::parse(1, &entries(&["", ""])).unwrap_err();
+        assert_eq!(
+            error,
+            "depends_on has 2 entries, but there are only 1 steps."
+        );
+    }
+
+    #[test]
+    fn missing_entries_wait_on_the_step_before() {
+        let graph = StepGraph::parse(3, &entries(&[""])).unwrap();
+        assert_eq!(graph.dependencies, vec![vec![], vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn repeated_dependencies_are_kept_once() {
+        let graph = StepGraph::parse(3, &entries(&["", "", " 1,1 , 2,"])).unwrap();
+        assert_eq!(graph.dependencies[2], vec![0, 1]);
+    }
+
+    #[test]
+    fn ready_waits_for_every_dependency() {
+        let graph = StepGraph::parse(3, &entries(&["", "", "1, 2"])).unwrap();
+        assert_eq!(graph.ready(&[false, false, false]), vec![0, 1]);
+        assert_eq!(graph.ready(&[true, false, false]), vec![1]);
+        assert_eq!(graph.ready(&[true, true, false]), vec![2]);
+        assert!(graph.ready(&[true, true, true]).is_empty());
+    }
+
+    #[test]
+    fn final_steps_are_the_ones_nothing_that_ran_waits_on() {
+        let graph = StepGraph::parse(3, &entries(&["", "1", "1"])).unwrap();
+        assert_eq!(graph.final_steps(&[true, true, true]), vec![1, 2]);
+        assert_eq!(graph.final_steps(&[true, false, false]), vec![0]);
+    }
+
+    #[test]
+    fn rounds_are_described_in_order() {
+        let graph = StepGraph::parse(3, &entries(&["", "", "1, 2"])).unwrap();
+        assert_eq!(
+            describe_rounds(&graph.rounds()),
+            "Running steps 1, 2 together, then step 3."
+        );
+    }
+
+    #[test]
+    fn skipped_steps_say_which_dependency_came_back_empty() {
+        let skipped = [SkippedStep {
+            step: 3,
+            prompt: String::from("Search the results."),
+            dependency: 2,
+        }];
+        assert_eq!(
+            describe_skipped(&skipped),
+            "These steps were skipped:\n- Step 3, \"Search the results.\": step 2, which it \
+             depends on, produced no result"
+        );
+    }
+}
@@ -0,0 +1,1212 @@
+use super::{
+    args::{
+        CaseSensitivity, Enabled, FileDisplayMode, FileType, GlobCaseSensitivity, Restriction,
+        SortBy, SortOrdering,
+    },
+    command_funcs::rank_functions,
+    commands::{Commands, FUNC_ENUMS_EMBED_PATH},
+    config::config,
+    function_embeddings::is_local_embed_model,
+    run_context::run_context,
+};
+use openai_func_enums::{
+    get_ranked_function_names, CommandError, Logger, RunCommand, ToolCallExecutionStrategy,
+};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Makes the request being run plan without the model, as if `--offline` had been given. This is
+/// turned on when the model can't be reached before it has answered the request, and a chat turns
+/// it back off for each new request.
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::SeqCst);
+}
+
+/// Whether requests are being planned without the model.
+pub fn offline() -> bool {
+    run_context().offline || OFFLINE.load(Ordering::SeqCst)
+}
+
+/// Quotes that mark a search pattern, or a replacement.
+const QUOTES: [char; 3] = ['"', '\'', '`'];
+
+/// Words that say which files a file type applies to, like "rust files".
+const FILE_WORDS: [&str; 9] = [
+    "file",
+    "files",
+    "code",
+    "source",
+    "sources",
+    "scripts",
+    "notebooks",
+    "documents",
+    "docs",
+];
+
+/// Words that turn a file type filter around, like "not markdown files".
+const NEGATIONS: [&str; 9] = [
+    "not",
+    "no",
+    "except",
+    "excluding",
+    "exclude",
+    "without",
+    "skip",
+    "skipping",
+    "ignoring",
+];
+
+/// Words that come just before a search pattern that isn't quoted, like "find TODO".
+const PATTERN_TRIGGERS: [&str; 12] = [
+    "for",
+    "find",
+    "search",
+    "grep",
+    "locate",
+    "contain",
+    "contains",
+    "containing",
+    "mention",
+    "mentions",
+    "mentioning",
+    "matching",
+];
+
+/// Words between a trigger and the pattern that aren't part of it, like "find all uses of TODO".
+const PATTERN_FILLERS: [&str; 22] = [
+    "for",
+    "all",
+    "the",
+    "any",
+    "every",
+    "each",
+    "a",
+    "an",
+    "occurrences",
+    "occurrence",
+    "instances",
+    "uses",
+    "usages",
+    "mentions",
+    "of",
+    "word",
+    "string",
+    "pattern",
+    "phrase",
+    "term",
+    "lines",
+    "with",
+];
+
+/// Words that end a pattern that isn't quoted.
+const PATTERN_STOPS: [&str; 30] = [
+    "in",
+    "inside",
+    "within",
+    "under",
+    "from",
+    "ignoring",
+    "sorted",
+    "sort",
+    "ordered",
+    "and",
+    "but",
+    "using",
+    "across",
+    "only",
+    "then",
+    "showing",
+    "show",
+    "that",
+    "which",
+    "files",
+    "file",
+    "case",
+    "on",
+    "at",
+    "with",
+    "without",
+    "except",
+    "excluding",
+    "including",
+    "or",
+];
+
+/// The words a file type goes by, including its extensions.
+const FILE_TYPES: [(FileType, &[&str]); 28] = [
+    (FileType::C, &["c", "h"]),
+    (FileType::CPP, &["c++", "cpp", "cc", "hpp", "cxx"]),
+    (FileType::Config, &["config", "configuration", "cfg", "ini"]),
+    (FileType::CSharp, &["c#", "csharp", "cs"]),
+    (FileType::Go, &["go", "golang"]),
+    (FileType::Java, &["java"]),
+    (FileType::JavaScript, &["javascript", "js", "jsx", "mjs"]),
+    (FileType::Json, &["json"]),
+    (FileType::JsonLines, &["jsonl", "jsonlines", "ndjson"]),
+    (FileType::Jupyter, &["jupyter", "ipynb", "notebook"]),
+    (FileType::License, &["license", "licence"]),
+    (FileType::Log, &["log", "logs"]),
+    (FileType::Lua, &["lua"]),
+    (FileType::Markdown, &["markdown", "md"]),
+    (FileType::OCaml, &["ocaml", "ml", "mli"]),
+    (FileType::Org, &["org"]),
+    (FileType::PDF, &["pdf"]),
+    (FileType::Python, &["python", "py"]),
+    (FileType::Rust, &["rust", "rs"]),
+    (FileType::Shell, &["shell", "sh", "bash", "zsh"]),
+    (FileType::SQL, &["sql"]),
+    (FileType::Text, &["text", "txt"]),
+    (FileType::Toml, &["toml"]),
+    (FileType::TypeScript, &["typescript", "ts", "tsx"]),
+    (FileType::XML, &["xml"]),
+    (FileType::Vim, &["vim", "vimscript"]),
+    (FileType::Yaml, &["yaml", "yml"]),
+    (FileType::Zig, &["zig"]),
+];
+
+/// Finds the steps one `Commands` variant adds for a prompt, claiming the words it used.
+type Rule = fn(&mut Prompt) -> Vec<Commands>;
+
+/// A rule for each preparation command, by function name. Without a ranking, they're tried in
+/// this order, which puts the more specific phrases first.
+const RULES: [(&str, Rule); 18] = [
+    ("CaseFilter", case_filter),
+    ("ContextLines", context_lines),
+    ("TreatBinaryAsText", |prompt| {
+        enable(
+            prompt,
+            &[
+                &["binary", "files", "as", "text"],
+                &["binary", "as", "text"],
+            ],
+            |enabled| Commands::TreatBinaryAsText { enabled },
+        )
+    }),
+    ("RestrictionLevel", restriction_level),
+    ("Files", files),
+    ("FileTypeFilter", file_type_filter),
+    ("FixedStrings", |prompt| {
+        enable(
+            prompt,
+            &[
+                &["fixed", "strings"],
+                &["fixed", "string"],
+                &["fixed-strings"],
+                &["fixed-string"],
+                &["not", "as", "a", "regex"],
+                &["not", "a", "regex"],
+                &["no", "regex"],
+                &["literal"],
+                &["literally"],
+            ],
+            |enabled| Commands::FixedStrings { enabled },
+        )
+    }),
+    ("SortResultsBy", sort_results_by),
+    ("Replace", replace),
+    ("Threads", threads),
+    ("Multiline", |prompt| {
+        enable(
+            prompt,
+            &[
+                &["across", "multiple", "lines"],
+                &["over", "multiple", "lines"],
+                &["across", "lines"],
+                &["spanning", "lines"],
+                &["multiline"],
+                &["multi-line"],
+            ],
+            |enabled| Commands::Multiline { enabled },
+        )
+    }),
+    ("DotAll", |prompt| {
+        enable(
+            prompt,
+            &[
+                &["dot", "matches", "newlines"],
+                &["dot", "matches", "newline"],
+                &["dot", "match", "newlines"],
+                &["dot", "match", "newline"],
+                &["dotall"],
+                &["dot-all"],
+            ],
+            |enabled| Commands::DotAll { enabled },
+        )
+    }),
+    ("CRLF", |prompt| {
+        enable(
+            prompt,
+            &[&["windows", "line", "endings"], &["crlf"]],
+            |enabled| Commands::CRLF { enabled },
+        )
+    }),
+    ("IncludeZip", |prompt| {
+        enable(
+            prompt,
+            &[
+                &["compressed"],
+                &["zip"],
+                &["zipped"],
+                &["gzip"],
+                &["gzipped"],
+                &["archives"],
+                &["archived"],
+            ],
+            |enabled| Commands::IncludeZip { enabled },
+        )
+    }),
+    ("TrimWhitespace", |prompt| {
+        enable(
+            prompt,
+            &[
+                &["trim", "whitespace"],
+                &["trim"],
+                &["trimmed"],
+                &["trimming"],
+            ],
+            |enabled| Commands::TrimWhitespace { enabled },
+        )
+    }),
+    ("Statistics", |prompt| {
+        enable(prompt, &[&["statistics"], &["stats"]], |enabled| {
+            Commands::Statistics { enabled }
+        })
+    }),
+    ("Debug", |prompt| {
+        enable(
+            prompt,
+            &[
+                &["debug", "messages"],
+                &["debug", "output"],
+                &["debug", "info"],
+                &["debugging"],
+            ],
+            |enabled| Commands::Debug { enabled },
+        )
+    }),
+    ("TraceData", |prompt| {
+        enable(
+            prompt,
+            &[
+                &["trace", "data"],
+                &["trace", "output"],
+                &["trace", "messages"],
+                &["tracing"],
+            ],
+            |enabled| Commands::TraceData { enabled },
+        )
+    }),
+];
+
+/// A word of the prompt, or a quoted string in it.
+struct Word {
+    /// Where it starts and ends in the prompt, in bytes, leaving out punctuation around it.
+    start: usize,
+    end: usize,
+    /// The word in lowercase, or a quoted string as it was written, without the quotes.
+    text: String,
+    quoted: bool,
+    /// Whether a rule has used it already.
+    claimed: bool,
+}
+
+/// A prompt split into words, keeping track of which ones the rules have used.
+struct Prompt<'a> {
+    text: &'a str,
+    words: Vec<Word>,
+}
+
+impl<'a> Prompt<'a> {
+    fn new(text: &'a str) -> Prompt<'a> {
+        let mut words = Vec::new();
+        let mut rest = 0;
+
+        while let Some(offset) = text[rest..].find(|c: char| !c.is_whitespace()) {
+            let start = rest + offset;
+
+            let quote = text[start..].chars().next().filter(|c| QUOTES.contains(c));
+            if let Some(quote) = quote {
+                let inner = start + quote.len_utf8();
+                if let Some(length) = text[inner..].find(quote) {
+                    words.push(Word {
+                        start,
+                        end: inner + length + quote.len_utf8(),
+                        text: text[inner..inner + length].to_string(),
+                        quoted: true,
+                        claimed: false,
+                    });
+                    rest = inner + length + quote.len_utf8();
+                    continue;
+                }
+            }
+
+            let end = text[start..]
+                .find(char::is_whitespace)
+                .map_or(text.len(), |length| start + length);
+            let raw = &text[start..end];
+            let trimmed = raw
+                .trim_start_matches(|c: char| ",;:!?()[]{}".contains(c))
+                .trim_end_matches(|c: char| ",;:!?()[]{}.".contains(c));
+            if !trimmed.is_empty() {
+                let trimmed_start = start
+                    + (raw.len()
+                        - raw
+                            .trim_start_matches(|c: char| ",;:!?()[]{}".contains(c))
+                            .len());
+                words.push(Word {
+                    start: trimmed_start,
+                    end: trimmed_start + trimmed.len(),
+                    text: trimmed.to_lowercase(),
+                    quoted: false,
+                    claimed: false,
+                });
+            }
+            rest = end;
+        }
+
+        Prompt { text, words }
+    }
+
+    /// The word at `index`, if it's there and no rule has used it.
+    fn word(&self, index: usize) -> Option<&str> {
+        self.words
+            .get(index)
+            .filter(|word| !word.quoted && !word.claimed)
+            .map(|word| word.text.as_str())
+    }
+
+    /// Whether `phrase`'s words are all there in order and unused, starting at `start`.
+    fn has(&self, start: usize, phrase: &[&str]) -> bool {
+        phrase
+            .iter()
+            .enumerate()
+            .all(|(offset, expected)| self.word(start + offset) == Some(*expected))
+    }
+
+    /// Where `phrase` starts, if its words are all there in order and unused.
+    fn find(&self, phrase: &[&str]) -> Option<usize> {
+        (0..self.words.len()).find(|start| self.has(*start, phrase))
+    }
+
+    /// Claims the first of `phrases` that's in the prompt, and says whether there was one.
+    fn take_any(&mut self, phrases: &[&[&str]]) -> bool {
+        for phrase in phrases {
+            if let Some(start) = self.find(phrase) {
+                self.claim(start, phrase.len());
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Like [`Prompt::take_any`], but leaves a phrase alone where it's the pattern, like "hidden"
+    /// in "find the word hidden". Followed by a word like "files", it's a setting after all.
+    fn take_any_setting(&mut self, phrases: &[&[&str]]) -> bool {
+        for phrase in phrases {
+            let start = (0..self.words.len()).find(|start| {
+                self.has(*start, phrase)
+                    && (!self.follows_trigger(*start)
+                        || self.words.get(start + phrase.len()).is_some_and(|word| {
+                            !word.quoted && FILE_WORDS.contains(&word.text.as_str())
+                        }))
+            });
+            if let Some(start) = start {
+                self.claim(start, phrase.len());
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether the word at `index` comes right after something like "find" or "search for the
+    /// word", which is where [`unquoted_pattern`] looks for the pattern.
+    fn follows_trigger(&self, index: usize) -> bool {
+        for before in (0..index).rev() {
+            match self.word(before) {
+                Some(word) if PATTERN_TRIGGERS.contains(&word) => return true,
+                Some(word) if PATTERN_FILLERS.contains(&word) => {}
+                _ => return false,
+            }
+        }
+        false
+    }
+
+    /// Whether the word at `index` is ruled out by the words before it, like the path in "not in
+    /// tests/" or "excluding tests/".
+    fn negated(&self, index: usize) -> bool {
+        let mut before = index;
+        while let Some(previous) = before.checked_sub(1) {
+            let word = &self.words[previous];
+            match word.text.as_str() {
+                _ if word.quoted => return false,
+                text if NEGATIONS.contains(&text) => return true,
+                "in" | "inside" | "under" | "within" | "from" | "the" => before = previous,
+                _ => return false,
+            }
+        }
+        false
+    }
+
+    fn claim(&mut self, start: usize, count: usize) {
+        for word in &mut self.words[start..start + count] {
+            word.claimed = true;
+        }
+    }
+}
+
+/// Turns a setting on if any of `phrases` is in the prompt, and isn't the pattern.
+fn enable(
+    prompt: &mut Prompt,
+    phrases: &[&[&str]],
+    command: fn(Enabled) -> Commands,
+) -> Vec<Commands> {
+    if prompt.take_any_setting(phrases) {
+        vec![command(Enabled::Yes)]
+    } else {
+        Vec::new()
+    }
+}
+
+fn case_filter(prompt: &mut Prompt) -> Vec<Commands> {
+    let modes: [(CaseSensitivity, &[&[&str]]); 3] = [
+        (
+            CaseSensitivity::IgnoreCase,
+            &[
+                &["ignore", "case"],
+                &["ignoring", "case"],
+                &["case", "insensitive"],
+                &["case-insensitive"],
+                &["case", "insensitively"],
+                &["case-insensitively"],
+                &["any", "case"],
+            ],
+        ),
+        (
+            CaseSensitivity::SmartCase,
+            &[&["smart", "case"], &["smart-case"]],
+        ),
+        (
+            CaseSensitivity::CaseSensitive,
+            &[
+                &["case", "sensitive"],
+                &["case-sensitive"],
+                &["case", "sensitively"],
+                &["case-sensitively"],
+                &["match", "case"],
+                &["matching", "case"],
+                &["exact", "case"],
+            ],
+        ),
+    ];
+
+    for (case_sensitivity, phrases) in modes {
+        if prompt.take_any(phrases) {
+            return vec![Commands::CaseFilter { case_sensitivity }];
+        }
+    }
+    Vec::new()
+}
+
+/// Reads a small number, in digits or words.
+fn number(word: &str) -> Option<i32> {
+    const NUMBERS: [&str; 11] = [
+        "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten",
+    ];
+    word.parse()
+        .ok()
+        .or_else(|| NUMBERS.iter().position(|n| *n == word).map(|n| n as i32))
+}
+
+fn context_lines(prompt: &mut Prompt) -> Vec<Commands> {
+    let mut lines_before = None;
+    let mut lines_after = None;
+
+    for start in 0..prompt.words.len() {
+        let Some(count) = prompt.word(start).and_then(number) else {
+            continue;
+        };
+        if !matches!(prompt.word(start + 1), Some("line" | "lines")) {
+            continue;
+        }
+
+        let (sides, length) = match (prompt.word(start + 2), prompt.word(start + 3)) {
+            (Some("of"), Some("context")) => ((true, true), 4),
+            (Some("context" | "around"), _) => ((true, true), 3),
+            (Some("before" | "above"), _) => ((true, false), 3),
+            (Some("after" | "below"), _) => ((false, true), 3),
+            _ => continue,
+        };
+        if sides.0 {
+            lines_before = Some(count);
+        }
+        if sides.1 {
+            lines_after = Some(count);
+        }
+        prompt.claim(start, length);
+    }
+
+    if lines_before.is_none() && lines_after.is_none() {
+        return Vec::new();
+    }
+    vec![Commands::ContextLines {
+        lines_before: lines_before.unwrap_or(0),
+        lines_after: lines_after.unwrap_or(0),
+    }]
+}
+
+fn restriction_level(prompt: &mut Prompt) -> Vec<Commands> {
+    let unrestricted = prompt.take_any_setting(&[&["unrestricted"]]);
+    let hidden = prompt.take_any_setting(&[&["hidden"], &["dotfiles"], &["dot", "files"]]);
+    let binary = prompt.take_any_setting(&[&["binary"]]);
+    let ignored = prompt.take_any_setting(&[&["ignored"], &["gitignored"]]);
+
+    let restriction = if unrestricted || binary {
+        Restriction::IncludeIgnoredHiddenBinary
+    } else if hidden {
+        Restriction::IncludeIgnoredHidden
+    } else if ignored {
+        Restriction::IncludeIgnored
+    } else {
+        return Vec::new();
+    };
+    vec![Commands::RestrictionLevel { restriction }]
+}
+
+fn files(prompt: &mut Prompt) -> Vec<Commands> {
+    let without_match: &[&[&str]] = &[
+        &["files", "without", "matches"],
+        &["files", "without", "a", "match"],
+        &["files", "without", "any", "matches"],
+        &["files", "with", "no", "matches"],
+        &["files", "that", "don't", "match"],
+        &["files", "that", "don't", "contain"],
+        &["files", "not", "containing"],
+    ];
+    let with_match: &[&[&str]] = &[
+        &["which", "files"],
+        &["what", "files"],
+        &["files", "with", "matches"],
+        &["files", "with", "a", "match"],
+        &["files", "that", "match"],
+        &["files", "that", "contain"],
+        &["files", "containing"],
+        &["files", "contain"],
+        &["files", "mentioning"],
+        &["files", "mention"],
+        &["list", "the", "files"],
+        &["list", "files"],
+        &["just", "the", "file", "names"],
+        &["just", "file", "names"],
+        &["only", "file", "names"],
+        &["file", "names", "only"],
+        &["only", "filenames"],
+        &["filenames", "only"],
+    ];
+
+    let file_display_mode = if prompt.take_any(without_match) {
+        FileDisplayMode::FilesWithoutMatch
+    } else if prompt.take_any(with_match) {
+        FileDisplayMode::FilesWithMatch
+    } else {
+        return Vec::new();
+    };
+    vec![Commands::Files { file_display_mode }]
+}
+
+/// The file type `alias` names, if any.
+fn file_type(alias: &str) -> Option<FileType> {
+    FILE_TYPES
+        .iter()
+        .find(|(_, aliases)| aliases.contains(&alias))
+        .map(|(file_type, _)| file_type.clone())
+}
+
+/// Finds file types named by extension, like "*.rs" or ".md", or by name before a word like
+/// "files", like "rust files" or "toml or yaml files". The word "files" itself isn't claimed, so
+/// it's still there for phrases like "files without matches".
+fn file_type_filter(prompt: &mut Prompt) -> Vec<Commands> {
+    let mut found: Vec<(usize, FileType)> = Vec::new();
+
+    for index in 0..prompt.words.len() {
+        let Some(word) = prompt.word(index) else {
+            continue;
+        };
+        let extension = word.strip_prefix("*.").or_else(|| word.strip_prefix('.'));
+        if let Some(file_type) = extension.and_then(file_type) {
+            prompt.claim(index, 1);
+            found.push((index, file_type));
+        }
+    }
+
+    // Another rule may have used the word "files" in a phrase of its own, which doesn't stop it
+    // saying which files a file type applies to.
+    for index in 0..prompt.words.len() {
+        let word = &prompt.words[index];
+        if word.quoted || !FILE_WORDS.contains(&word.text.as_str()) {
+            continue;
+        }
+
+        let mut names = Vec::new();
+        let mut cursor = index;
+        while let Some(file_type) = cursor
+            .checked_sub(1)
+            .and_then(|previous| prompt.word(previous))
+            .and_then(file_type)
+        {
+            cursor -= 1;
+            names.push((cursor, file_type));
+            match cursor
+                .checked_sub(1)
+                .and_then(|previous| prompt.word(previous))
+            {
+                Some("and" | "or") => cursor -= 1,
+                _ => break,
+            }
+        }
+        // A connector with nothing named before it isn't part of the list.
+        if let Some((first, _)) = names.last() {
+            prompt.claim(*first, index - first);
+            found.extend(names);
+        }
+    }
+
+    found.sort_by_key(|(index, _)| *index);
+    let mut commands: Vec<Commands> = Vec::new();
+    let mut seen: Vec<FileType> = Vec::new();
+    for (index, file_type) in found {
+        if seen.contains(&file_type) {
+            continue;
+        }
+        let negated = prompt.words[index.saturating_sub(2)..index]
+            .iter()
+            .any(|word| NEGATIONS.contains(&word.text.as_str()));
+        seen.push(file_type.clone());
+        commands.push(Commands::FileTypeFilter {
+            enabled: if negated { Enabled::No } else { Enabled::Yes },
+            file_type,
+        });
+    }
+    commands
+}
+
+fn sort_results_by(prompt: &mut Prompt) -> Vec<Commands> {
+    let shortcuts: [(SortOrdering, &[&[&str]]); 2] = [
+        (
+            SortOrdering::Descending,
+            &[
+                &["newest", "first"],
+                &["latest", "first"],
+                &["most", "recent", "first"],
+                &["most", "recently", "modified"],
+                &["recently", "modified", "first"],
+            ],
+        ),
+        (SortOrdering::Ascending, &[&["oldest", "first"]]),
+    ];
+    for (sort_ordering, phrases) in shortcuts {
+        if prompt.take_any(phrases) {
+            return vec![Commands::SortResultsBy {
+                sort_by: SortBy::Modified,
+                sort_ordering,
+            }];
+        }
+    }
+
+    for start in 0..prompt.words.len() {
+        if !matches!(
+            prompt.word(start),
+            Some("sort" | "sorted" | "sorting" | "order" | "ordered" | "ordering")
+        ) || prompt.word(start + 1) != Some("by")
+        {
+            continue;
+        }
+
+        let mut cursor = start + 2;
+        while matches!(prompt.word(cursor), Some("the" | "their" | "last" | "file")) {
+            cursor += 1;
+        }
+        let sort_by = match prompt.word(cursor) {
+            Some("modified" | "modification" | "mtime" | "date" | "time") => SortBy::Modified,
+            Some("path" | "paths" | "name" | "names" | "filename" | "filenames") => SortBy::Path,
+            Some("created" | "creation") => SortBy::Created,
+            Some("accessed" | "access") => SortBy::Accessed,
+            _ => continue,
+        };
+        cursor += 1;
+        if matches!(prompt.word(cursor), Some("time" | "date")) {
+            cursor += 1;
+        }
+        prompt.claim(start, cursor - start);
+
+        let mut sort_ordering = SortOrdering::Ascending;
+        for index in cursor..cursor + 3 {
+            let ordering = match prompt.word(index) {
+                Some("descending" | "desc" | "newest" | "latest" | "reverse" | "reversed") => {
+                    SortOrdering::Descending
+                }
+                Some("ascending" | "asc" | "oldest" | "alphabetical" | "alphabetically") => {
+                    SortOrdering::Ascending
+                }
+                _ => continue,
+            };
+            sort_ordering = ordering;
+            prompt.claim(index, 1);
+            break;
+        }
+
+        return vec![Commands::SortResultsBy {
+            sort_by,
+            sort_ordering,
+        }];
+    }
+    Vec::new()
+}
+
+/// Finds "replace ... with" followed by a quoted replacement.
+fn replace(prompt: &mut Prompt) -> Vec<Commands> {
+    let Some(verb) = (0..prompt.words.len()).find(|index| {
+        matches!(
+            prompt.word(*index),
+            Some("replace" | "replacing" | "replaced" | "substitute")
+        )
+    }) else {
+        return Vec::new();
+    };
+
+    let replacement = (verb + 1..prompt.words.len()).find(|index| {
+        let word = &prompt.words[*index];
+        word.quoted && !word.claimed && matches!(prompt.word(index - 1), Some("with" | "by"))
+    });
+    match replacement {
+        Some(index) => {
+            let replacement_text = prompt.words[index].text.clone();
+            prompt.claim(verb, 1);
+            prompt.claim(index - 1, 2);
+            vec![Commands::Replace { replacement_text }]
+        }
+        None => Vec::new(),
+    }
+}
+
+fn threads(prompt: &mut Prompt) -> Vec<Commands> {
+    for start in 0..prompt.words.len() {
+        if let Some(thread_count) = prompt.word(start).and_then(number) {
+            if matches!(prompt.word(start + 1), Some("thread" | "threads")) {
+                prompt.claim(start, 2);
+                return vec![Commands::Threads { thread_count }];
+            }
+        }
+    }
+    Vec::new()
+}
+
+/// The search the prompt asks for: the first quoted string that isn't a replacement, or
+/// otherwise the words after something like "find" or "search for", up to something like "in".
+/// Words with a `/` in them are the paths to search, unless they're ruled out, like "not in
+/// tests/", which makes them globs to leave out instead.
+fn search(prompt: &mut Prompt) -> Option<Commands> {
+    let pattern = match prompt
+        .words
+        .iter()
+        .position(|word| word.quoted && !word.claimed)
+    {
+        Some(index) => {
+            prompt.claim(index, 1);
+            prompt.words[index].text.clone()
+        }
+        None => unquoted_pattern(prompt)?,
+    };
+
+    let mut paths = Vec::new();
+    let mut exclude_globs = Vec::new();
+    for (index, word) in prompt.words.iter().enumerate() {
+        if word.quoted || word.claimed || !word.text.contains('/') || word.text.contains("://") {
+            continue;
+        }
+        let path = &prompt.text[word.start..word.end];
+        if prompt.negated(index) {
+            // Globs are matched against paths under the directory being searched, which don't
+            // start with "./".
+            exclude_globs.push(path.trim_start_matches("./").to_string());
+        } else {
+            paths.push(path.to_string());
+        }
+    }
+
+    Some(Commands::Search {
+        pattern,
+        paths,
+        include_globs: Vec::new(),
+        exclude_globs,
+        glob_case_sensitivity: GlobCaseSensitivity::CaseSensitive,
+    })
+}
+
+fn unquoted_pattern(prompt: &mut Prompt) -> Option<String> {
+    for trigger in 0..prompt.words.len() {
+        let word = &prompt.words[trigger];
+        if word.quoted || !PATTERN_TRIGGERS.contains(&word.text.as_str()) {
+            continue;
+        }
+
+        let mut start = trigger + 1;
+        while matches!(prompt.word(start), Some(word) if PATTERN_FILLERS.contains(&word)) {
+            start += 1;
+        }
+        let mut end = start;
+        while matches!(prompt.word(end), Some(word) if !PATTERN_STOPS.contains(&word)) {
+            end += 1;
+        }
+        if end > start {
+            prompt.claim(start, end - start);
+            let pattern = &prompt.text[prompt.words[start].start..prompt.words[end - 1].end];
+            return Some(pattern.to_string());
+        }
+    }
+    None
+}
+
+/// Plans `prompt` without the model, as the steps to run in order: whatever settings it asks
+/// for, then the search.
+///
+/// Each preparation command has a rule that looks for the words that ask for it, and fills in
+/// its arguments from the words around them. `ranked_func_names` decides which rule gets first
+/// claim on a word two of them could use, and the order their steps run in. The pattern is the
+/// first quoted string, or the words after something like "find" or "search for".
+///
+/// ```
+/// use dripgrep_lib::cli::args::{CaseSensitivity, Enabled, FileType};
+/// use dripgrep_lib::cli::commands::Commands;
+/// use dripgrep_lib::cli::offline_planner::plan;
+///
+/// let steps = plan("find \"fn main\" in rust files, ignoring case", &[]).unwrap();
+/// assert!(matches!(
+///     &steps[..],
+///     [
+///         Commands::CaseFilter { case_sensitivity: CaseSensitivity::IgnoreCase },
+///         Commands::FileTypeFilter { enabled: Enabled::Yes, file_type: FileType::Rust },
+///         Commands::Search { pattern, .. },
+///     ] if pattern == "fn main"
+/// ));
+///
+/// let steps = plan("search for TODO but not in markdown files", &[]).unwrap();
+/// assert!(matches!(
+///     &steps[..],
+///     [
+///         Commands::FileTypeFilter { enabled: Enabled::No, file_type: FileType::Markdown },
+///         Commands::Search { pattern, .. },
+///     ] if pattern == "TODO"
+/// ));
+///
+/// assert!(plan("make it faster", &[]).is_err());
+/// ```
+pub fn plan(prompt: &str, ranked_func_names: &[String]) -> Result<Vec<Commands>, String> {
+    let mut rules: Vec<Rule> = ranked_func_names
+        .iter()
+        .filter_map(|name| {
+            RULES
+                .iter()
+                .find(|(rule_name, _)| *rule_name == name.as_str())
+        })
+        .map(|(_, rule)| *rule)
+        .collect();
+    for (name, rule) in RULES {
+        if !ranked_func_names
+            .iter()
+            .any(|ranked| ranked.as_str() == name)
+        {
+            rules.push(rule);
+        }
+    }
+
+    let mut prompt = Prompt::new(prompt);
+    let mut steps: Vec<Commands> = rules.iter().flat_map(|rule| rule(&mut prompt)).collect();
+
+    let search = search(&mut prompt).ok_or_else(|| {
+        String::from(
+            "Couldn't tell what to search for without the model. Put the pattern in quotes, like: \
+             find \"fn main\" in rust files",
+        )
+    })?;
+    steps.push(search);
+    Ok(steps)
+}
+
+/// Ranks the functions against `prompt` if it can be embedded, and otherwise keeps them in the
+/// order their embeddings are stored in, since every function is then as similar as any other.
+async fn ranked_function_names(prompt: &str) -> Vec<String> {
+    // The local embedding model doesn't need the network, so it ranks offline too.
+    if !offline() || is_local_embed_model(&config().embed_model) {
+        if let Ok(ranked) = rank_functions(prompt).await {
+            return ranked;
+        }
+    }
+    get_ranked_function_names(Vec::new(), Path::new(FUNC_ENUMS_EMBED_PATH))
+        .await
+        .unwrap_or_default()
+}
+
+/// Plans `prompt` with [`plan`], and runs the steps one after another through the same commands
+/// the model would have called, handing each the search plan the last one left.
+pub async fn run_offline(
+    execution_strategy: ToolCallExecutionStrategy,
+    logger: Arc<Logger>,
+    system_message: Option<(String, usize)>,
+    prompt: &str,
+    arguments: Option<Vec<String>>,
+) -> Result<(Option<String>, Option<Vec<String>>), Box<dyn std::error::Error + Send + Sync + 'static>>
+{
+    let ranked_func_names = ranked_function_names(prompt).await;
+    let steps = plan(prompt, &ranked_func_names).map_err(|message| CommandError::new(&message))?;
+
+    let described: Vec<String> = steps
+        .iter()
+        .enumerate()
+        .map(|(index, step)| format!("{}. {:?}", index + 1, step))
+        .collect();
+    logger
+        .log(format!(
+            "Planned without the model:\n{}",
+            described.join("\n")
+        ))
+        .await;
+
+    let mut arguments = arguments;
+    let mut result = None;
+    for step in steps {
+        let (step_result, step_arguments) = step
+            .run(
+                execution_strategy.clone(),
+                arguments,
+                logger.clone(),
+                system_message.clone(),
+            )
+            .await?;
+        result = step_result;
+        arguments = step_arguments;
+    }
+    Ok((result, arguments))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn restriction(steps: &[Commands]) -> Option<&Restriction> {
+        steps.iter().find_map(|step| match step {
+            Commands::RestrictionLevel { restriction } => Some(restriction),
+            _ => None,
+        })
+    }
+
+    fn pattern(steps: &[Commands]) -> Option<&str> {
+        steps.iter().find_map(|step| match step {
+            Commands::Search { pattern, .. } => Some(pattern.as_str()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn a_restriction_word_after_find_is_the_pattern() {
+        for (prompt, expected) in [
+            ("find the word hidden", "hidden"),
+            ("search for binary in src/", "binary"),
+            ("grep ignored", "ignored"),
+        ] {
+            let steps = plan(prompt, &[]).unwrap();
+            assert!(restriction(&steps).is_none(), "{}", prompt);
+            assert_eq!(pattern(&steps), Some(expected), "{}", prompt);
+        }
+    }
+
+    #[test]
+    fn a_restriction_word_elsewhere_is_a_setting() {
+        let steps = plan("search for TODO including hidden files", &[]).unwrap();
+        assert!(matches!(
+            restriction(&steps),
+            Some(Restriction::IncludeIgnoredHidden)
+        ));
+        assert_eq!(pattern(&steps), Some("TODO"));
+
+        let steps = plan("find hidden files containing TODO", &[]).unwrap();
+        assert!(matches!(
+            restriction(&steps),
+            Some(Restriction::IncludeIgnoredHidden)
+        ));
+        assert_eq!(pattern(&steps), Some("TODO"));
+    }
+
+    fn search(steps: &[Commands]) -> (&[String], &[String]) {
+        steps
+            .iter()
+            .find_map(|step| match step {
+                Commands::Search {
+                    paths,
+                    exclude_globs,
+                    ..
+                } => Some((paths.as_slice(), exclude_globs.as_slice())),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn paths_are_searched_and_ruled_out_paths_are_left_out() {
+        let steps = plan("search for fn main in *.rs not in tests/", &[]).unwrap();
+        assert_eq!(pattern(&steps), Some("fn main"));
+        assert!(matches!(
+            steps[0],
+            Commands::FileTypeFilter {
+                enabled: Enabled::Yes,
+                file_type: FileType::Rust
+            }
+        ));
+        assert_eq!(search(&steps), (&[][..], &[String::from("tests/")][..]));
+
+        let steps = plan("find TODO in src/ excluding ./src/vendor/", &[]).unwrap();
+        assert_eq!(
+            search(&steps),
+            (
+                &[String::from("src/")][..],
+                &[String::from("src/vendor/")][..]
+            )
+        );
+
+        // A URL isn't a path.
+        let steps = plan("find \"https://example.com/\" in docs/", &[]).unwrap();
+        assert_eq!(pattern(&steps), Some("https://example.com/"));
+        assert_eq!(search(&steps), (&[String::from("docs/")][..], &[][..]));
+    }
+
+    #[test]
+    fn a_setting_word_after_find_is_the_pattern() {
+        for (prompt, expected) in [
+            ("find literal", "literal"),
+            ("search for debugging in src/", "debugging"),
+            ("find zip", "zip"),
+            ("find the word trim", "trim"),
+        ] {
+            let steps = plan(prompt, &[]).unwrap();
+            assert_eq!(steps.len(), 1, "{}: {:?}", prompt, steps);
+            assert_eq!(pattern(&steps), Some(expected), "{}", prompt);
+        }
+
+        // Followed by "files", it's the setting after all.
+        let steps = plan("find zip files containing \"needle\"", &[]).unwrap();
+        assert!(steps.iter().any(|step| matches!(
+            step,
+            Commands::IncludeZip {
+                enabled: Enabled::Yes
+            }
+        )));
+    }
+
+    #[test]
+    fn settings_are_read_from_their_phrases() {
+        let steps = plan(
+            "find \"TODO\" case-insensitively with 2 lines of context, as a literal string, \
+             sorted by path descending, using 4 threads, with stats",
+            &[],
+        )
+        .unwrap();
+        assert!(matches!(
+            &steps[..],
+            [
+                Commands::CaseFilter {
+                    case_sensitivity: CaseSensitivity::IgnoreCase
+                },
+                Commands::ContextLines {
+                    lines_before: 2,
+                    lines_after: 2
+                },
+                Commands::FixedStrings {
+                    enabled: Enabled::Yes
+                },
+                Commands::SortResultsBy {
+                    sort_by: SortBy::Path,
+                    sort_ordering: SortOrdering::Descending
+                },
+                Commands::Threads { thread_count: 4 },
+                Commands::Statistics {
+                    enabled: Enabled::Yes
+                },
+                Commands::Search { .. },
+            ]
+        ));
+        assert_eq!(pattern(&steps), Some("TODO"));
+    }
+
+    #[test]
+    fn files_and_replacements_are_read_from_their_phrases() {
+        let steps = plan("which files mention \"unsafe\"", &[]).unwrap();
+        assert!(matches!(
+            steps[0],
+            Commands::Files {
+                file_display_mode: FileDisplayMode::FilesWithMatch
+            }
+        ));
+
+        let steps = plan("list files without matches for \"license\"", &[]).unwrap();
+        assert!(matches!(
+            steps[0],
+            Commands::Files {
+                file_display_mode: FileDisplayMode::FilesWithoutMatch
+            }
+        ));
+
+        let steps = plan("replace \"colour\" with \"color\"", &[]).unwrap();
+        assert!(matches!(
+            &steps[..],
+            [Commands::Replace { replacement_text }, Commands::Search { pattern, .. }]
+                if replacement_text == "color" && pattern == "colour"
+        ));
+    }
+
+    #[test]
+    fn file_types_can_be_listed_and_ruled_out() {
+        let steps = plan(
+            "find \"version\" in toml or yaml files but not json files",
+            &[],
+        )
+        .unwrap();
+        let file_types: Vec<(&FileType, &Enabled)> = steps
+            .iter()
+            .filter_map(|step| match step {
+                Commands::FileTypeFilter { enabled, file_type } => Some((file_type, enabled)),
+                _ => None,
+            })
+            .collect();
+        assert!(matches!(
+            &file_types[..],
+            [
+                (FileType::Toml, Enabled::Yes),
+                (FileType::Yaml, Enabled::Yes),
+                (FileType::Json, Enabled::No),
+            ]
+        ));
+    }
+
+    #[test]
+    fn ranking_decides_which_rule_claims_a_word_first() {
+        // "binary" is either the restriction level or part of "binary as text", whichever rule
+        // ranks higher.
+        let prompt = "search for \"magic\" treating binary as text";
+        let steps = plan(prompt, &[String::from("TreatBinaryAsText")]).unwrap();
+        assert!(restriction(&steps).is_none());
+        assert!(matches!(
+            steps[0],
+            Commands::TreatBinaryAsText {
+                enabled: Enabled::Yes
+            }
+        ));
+
+        let steps = plan(prompt, &[String::from("RestrictionLevel")]).unwrap();
+        assert!(matches!(
+            restriction(&steps),
+            Some(Restriction::IncludeIgnoredHiddenBinary)
+        ));
+    }
+}
@@ -0,0 +1,224 @@
+use super::commands::CommandsGPT;
+use super::model_client::call_function;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// The only function offered when verifying, so the model has to answer with it.
+const VERIFY_FUNCTION: &str = "ReportUnsupportedSteps";
+
+/// A step of a multi-step plan that none of the `Commands` variants can carry out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnsupportedStep {
+    /// The step's position in the prompt list, counting from 1.
+    pub step: usize,
+    pub prompt: String,
+    pub reason: String,
+}
+
+#[derive(Deserialize)]
+struct Report {
+    unsupported_steps: Vec<ReportedStep>,
+}
+
+#[derive(Deserialize)]
+struct ReportedStep {
+    step: usize,
+    reason: String,
+}
+
+/// One line per function the model can be offered, with its arguments and, for enum arguments,
+/// the values it accepts. `CallMultiStep` and `GPT` only hand work to other functions, so they
+/// aren't listed.
+pub fn available_functions() -> String {
+    let (functions, _) = CommandsGPT::all_function_jsons();
+
+    let mut lines = Vec::new();
+    for function in functions.as_array().into_iter().flatten() {
+        let name = function["name"].as_str().unwrap_or_default();
+        if name == "CallMultiStep" || name == "GPT" {
+            continue;
+        }
+
+        let arguments: Vec<String> = function["parameters"]["properties"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(argument, schema)| match schema["enum"].as_array() {
+                Some(values) => {
+                    let values: Vec<&str> = values.iter().filter_map(Value::as_str).collect();
+                    format!("{}: {}", argument, values.join("|"))
+                }
+                None => format!(
+                    "{}: {}",
+                    argument,
+                    schema["type"].as_str().unwrap_or("value")
+                ),
+            })
+            .collect();
+
+        lines.push(format!(
+            "- {}({}): {}",
+            name,
+            arguments.join(", "),
+            function["description"].as_str().unwrap_or_default()
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Asks the model which steps of `prompt_list` can't be done with any available function, before
+/// any of them run. An empty list means every step has a tool.
+pub async fn verify_plan(
+    model_name: &str,
+    prompt_list: &[String],
+) -> Result<Vec<UnsupportedStep>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let system_message = format!(
+        "You check a multi-step plan for a file search tool before it runs. These are the only \
+         functions available:\n{}\n\nCall {} with every step that none of them can carry out, \
+         like deleting or editing files, or settings with values that aren't listed. Steps that \
+         only need your own knowledge, like translating a phrase, can be carried out. Call it \
+         with an empty list if every step can be carried out.",
+        available_functions(),
+        VERIFY_FUNCTION
+    );
+
+    let steps: Vec<String> = prompt_list
+        .iter()
+        .enumerate()
+        .map(|(index, prompt)| format!("{}. {}", index + 1, prompt))
+        .collect();
+
+    let arguments = call_function(
+        model_name,
+        system_message,
+        &steps.join("\n"),
+        VERIFY_FUNCTION,
+        "Reports the steps of the plan that no available function can carry out",
+        json!({
+            "type": "object",
+            "properties": {
+                "unsupported_steps": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "step": {
+                                "type": "integer",
+                                "description": "The step's number in the plan",
+                            },
+                            "reason": {
+                                "type": "string",
+                                "description": "What the step needs that no function does",
+                            },
+                        },
+                        "required": ["step", "reason"],
+                    },
+                },
+            },
+            "required": ["unsupported_steps"],
+        }),
+    )
+    .await?;
+
+    Ok(unsupported_steps(prompt_list, &arguments)?)
+}
+
+/// Reads the model's report on `prompt_list` into the steps it found unsupported.
+fn unsupported_steps(
+    prompt_list: &[String],
+    arguments: &str,
+) -> Result<Vec<UnsupportedStep>, serde_json::Error> {
+    let report: Report = serde_json::from_str(arguments)?;
+
+    // Anything pointing outside the plan can't be shown against a step, so it's dropped.
+    Ok(report
+        .unsupported_steps
+        .into_iter()
+        .filter_map(|reported| {
+            let prompt = prompt_list.get(reported.step.checked_sub(1)?)?;
+            Some(UnsupportedStep {
+                step: reported.step,
+                prompt: prompt.clone(),
+                reason: reported.reason,
+            })
+        })
+        .collect())
+}
+
+/// Explains to the user, and to whatever asked for the plan, why none of it ran.
+pub fn describe_unsupported(steps: &[UnsupportedStep]) -> String {
+    let mut description =
+        String::from("Nothing was done, because no available function can carry out these steps:");
+    for step in steps {
+        description.push_str(&format!(
+            "\n- Step {}, \"{}\": {}",
+            step.step, step.prompt, step.reason
+        ));
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan() -> Vec<String> {
+        vec![
+            String::from("Search for TODO."),
+            String::from("Delete every file with a match."),
+        ]
+    }
+
+    #[test]
+    fn reported_steps_are_matched_to_the_plan() {
+        let arguments = json!({ "unsupported_steps": [
+            { "step": 2, "reason": "Nothing deletes files." },
+        ] })
+        .to_string();
+        assert_eq!(
+            unsupported_steps(&plan(), &arguments).unwrap(),
+            [UnsupportedStep {
+                step: 2,
+                prompt: String::from("Delete every file with a match."),
+                reason: String::from("Nothing deletes files."),
+            }]
+        );
+
+        let arguments = json!({ "unsupported_steps": [] }).to_string();
+        assert!(unsupported_steps(&plan(), &arguments).unwrap().is_empty());
+    }
+
+    #[test]
+    fn steps_outside_the_plan_are_dropped() {
+        let arguments = json!({ "unsupported_steps": [
+            { "step": 0, "reason": "Counted from zero." },
+            { "step": 3, "reason": "Past the end." },
+        ] })
+        .to_string();
+        assert!(unsupported_steps(&plan(), &arguments).unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_functions_that_do_something_are_listed() {
+        let functions = available_functions();
+        assert!(functions
+            .lines()
+            .any(|line| line.starts_with("- CaseFilter(CaseSensitivity: IgnoreCase|")));
+        assert!(!functions.contains("- CallMultiStep("));
+        assert!(!functions.contains("- GPT("));
+    }
+
+    #[test]
+    fn unsupported_steps_are_described() {
+        let steps = [UnsupportedStep {
+            step: 2,
+            prompt: String::from("Delete every file with a match."),
+            reason: String::from("Nothing deletes files."),
+        }];
+        assert_eq!(
+            describe_unsupported(&steps),
+            "Nothing was done, because no available function can carry out these steps:\n- Step \
+             2, \"Delete every file with a match.\": Nothing deletes files."
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use super::config::{Config, ProviderKind};
+use async_openai::{
+    config::OpenAIConfig,
+    error::OpenAIError,
+    types::{
+        CreateChatCompletionRequest, CreateChatCompletionResponse, CreateEmbeddingRequest,
+        CreateEmbeddingResponse,
+    },
+    Client,
+};
+use async_trait::async_trait;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+/// A provider that couldn't be reached at all, as opposed to one that answered with an error.
+/// This is what lets `dripgrep gpt` plan offline instead.
+#[derive(Debug)]
+pub struct Unreachable {
+    pub message: String,
+}
+
+impl fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Unreachable {}
+
+/// Whether `error`, or anything it was caused by, is [`Unreachable`].
+pub fn is_unreachable(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut error = Some(error);
+    while let Some(current) = error {
+        if current.is::<Unreachable>() {
+            return true;
+        }
+        error = current.source();
+    }
+    false
+}
+
+/// Boxes `error`, as [`Unreachable`] if the request never got to the server.
+fn boxed(error: OpenAIError) -> Box<dyn std::error::Error + Send + Sync> {
+    match &error {
+        OpenAIError::Reqwest(e) if e.is_connect() || e.is_timeout() => Box::new(Unreachable {
+            message: error.to_string(),
+        }),
+        _ => Box::new(error),
+    }
+}
+
+/// Somewhere chat completions and embeddings come from.
+///
+/// Requests and responses are in OpenAI's format, since that's what every provider here speaks,
+/// and it's what the `ToolSet` macro builds tools in. Everything that talks to a model goes
+/// through [`provider`], so nothing else needs to know which one it is.
+#[async_trait]
+pub trait Provider: Send + Sync {
+    async fn chat(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, Box<dyn std::error::Error + Send + Sync + 'static>>;
+
+    async fn embed(
+        &self,
+        request: CreateEmbeddingRequest,
+    ) -> Result<CreateEmbeddingResponse, Box<dyn std::error::Error + Send + Sync + 'static>>;
+}
+
+/// The OpenAI API, with the key from `OPENAI_API_KEY` unless another one is given.
+pub struct OpenAi {
+    client: Client<OpenAIConfig>,
+}
+
+impl OpenAi {
+    /// `base_url` is only for proxies in front of the OpenAI API. A server that isn't OpenAI
+    /// shouldn't be sent an OpenAI key, and is better reached with [`OpenAiCompatible`].
+    pub fn new(base_url: Option<String>, api_key: Option<String>) -> OpenAi {
+        let mut config = OpenAIConfig::new();
+        if let Some(base_url) = base_url {
+            config = config.with_api_base(base_url);
+        }
+        if let Some(api_key) = api_key {
+            config = config.with_api_key(api_key);
+        }
+        OpenAi {
+            client: Client::with_config(config),
+        }
+    }
+}
+
+#[async_trait]
+impl Provider for OpenAi {
+    async fn chat(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, Box<dyn std::error::Error + Send + Sync + 'static>>
+    {
+        self.client.chat().create(request).await.map_err(boxed)
+    }
+
+    async fn embed(
+        &self,
+        request: CreateEmbeddingRequest,
+    ) -> Result<CreateEmbeddingResponse, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        self.client
+            .embeddings()
+            .create(request)
+            .await
+            .map_err(boxed)
+    }
+}
+
+/// Any server with OpenAI's chat completions and embeddings endpoints, like a llama.cpp or
+/// Ollama server on localhost.
+///
+/// `OPENAI_API_KEY` is never sent to it. Servers that want a key get the one they're given,
+/// usually from the environment variable the config names in `api_key_env`.
+pub struct OpenAiCompatible {
+    base_url: String,
+    client: Client<OpenAIConfig>,
+}
+
+impl OpenAiCompatible {
+    pub fn new(base_url: String, api_key: Option<String>) -> OpenAiCompatible {
+        let config = OpenAIConfig::new()
+            .with_api_base(base_url.clone())
+            .with_api_key(api_key.unwrap_or_default());
+        OpenAiCompatible {
+            base_url,
+            client: Client::with_config(config),
+        }
+    }
+
+    fn error(&self, error: OpenAIError) -> Box<dyn std::error::Error + Send + Sync> {
+        let message = format!("{} (from the server at {})", error, self.base_url);
+        match boxed(error).downcast::<Unreachable>() {
+            Ok(_) => Box::new(Unreachable { message }),
+            Err(_) => message.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Provider for OpenAiCompatible {
+    async fn chat(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, Box<dyn std::error::Error + Send + Sync + 'static>>
+    {
+        self.client
+            .chat()
+            .create(request)
+            .await
+            .map_err(|e| self.error(e))
+    }
+
+    async fn embed(
+        &self,
+        request: CreateEmbeddingRequest,
+    ) -> Result<CreateEmbeddingResponse, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        self.client
+            .embeddings()
+            .create(request)
+            .await
+            .map_err(|e| self.error(e))
+    }
+}
+
+/// Builds the provider `config` asks for. Without a `provider`, a `base_url` means an
+/// OpenAI-compatible server, and no `base_url` means OpenAI.
+pub fn from_config(
+    config: &Config,
+) -> Result<Arc<dyn Provider>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let api_key = match &config.api_key_env {
+        Some(variable) => Some(std::env::var(variable).map_err(|_| {
+            format!(
+                "api_key_env names {}, but it isn't set to anything",
+                variable
+            )
+        })?),
+        None => None,
+    };
+
+    let kind = config.provider.unwrap_or(match config.base_url {
+        Some(_) => ProviderKind::OpenAiCompatible,
+        None => ProviderKind::OpenAi,
+    });
+
+    Ok(match kind {
+        ProviderKind::OpenAi => Arc::new(OpenAi::new(config.base_url.clone(), api_key)),
+        ProviderKind::OpenAiCompatible => {
+            let base_url = config.base_url.clone().ok_or(
+                "The openai-compatible provider needs a base_url, like http://localhost:11434/v1",
+            )?;
+            Arc::new(OpenAiCompatible::new(base_url, api_key))
+        }
+    })
+}
+
+static PROVIDER: OnceLock<Arc<dyn Provider>> = OnceLock::new();
+
+/// Sends every chat and embedding request to `provider`.
+pub fn set_provider(provider: Arc<dyn Provider>) {
+    let _ = PROVIDER.set(provider);
+}
+
+/// Points every chat and embedding request at the OpenAI-compatible server at `api_base`, with
+/// no key.
+pub fn set_api_base(api_base: String) {
+    set_provider(Arc::new(OpenAiCompatible::new(api_base, None)));
+}
+
+/// The provider set with [`set_provider`], or OpenAI.
+pub fn provider() -> Arc<dyn Provider> {
+    PROVIDER
+        .get_or_init(|| Arc::new(OpenAi::new(None, None)))
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::CreateEmbeddingRequestArgs;
+
+    #[derive(Debug)]
+    struct Wrapper(Unreachable);
+
+    impl fmt::Display for Wrapper {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Couldn't rank the functions")
+        }
+    }
+
+    impl std::error::Error for Wrapper {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn unreachable_is_found_behind_other_errors() {
+        let error = Wrapper(Unreachable {
+            message: String::from("connection refused"),
+        });
+        assert!(is_unreachable(&error));
+        assert!(!is_unreachable(&fmt::Error));
+    }
+
+    #[tokio::test]
+    async fn a_server_that_isnt_there_is_unreachable() {
+        // Nothing listens on a port once its listener is gone.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let base_url = format!("http://127.0.0.1:{}/v1", port);
+        let provider = OpenAiCompatible::new(base_url.clone(), None);
+
+        let request = CreateEmbeddingRequestArgs::default()
+            .model("nomic-embed-text")
+            .input("find TODO")
+            .build()
+            .unwrap();
+        let error = provider.embed(request).await.unwrap_err();
+        assert!(is_unreachable(&*error));
+        assert!(error.to_string().contains(&base_url), "{}", error);
+    }
+
+    #[test]
+    fn errors_from_a_server_say_where_they_came_from() {
+        let provider = OpenAiCompatible::new(String::from("http://localhost:11434/v1"), None);
+        let error = provider.error(OpenAIError::InvalidArgument(String::from("no model")));
+        assert!(!is_unreachable(&*error));
+        assert_eq!(
+            error.to_string(),
+            "invalid args: no model (from the server at http://localhost:11434/v1)"
+        );
+    }
+
+    #[test]
+    fn providers_need_what_they_ask_for() {
+        let config = Config {
+            provider: Some(ProviderKind::OpenAiCompatible),
+            ..Config::default()
+        };
+        let error = from_config(&config).err().unwrap();
+        assert!(error.to_string().contains("needs a base_url"), "{}", error);
+
+        let config = Config {
+            base_url: Some(String::from("http://localhost:11434/v1")),
+            api_key_env: Some(String::from("DRIPGREP_TEST_KEY_THAT_ISNT_SET")),
+            ..Config::default()
+        };
+        let error = from_config(&config).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "api_key_env names DRIPGREP_TEST_KEY_THAT_ISNT_SET, but it isn't set to anything"
+        );
+
+        let config = Config {
+            base_url: Some(String::from("http://localhost:11434/v1")),
+            ..Config::default()
+        };
+        assert!(from_config(&config).is_ok());
+    }
+}
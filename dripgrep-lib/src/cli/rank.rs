@@ -0,0 +1,217 @@
+use super::command_funcs::required_functions;
+use super::commands::CommandsGPT;
+use super::config::config;
+use super::function_embeddings::{current_functions, FunctionEmbeddings};
+use super::model_client::{embedding, offered_functions};
+use openai_func_enums::Logger;
+use std::sync::Arc;
+
+/// One function, as a prompt ranks it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RankedFunction {
+    pub name: String,
+    /// How similar its embedding is to the prompt's, or `None` if it has no embedding.
+    pub similarity: Option<f32>,
+    /// Whether its embedding was made from a description it doesn't have anymore.
+    pub stale: bool,
+    /// What it costs to offer it.
+    pub tokens: usize,
+    /// Whether it fits in `max_func_tokens` along with every function offered before it.
+    pub fits: bool,
+    /// Whether the model would be offered it.
+    pub offered: bool,
+}
+
+/// Ranks every function against `prompt` the way [`gpt`](super::command_funcs::gpt) does, in the
+/// order the model would be offered them: the required functions, then the rest, most similar
+/// first. Functions that can't be ranked come last.
+pub async fn rank_prompt(
+    prompt: &str,
+) -> Result<Vec<RankedFunction>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let embed_model = &config().embed_model;
+    let function_embeddings = FunctionEmbeddings::load(embed_model)?;
+    let prompt_embedding = embedding(prompt, embed_model).await?;
+    let similarities = function_embeddings.similarities(&prompt_embedding);
+    let ranked = function_embeddings.rank(&prompt_embedding);
+    let staleness = function_embeddings.staleness();
+
+    let required = required_functions();
+    let (offered, _) = offered_functions(Some(ranked.clone()), Some(required.clone()))?;
+
+    let (names, candidates) = offer_order(
+        &required,
+        &ranked,
+        current_functions().into_iter().map(|(name, _)| name),
+    );
+    let tokens: Vec<usize> = names
+        .iter()
+        .map(|name| CommandsGPT::function_jsons_allowed_with_required(vec![name.clone()], None).1)
+        .collect();
+    let fits = fitting(&tokens, candidates, config().max_func_tokens as usize);
+
+    Ok(names
+        .into_iter()
+        .zip(tokens)
+        .zip(fits)
+        .map(|((name, tokens), fits)| RankedFunction {
+            similarity: similarities
+                .iter()
+                .find(|(similar_name, _)| *similar_name == name)
+                .map(|(_, similarity)| *similarity),
+            stale: staleness.changed.contains(&name),
+            offered: offered.contains(&name),
+            name,
+            tokens,
+            fits,
+        })
+        .collect())
+}
+
+/// The order functions would be offered in: `required`, then `ranked`, then the rest of
+/// `functions`, which can't be ranked. Also returns how many of them are ranked or required.
+fn offer_order(
+    required: &[String],
+    ranked: &[String],
+    functions: impl IntoIterator<Item = String>,
+) -> (Vec<String>, usize) {
+    let mut names: Vec<String> = required
+        .iter()
+        .chain(ranked.iter().filter(|name| !required.contains(name)))
+        .cloned()
+        .collect();
+    let candidates = names.len();
+    for name in functions {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    (names, candidates)
+}
+
+/// Whether each of the first `candidates` functions, costing `tokens`, fits in `limit` along with
+/// every one before it that fit. The rest never fit.
+fn fitting(tokens: &[usize], candidates: usize, limit: usize) -> Vec<bool> {
+    let mut total_tokens = 0;
+    tokens
+        .iter()
+        .enumerate()
+        .map(|(index, tokens)| {
+            let fits = index < candidates && total_tokens + tokens <= limit;
+            if fits {
+                total_tokens += tokens;
+            }
+            fits
+        })
+        .collect()
+}
+
+/// Prints how every function ranks against `prompt`, what each costs, and which the model would
+/// be offered, to make it easier to see how a change to a description plays out.
+pub async fn rank(
+    logger: Arc<Logger>,
+    prompt: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let functions = rank_prompt(prompt).await?;
+
+    let name_width = functions
+        .iter()
+        .map(|function| function.name.len())
+        .chain(["Function".len()])
+        .max()
+        .unwrap_or_default();
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+
+    let mut lines = vec![
+        format!(
+            "Functions for this prompt with {}, in the order they'd be offered. The required \
+             ones ({}) come first.\n",
+            config().embed_model,
+            required_functions().join(", ")
+        ),
+        format!(
+            "{:>3}  {:<name_width$}  {:>10}  {:>6}  {:<4}  Offered",
+            "#", "Function", "Similarity", "Tokens", "Fits"
+        ),
+    ];
+    for (index, function) in functions.iter().enumerate() {
+        let similarity = match function.similarity {
+            Some(similarity) if function.stale => format!("{:.4}*", similarity),
+            Some(similarity) => format!("{:.4} ", similarity),
+            None => String::from("- "),
+        };
+        lines.push(format!(
+            "{:>3}  {:<name_width$}  {:>10}  {:>6}  {:<4}  {}",
+            index + 1,
+            function.name,
+            similarity,
+            function.tokens,
+            yes_no(function.fits),
+            yes_no(function.offered)
+        ));
+    }
+
+    let offered: Vec<&RankedFunction> = functions.iter().filter(|f| f.offered).collect();
+    lines.push(format!(
+        "\nOffered {} functions in {} of {} tokens: {}",
+        offered.len(),
+        offered.iter().map(|f| f.tokens).sum::<usize>(),
+        config().max_func_tokens,
+        offered
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    ));
+    if functions.iter().any(|f| f.stale) {
+        lines.push(String::from(
+            "* Embedded from an older description, so this ranks the old one.",
+        ));
+    }
+    if functions.iter().any(|f| f.offered && !f.fits) {
+        lines.push(String::from(
+            "Without the function_filtering feature, every ranked function is offered, whether \
+             or not it fits.",
+        ));
+    }
+    if functions.iter().any(|f| f.similarity.is_none()) {
+        lines.push(String::from(
+            "Functions without a similarity have no embedding, so they come last.",
+        ));
+    }
+
+    logger.log(lines.join("\n")).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn required_functions_come_first_and_unranked_ones_last() {
+        let (order, candidates) = offer_order(
+            &names(&["Search"]),
+            &names(&["FileTypeFilter", "Search", "CaseFilter"]),
+            names(&["CaseFilter", "Threads", "FileTypeFilter", "Search"]),
+        );
+        assert_eq!(
+            order,
+            names(&["Search", "FileTypeFilter", "CaseFilter", "Threads"])
+        );
+        assert_eq!(candidates, 3);
+    }
+
+    #[test]
+    fn functions_fit_while_there_are_tokens_left() {
+        // The second is too big, but smaller ones after it can still fit. Unranked ones never
+        // do.
+        assert_eq!(
+            fitting(&[40, 70, 20, 10], 3, 100),
+            [true, false, true, false]
+        );
+    }
+}
@@ -0,0 +1,57 @@
+use super::call_guard::{DEFAULT_MAX_MODEL_CALLS, DEFAULT_MAX_PLAN_DEPTH};
+use super::check_in::DEFAULT_CHECK_IN_EVERY;
+use std::sync::OnceLock;
+
+/// How this run of dripgrep was asked to behave: the switches and limits given on the command
+/// line, which every request, plan and search goes by.
+#[derive(Clone, Debug)]
+pub struct RunContext {
+    /// Makes `Search` explain the ripgrep command it would run instead of running it.
+    pub dry_run: bool,
+    /// Makes `dripgrep gpt` plan without the model.
+    pub offline: bool,
+    /// Makes `Search` show the prepared plan and wait for a yes or no before running it.
+    pub confirm: bool,
+    /// Makes plans with more than this many steps check in with the model after every this many
+    /// steps that run. Zero turns check-ins off.
+    pub check_in_every: usize,
+    /// How many multi-step plans can run inside each other. The plan `dripgrep gpt` starts is
+    /// the first.
+    pub max_plan_depth: usize,
+    /// How many chat completion requests a request can make, counting every plan, step,
+    /// verification and check-in it leads to.
+    pub max_model_calls: usize,
+    /// Refuses any call that could take the session's cost past this many dollars.
+    pub max_cost: Option<f64>,
+    /// Treats every call as free, for sessions whose model is a stand-in like the mock server.
+    /// Tokens are still counted, but nothing is priced as if it had been spent.
+    pub unbilled: bool,
+}
+
+impl Default for RunContext {
+    fn default() -> RunContext {
+        RunContext {
+            dry_run: false,
+            offline: false,
+            confirm: false,
+            check_in_every: DEFAULT_CHECK_IN_EVERY,
+            max_plan_depth: DEFAULT_MAX_PLAN_DEPTH,
+            max_model_calls: DEFAULT_MAX_MODEL_CALLS,
+            max_cost: None,
+            unbilled: false,
+        }
+    }
+}
+
+static RUN_CONTEXT: OnceLock<RunContext> = OnceLock::new();
+
+/// Makes `context` the one this run goes by. It's set once, from the command line, before any
+/// request is made; calls after the first are ignored.
+pub fn set_run_context(context: RunContext) {
+    let _ = RUN_CONTEXT.set(context);
+}
+
+/// The context set with [`set_run_context`], or the defaults.
+pub fn run_context() -> &'static RunContext {
+    RUN_CONTEXT.get_or_init(RunContext::default)
+}
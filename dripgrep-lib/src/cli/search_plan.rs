@@ -0,0 +1,663 @@
+use super::args::{
+    CaseSensitivity, Enabled, FileDisplayMode, FileType, Restriction, SortBy, SortOrdering,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Debug};
+
+/// The number of lines of surrounding context to show around each match.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ContextLines {
+    pub before: u32,
+    pub after: u32,
+}
+
+/// A single `--type` or `--type-not` filter.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FileTypeFilter {
+    pub file_type: FileType,
+    pub include: bool,
+}
+
+/// A single `--glob` or `--iglob` filter. Excluded globs are rendered with ripgrep's `!` prefix.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GlobFilter {
+    pub glob: String,
+    pub include: bool,
+    pub ignore_case: bool,
+}
+
+/// How results should be sorted, and in which direction.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Sort {
+    pub sort_by: SortBy,
+    pub sort_ordering: SortOrdering,
+}
+
+/// Everything the model has configured for the next search.
+///
+/// Each preparation variant of `Commands` mutates one part of the plan. Nothing is turned into
+/// ripgrep flags until `search` asks for them with [`SearchPlan::to_args`], so the plan can be
+/// inspected (or changed) at any point before that. It travels between tool calls inside a
+/// [`CallContext`](super::call_context::CallContext).
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SearchPlan {
+    pub case_sensitivity: Option<CaseSensitivity>,
+    pub context: Option<ContextLines>,
+    pub crlf: Option<bool>,
+    pub debug: Option<bool>,
+    pub dot_all: Option<bool>,
+    pub file_display_mode: Option<FileDisplayMode>,
+    pub file_types: Vec<FileTypeFilter>,
+    pub fixed_strings: Option<bool>,
+    pub globs: Vec<GlobFilter>,
+    pub include_zip: Option<bool>,
+    pub multiline: Option<bool>,
+    pub paths: Vec<String>,
+    pub replace: Option<String>,
+    pub restriction: Option<Restriction>,
+    pub sort: Option<Sort>,
+    pub statistics: Option<bool>,
+    pub threads: Option<u32>,
+    pub trace: Option<bool>,
+    pub treat_binary_as_text: Option<bool>,
+    pub trim_whitespace: Option<bool>,
+}
+
+/// Something that happened when a setting was applied to a plan that already had an opinion about
+/// it. Settings are always resolved in favor of the most recent call, and the conflict is reported
+/// back to the model so it knows what the plan ended up being.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Conflict {
+    /// The setting already had this value.
+    Duplicate { setting: String, value: String },
+    /// The setting had a different value, which has been replaced.
+    Replaced {
+        setting: String,
+        previous: String,
+        current: String,
+    },
+    /// The setting can't coexist with the current value of another setting, so that one was
+    /// changed to match.
+    Overrode {
+        setting: String,
+        value: String,
+        other: String,
+        other_value: String,
+    },
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Conflict::Duplicate { setting, value } => {
+                write!(f, "{} was already set to {}.", setting, value)
+            }
+            Conflict::Replaced {
+                setting,
+                previous,
+                current,
+            } => write!(
+                f,
+                "{} was set to {} and is now {}; the most recent setting wins.",
+                setting, previous, current
+            ),
+            Conflict::Overrode {
+                setting,
+                value,
+                other,
+                other_value,
+            } => write!(
+                f,
+                "{} set to {} requires {} to be {}, so it was changed.",
+                setting, value, other, other_value
+            ),
+        }
+    }
+}
+
+/// Turns the conflicts from applying a setting into the tool result handed back to the model.
+pub fn describe_conflicts(conflicts: &[Conflict]) -> String {
+    if conflicts.is_empty() {
+        return String::from("Ok.");
+    }
+
+    let notes: Vec<String> = conflicts.iter().map(|c| c.to_string()).collect();
+    format!("Ok, with conflicts: {}", notes.join(" "))
+}
+
+/// Sets `slot` to `value`, last write wins, and reports whether that overwrote something.
+pub fn update<T: Debug + PartialEq>(
+    slot: &mut Option<T>,
+    value: T,
+    setting: &str,
+) -> Vec<Conflict> {
+    let conflict = match slot.as_ref() {
+        Some(previous) if *previous == value => Some(Conflict::Duplicate {
+            setting: setting.to_string(),
+            value: format!("{:?}", value),
+        }),
+        Some(previous) => Some(Conflict::Replaced {
+            setting: setting.to_string(),
+            previous: format!("{:?}", previous),
+            current: format!("{:?}", value),
+        }),
+        None => None,
+    };
+
+    *slot = Some(value);
+    conflict.into_iter().collect()
+}
+
+impl SearchPlan {
+    /// The settings the model chose for this plan, without the paths and globs that were given
+    /// to `Search` itself.
+    pub fn settings(&self) -> SearchPlan {
+        SearchPlan {
+            globs: Vec::new(),
+            paths: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Turns multiline mode on or off. Turning it off also turns off dot-all, since dot-all only
+    /// means something in multiline mode.
+    pub fn set_multiline(&mut self, enabled: bool) -> Vec<Conflict> {
+        let mut conflicts = update(&mut self.multiline, enabled, "Multiline");
+
+        if !enabled && self.dot_all == Some(true) {
+            self.dot_all = Some(false);
+            conflicts.push(Conflict::Overrode {
+                setting: String::from("Multiline"),
+                value: String::from("false"),
+                other: String::from("DotAll"),
+                other_value: String::from("false"),
+            });
+        }
+
+        conflicts
+    }
+
+    /// Turns dot-all on or off. Turning it on also turns on multiline mode.
+    pub fn set_dot_all(&mut self, enabled: bool) -> Vec<Conflict> {
+        let mut conflicts = update(&mut self.dot_all, enabled, "DotAll");
+
+        if enabled && self.multiline == Some(false) {
+            self.multiline = Some(true);
+            conflicts.push(Conflict::Overrode {
+                setting: String::from("DotAll"),
+                value: String::from("true"),
+                other: String::from("Multiline"),
+                other_value: String::from("true"),
+            });
+        }
+
+        conflicts
+    }
+
+    /// Includes or excludes a file type. A file type can only be one or the other, so a later
+    /// call about the same type replaces the earlier one.
+    pub fn set_file_type(&mut self, file_type: FileType, include: bool) -> Vec<Conflict> {
+        let setting = format!("FileTypeFilter for {:?}", file_type);
+        let existing = self
+            .file_types
+            .iter()
+            .position(|filter| filter.file_type == file_type);
+
+        let mut previous = existing.map(|index| self.file_types.remove(index).include);
+        let conflicts = update(&mut previous, include, &setting);
+
+        self.file_types.push(FileTypeFilter { file_type, include });
+        conflicts
+    }
+
+    /// Includes or excludes a glob. Like a file type, a glob can only be one or the other, so a
+    /// later call about the same glob replaces the earlier one.
+    pub fn add_glob(&mut self, glob: &str, include: bool, ignore_case: bool) -> Vec<Conflict> {
+        let setting = format!("Glob filter for {}", glob);
+        let existing = self.globs.iter().position(|filter| filter.glob == glob);
+
+        let mut previous = existing.map(|index| self.globs.remove(index).include);
+        let conflicts = update(&mut previous, include, &setting);
+
+        self.globs.push(GlobFilter {
+            glob: glob.to_string(),
+            include,
+            ignore_case,
+        });
+        conflicts
+    }
+
+    /// Returns true if files should only be listed rather than searched, in which case ripgrep
+    /// must not be given a pattern.
+    pub fn lists_files(&self) -> bool {
+        matches!(self.file_display_mode, Some(FileDisplayMode::FilesIncluded))
+    }
+
+    /// Checks that the plan is ready to search for `pattern`.
+    ///
+    /// Anything in [`PlanCheck::missing`] has to be fixed before searching. Anything in
+    /// [`PlanCheck::ignored`] is a setting the search won't honor, which usually means a
+    /// preparation step was meant for a different search.
+    pub fn check(&self, pattern: &str) -> PlanCheck {
+        let mut check = PlanCheck::default();
+
+        if pattern.is_empty() && !self.lists_files() {
+            check.missing.push(String::from(
+                "Search needs a pattern unless Files is set to list the included files.",
+            ));
+        }
+
+        if self.file_display_mode.is_some() {
+            let ignored = [
+                ("ContextLines", self.context.is_some()),
+                ("Replace", self.replace.is_some()),
+                ("TrimWhitespace", self.trim_whitespace == Some(true)),
+            ];
+            for (setting, _) in ignored.iter().filter(|(_, set)| *set) {
+                check.ignored.push(format!(
+                    "{} has no effect because Files only lists file names.",
+                    setting
+                ));
+            }
+        }
+
+        if self.fixed_strings == Some(true) && self.dot_all == Some(true) {
+            check.ignored.push(String::from(
+                "DotAll has no effect because FixedStrings treats the pattern as literal text.",
+            ));
+        }
+
+        check
+    }
+
+    /// Renders the plan as ripgrep arguments: flags first, then any paths. The pattern is not
+    /// included.
+    pub fn to_args(&self) -> Vec<String> {
+        self.annotated_args()
+            .into_iter()
+            .flat_map(|arg| arg.args)
+            .collect()
+    }
+
+    /// The same arguments as [`SearchPlan::to_args`], in the same order, grouped by the
+    /// `Commands` variant whose setting produced them.
+    pub fn annotated_args(&self) -> Vec<PlanArgs> {
+        let mut args = PlanArgsBuilder::default();
+
+        if let Some(case_sensitivity) = &self.case_sensitivity {
+            args.push(
+                "CaseFilter",
+                [match case_sensitivity {
+                    CaseSensitivity::IgnoreCase => "--ignore-case",
+                    CaseSensitivity::CaseSensitive => "--case-sensitive",
+                    CaseSensitivity::SmartCase => "--smart-case",
+                }],
+            );
+        }
+
+        if let Some(context) = &self.context {
+            args.push(
+                "ContextLines",
+                [
+                    String::from("--before-context"),
+                    context.before.to_string(),
+                    String::from("--after-context"),
+                    context.after.to_string(),
+                ],
+            );
+        }
+
+        args.toggle("CRLF", self.crlf, "--crlf", "--no-crlf");
+
+        if let Some(true) = self.debug {
+            args.push("Debug", ["--debug"]);
+        }
+
+        // Dot-all does nothing outside of multiline mode, so enabling it implies multiline.
+        // `set_multiline` and `set_dot_all` keep the two from contradicting each other.
+        match (self.multiline, self.dot_all) {
+            (None, Some(true)) => args.push("DotAll", ["--multiline"]),
+            (multiline, _) => args.toggle("Multiline", multiline, "--multiline", "--no-multiline"),
+        }
+        args.toggle(
+            "DotAll",
+            self.dot_all,
+            "--multiline-dotall",
+            "--no-multiline-dotall",
+        );
+
+        match &self.file_display_mode {
+            Some(FileDisplayMode::FilesIncluded) => args.push("Files", ["--files"]),
+            Some(FileDisplayMode::FilesWithMatch) => args.push("Files", ["--files-with-matches"]),
+            Some(FileDisplayMode::FilesWithoutMatch) => {
+                args.push("Files", ["--files-without-match"])
+            }
+            None => {}
+        }
+
+        for filter in &self.file_types {
+            let flag = match filter.include {
+                true => "--type",
+                false => "--type-not",
+            };
+            args.push(
+                "FileTypeFilter",
+                [String::from(flag), filter.file_type.flag_value()],
+            );
+        }
+
+        args.toggle(
+            "FixedStrings",
+            self.fixed_strings,
+            "--fixed-strings",
+            "--no-fixed-strings",
+        );
+        for filter in &self.globs {
+            let flag = match filter.ignore_case {
+                true => "--iglob",
+                false => "--glob",
+            };
+            let glob = match filter.include {
+                true => filter.glob.clone(),
+                false => format!("!{}", filter.glob),
+            };
+            args.push("Search", [String::from(flag), glob]);
+        }
+
+        args.toggle(
+            "IncludeZip",
+            self.include_zip,
+            "--search-zip",
+            "--no-search-zip",
+        );
+
+        // Arguments go to ripgrep as they are, with no shell in between, so the replacement text
+        // must not be quoted.
+        if let Some(replacement_text) = &self.replace {
+            args.push(
+                "Replace",
+                [String::from("--replace"), replacement_text.clone()],
+            );
+        }
+
+        match &self.restriction {
+            Some(Restriction::IncludeIgnored) => args.push("RestrictionLevel", ["-u"]),
+            Some(Restriction::IncludeIgnoredHidden) => args.push("RestrictionLevel", ["-uu"]),
+            Some(Restriction::IncludeIgnoredHiddenBinary) => {
+                args.push("RestrictionLevel", ["-uuu"])
+            }
+            Some(Restriction::Default) | None => {}
+        }
+
+        if let Some(sort) = &self.sort {
+            let flag = match sort.sort_ordering {
+                SortOrdering::Ascending => "--sort",
+                SortOrdering::Descending => "--sortr",
+            };
+            args.push(
+                "SortResultsBy",
+                [String::from(flag), sort.sort_by.flag_value()],
+            );
+        }
+
+        args.toggle("Statistics", self.statistics, "--stats", "--no-stats");
+
+        // A thread count of zero means ripgrep picks, which is also what it does without the flag.
+        if let Some(threads) = self.threads.filter(|threads| *threads > 0) {
+            args.push("Threads", [String::from("--threads"), threads.to_string()]);
+        }
+
+        if let Some(true) = self.trace {
+            args.push("TraceData", ["--trace"]);
+        }
+
+        args.toggle(
+            "TreatBinaryAsText",
+            self.treat_binary_as_text,
+            "--text",
+            "--no-text",
+        );
+        args.toggle(
+            "TrimWhitespace",
+            self.trim_whitespace,
+            "--trim",
+            "--no-trim",
+        );
+
+        // A path that starts with a dash would otherwise be taken for a flag.
+        if self.paths.iter().any(|path| path.starts_with('-')) {
+            args.push("Search", [String::from("--")]);
+        }
+        for path in &self.paths {
+            args.push("Search", [path.clone()]);
+        }
+
+        args.args
+    }
+}
+
+/// The outcome of [`SearchPlan::check`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlanCheck {
+    /// Problems that stop the search from running.
+    pub missing: Vec<String>,
+    /// Settings the search would run without.
+    pub ignored: Vec<String>,
+}
+
+impl PlanCheck {
+    /// Whether the plan is ready to search.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// Some of the arguments a plan renders to, and the `Commands` variant that set them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlanArgs {
+    pub setting: &'static str,
+    pub args: Vec<String>,
+}
+
+#[derive(Default)]
+struct PlanArgsBuilder {
+    args: Vec<PlanArgs>,
+}
+
+impl PlanArgsBuilder {
+    fn push<S: Into<String>>(&mut self, setting: &'static str, args: impl IntoIterator<Item = S>) {
+        self.args.push(PlanArgs {
+            setting,
+            args: args.into_iter().map(Into::into).collect(),
+        });
+    }
+
+    fn toggle(&mut self, setting: &'static str, value: Option<bool>, on: &str, off: &str) {
+        match value {
+            Some(true) => self.push(setting, [on]),
+            Some(false) => self.push(setting, [off]),
+            None => {}
+        }
+    }
+}
+
+impl From<&Enabled> for bool {
+    fn from(enabled: &Enabled) -> Self {
+        matches!(enabled, Enabled::Yes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn args_put_flags_before_paths() {
+        let mut plan = SearchPlan {
+            paths: vec![String::from("src")],
+            threads: Some(4),
+            ..SearchPlan::default()
+        };
+        plan.add_glob("*.rs", true, false);
+        plan.add_glob("target", false, true);
+
+        assert_eq!(
+            plan.to_args(),
+            [
+                "--glob",
+                "*.rs",
+                "--iglob",
+                "!target",
+                "--threads",
+                "4",
+                "src"
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_threads_leaves_the_choice_to_ripgrep() {
+        let plan = SearchPlan {
+            threads: Some(0),
+            ..SearchPlan::default()
+        };
+        assert!(plan.to_args().is_empty());
+    }
+
+    #[test]
+    fn settings_leave_out_what_search_was_given() {
+        let mut plan = SearchPlan {
+            statistics: Some(true),
+            paths: vec![String::from("src")],
+            ..SearchPlan::default()
+        };
+        plan.add_glob("*.rs", true, false);
+        assert_eq!(plan.settings().to_args(), ["--stats"]);
+    }
+
+    #[test]
+    fn update_reports_nothing_for_a_new_setting() {
+        let mut slot = None;
+        assert!(update(&mut slot, 3, "Threads").is_empty());
+        assert_eq!(slot, Some(3));
+    }
+
+    #[test]
+    fn update_reports_a_repeat() {
+        let mut slot = Some(true);
+        assert_eq!(
+            update(&mut slot, true, "Statistics"),
+            [Conflict::Duplicate {
+                setting: String::from("Statistics"),
+                value: String::from("true"),
+            }]
+        );
+    }
+
+    #[test]
+    fn update_keeps_the_last_write() {
+        let mut slot = Some(CaseSensitivity::IgnoreCase);
+        let conflicts = update(&mut slot, CaseSensitivity::SmartCase, "CaseFilter");
+        assert_eq!(slot, Some(CaseSensitivity::SmartCase));
+        assert_eq!(
+            conflicts,
+            [Conflict::Replaced {
+                setting: String::from("CaseFilter"),
+                previous: String::from("IgnoreCase"),
+                current: String::from("SmartCase"),
+            }]
+        );
+    }
+
+    #[test]
+    fn turning_multiline_off_turns_dot_all_off() {
+        let mut plan = SearchPlan::default();
+        plan.set_dot_all(true);
+        let conflicts = plan.set_multiline(false);
+        assert_eq!(plan.dot_all, Some(false));
+        assert!(matches!(
+            conflicts.as_slice(),
+            [Conflict::Overrode { other, .. }] if other == "DotAll"
+        ));
+        assert_eq!(plan.to_args(), ["--no-multiline", "--no-multiline-dotall"]);
+    }
+
+    #[test]
+    fn turning_dot_all_on_turns_multiline_on() {
+        let mut plan = SearchPlan::default();
+        plan.set_multiline(false);
+        let conflicts = plan.set_dot_all(true);
+        assert_eq!(plan.multiline, Some(true));
+        assert!(matches!(
+            conflicts.as_slice(),
+            [Conflict::Overrode { other, .. }] if other == "Multiline"
+        ));
+    }
+
+    #[test]
+    fn dot_all_alone_implies_multiline() {
+        let mut plan = SearchPlan::default();
+        plan.set_dot_all(true);
+        assert_eq!(plan.to_args(), ["--multiline", "--multiline-dotall"]);
+    }
+
+    #[test]
+    fn a_file_type_is_either_included_or_excluded() {
+        let mut plan = SearchPlan::default();
+        plan.set_file_type(FileType::Rust, true);
+        plan.set_file_type(FileType::Text, true);
+        let conflicts = plan.set_file_type(FileType::Rust, false);
+
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(plan.to_args(), ["--type", "txt", "--type-not", "rust"]);
+    }
+
+    #[test]
+    fn repeated_globs_are_added_once() {
+        let mut plan = SearchPlan::default();
+        plan.add_glob("*.rs", true, false);
+        let conflicts = plan.add_glob("*.rs", true, false);
+        assert!(matches!(conflicts.as_slice(), [Conflict::Duplicate { .. }]));
+        assert_eq!(plan.to_args(), ["--glob", "*.rs"]);
+    }
+
+    #[test]
+    fn a_glob_is_either_included_or_excluded() {
+        let mut plan = SearchPlan::default();
+        plan.add_glob("*.rs", true, false);
+        plan.add_glob("tests/", false, false);
+        let conflicts = plan.add_glob("*.rs", false, false);
+
+        assert_eq!(
+            conflicts,
+            [Conflict::Replaced {
+                setting: String::from("Glob filter for *.rs"),
+                previous: String::from("true"),
+                current: String::from("false"),
+            }]
+        );
+        assert_eq!(plan.to_args(), ["--glob", "!tests/", "--glob", "!*.rs"]);
+    }
+
+    #[test]
+    fn conflicts_are_described_for_the_model() {
+        assert_eq!(describe_conflicts(&[]), "Ok.");
+
+        let conflicts = [
+            Conflict::Duplicate {
+                setting: String::from("Debug"),
+                value: String::from("true"),
+            },
+            Conflict::Replaced {
+                setting: String::from("Threads"),
+                previous: String::from("2"),
+                current: String::from("4"),
+            },
+        ];
+        assert_eq!(
+            describe_conflicts(&conflicts),
+            "Ok, with conflicts: Debug was already set to true. Threads was set to 2 and is now \
+             4; the most recent setting wins."
+        );
+    }
+}
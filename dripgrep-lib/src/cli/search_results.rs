@@ -0,0 +1,752 @@
+use super::error::DripgrepError;
+use super::search_plan::SearchPlan;
+use serde::{Deserialize, Serialize};
+
+/// The part of a line that matched the pattern. `start` and `end` are byte offsets into the line.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SubMatch {
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A matching line, or a line of context around one.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SearchMatch {
+    pub path: String,
+    pub line_number: Option<u64>,
+    pub text: String,
+    pub submatches: Vec<SubMatch>,
+    pub context: bool,
+}
+
+/// One file that was searched (or listed), with its match counts when ripgrep reports them.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FileSummary {
+    pub path: String,
+    pub matched_lines: u64,
+    pub matches: u64,
+}
+
+/// Totals for the whole search.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Stats {
+    pub searches: u64,
+    pub searches_with_match: u64,
+    pub bytes_searched: u64,
+    pub matched_lines: u64,
+    pub matches: u64,
+    pub elapsed_seconds: f64,
+}
+
+/// Everything a search found.
+///
+/// `warnings` holds problems that didn't stop the search, like files that couldn't be read or
+/// lines that weren't valid UTF-8 and had to be decoded lossily.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SearchResults {
+    pub matches: Vec<SearchMatch>,
+    pub files: Vec<FileSummary>,
+    pub stats: Option<Stats>,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+/// How many tokens of search results are handed back to the model. Whatever prompt comes next
+/// has to fit them alongside the system message and the tools, all within the configured
+/// `max_request_tokens`.
+pub const SEARCH_RESULT_MAX_TOKENS: usize = 1000;
+
+// Enough for the note `summarize` adds when it has to leave results out.
+const TRUNCATION_NOTE_TOKENS: usize = 30;
+
+// Matched lines can be arbitrarily long (minified files, for one), so they're cut off in summaries.
+const MAX_SUMMARY_LINE_CHARS: usize = 200;
+
+/// How ripgrep is asked to print, which decides how its output gets parsed.
+///
+/// JSON is preferred, but ripgrep won't combine it with the file listing modes, and it reports
+/// the original text of a line rather than the replaced text. Those cases fall back to plain
+/// output with paths terminated by NUL so they can still be split reliably.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    Lines,
+    Paths,
+}
+
+impl OutputFormat {
+    pub fn for_plan(plan: &SearchPlan) -> OutputFormat {
+        match plan.file_display_mode {
+            Some(_) => OutputFormat::Paths,
+            None if plan.replace.is_some() => OutputFormat::Lines,
+            None => OutputFormat::Json,
+        }
+    }
+
+    pub fn args(&self) -> Vec<String> {
+        let args: &[&str] = match self {
+            OutputFormat::Json => &["--json"],
+            OutputFormat::Lines => &[
+                "--null",
+                "--no-heading",
+                "--with-filename",
+                "--line-number",
+                "--color",
+                "never",
+            ],
+            OutputFormat::Paths => &["--null", "--color", "never"],
+        };
+
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// Only JSON output carries statistics. Asking for them in the other formats would mix
+    /// ripgrep's stats text into the results.
+    pub fn supports_stats(&self) -> bool {
+        matches!(self, OutputFormat::Json)
+    }
+}
+
+impl SearchResults {
+    /// Parses ripgrep's stdout in the given format.
+    pub fn parse(format: OutputFormat, stdout: &[u8]) -> Result<SearchResults, DripgrepError> {
+        let mut results = match format {
+            OutputFormat::Json => SearchResults::from_json_lines(stdout)?,
+            OutputFormat::Lines => SearchResults::from_lines(stdout),
+            OutputFormat::Paths => SearchResults::from_paths(stdout),
+        };
+
+        if std::str::from_utf8(stdout).is_err() && format != OutputFormat::Json {
+            results.warn_lossy(None);
+        }
+
+        Ok(results)
+    }
+
+    /// Makes sense of a finished `rg` run that searched for `pattern`, given its exit code and
+    /// output.
+    ///
+    /// Exit code 1 just means nothing matched. Anything else that isn't a success is an error,
+    /// but ripgrep reports errors for individual files and carries on, so there may still be
+    /// results worth keeping, with the errors as warnings.
+    pub fn from_rg_output(
+        format: OutputFormat,
+        pattern: &str,
+        code: Option<i32>,
+        stdout: &[u8],
+        stderr: &[u8],
+    ) -> Result<SearchResults, DripgrepError> {
+        let mut results = SearchResults::parse(format, stdout)?;
+        if matches!(code, Some(0) | Some(1)) {
+            return Ok(results);
+        }
+
+        let stderr = String::from_utf8_lossy(stderr).trim().to_string();
+        if stderr.contains("regex parse error") {
+            return Err(DripgrepError::InvalidPattern {
+                pattern: pattern.to_string(),
+                message: stderr.trim_start_matches("rg: ").to_string(),
+            });
+        }
+
+        if results.files.is_empty() && results.matches.is_empty() {
+            return Err(DripgrepError::Failed { code, stderr });
+        }
+
+        results
+            .warnings
+            .extend(stderr.lines().map(|line| line.to_string()));
+        Ok(results)
+    }
+
+    /// Parses the output of `rg --json`.
+    pub fn from_json_lines(stdout: &[u8]) -> Result<SearchResults, DripgrepError> {
+        let mut results = SearchResults::default();
+        let mut lossy_lines = 0;
+
+        for line in stdout.split(|byte| *byte == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+
+            let message = serde_json::from_slice::<Message>(line)
+                .map_err(|e| DripgrepError::InvalidOutput(e.to_string()))?;
+            if let Message::Match(line) | Message::Context(line) = &message {
+                if line.is_lossy() {
+                    lossy_lines += 1;
+                }
+            }
+
+            match message {
+                Message::Begin {} => {}
+                Message::Match(line) => results.matches.push(line.into_search_match(false)),
+                Message::Context(line) => results.matches.push(line.into_search_match(true)),
+                Message::End(end) => results.files.push(FileSummary {
+                    path: end.path.into_string(),
+                    matched_lines: end.stats.matched_lines,
+                    matches: end.stats.matches,
+                }),
+                Message::Summary(summary) => results.stats = Some(summary.stats.into()),
+            }
+        }
+
+        if lossy_lines > 0 {
+            results.warn_lossy(Some(lossy_lines));
+        }
+
+        Ok(results)
+    }
+
+    /// Notes that some of the output wasn't valid UTF-8, and how many lines were affected if
+    /// that's known.
+    pub fn warn_lossy(&mut self, lines: Option<u64>) {
+        let affected = match lines {
+            Some(1) => String::from("1 line"),
+            Some(lines) => format!("{} lines", lines),
+            None => String::from("Some lines"),
+        };
+        self.warnings.push(format!(
+            "{} of the results contained invalid UTF-8, which was replaced with U+FFFD.",
+            affected
+        ));
+    }
+
+    fn from_lines(stdout: &[u8]) -> SearchResults {
+        let mut results = SearchResults::default();
+
+        for line in String::from_utf8_lossy(stdout).lines() {
+            // Anything without a path, like the "--" between groups of context, isn't a line
+            // of results.
+            let Some((path, rest)) = line.split_once('\0') else {
+                continue;
+            };
+
+            // Matching lines separate the line number with ':', context lines with '-'.
+            let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+            let (line_number, separator, text) = match rest.get(digits..digits + 1) {
+                Some(separator) => (rest[..digits].parse().ok(), separator, &rest[digits + 1..]),
+                None => (None, ":", rest),
+            };
+
+            results.matches.push(SearchMatch {
+                path: path.to_string(),
+                line_number,
+                text: text.to_string(),
+                submatches: vec![],
+                context: separator == "-",
+            });
+
+            if results.files.last().map(|file| file.path.as_str()) != Some(path) {
+                results.files.push(FileSummary {
+                    path: path.to_string(),
+                    matched_lines: 0,
+                    matches: 0,
+                });
+            }
+            if let Some(file) = results.files.last_mut().filter(|_| separator == ":") {
+                file.matched_lines += 1;
+            }
+        }
+
+        results
+    }
+
+    fn from_paths(stdout: &[u8]) -> SearchResults {
+        let files = String::from_utf8_lossy(stdout)
+            .split('\0')
+            .map(|path| path.trim_start_matches('\n'))
+            .filter(|path| !path.is_empty())
+            .map(|path| FileSummary {
+                path: path.to_string(),
+                matched_lines: 0,
+                matches: 0,
+            })
+            .collect();
+
+        SearchResults {
+            files,
+            ..SearchResults::default()
+        }
+    }
+
+    /// Renders the results the way `rg --pretty` would.
+    pub fn pretty(&self, include_stats: bool) -> String {
+        let mut output = String::new();
+
+        if self.matches.is_empty() {
+            for file in &self.files {
+                output.push_str(&format!("\x1b[35m{}\x1b[0m\n", file.path));
+            }
+        }
+
+        let mut current_path: Option<&str> = None;
+        for line in &self.matches {
+            if current_path != Some(line.path.as_str()) {
+                if current_path.is_some() {
+                    output.push('\n');
+                }
+                output.push_str(&format!("\x1b[35m{}\x1b[0m\n", line.path));
+                current_path = Some(line.path.as_str());
+            }
+
+            if let Some(line_number) = line.line_number {
+                let separator = if line.context { '-' } else { ':' };
+                output.push_str(&format!("\x1b[32m{}\x1b[0m{}", line_number, separator));
+            }
+            output.push_str(&highlight(line));
+            output.push('\n');
+        }
+
+        if let Some(stats) = self.stats.as_ref().filter(|_| include_stats) {
+            output.push_str(&format!(
+                "\n{} matches\n{} matched lines\n{} files contained matches\n{} files searched\n{} bytes searched\n{:.6} seconds\n",
+                stats.matches,
+                stats.matched_lines,
+                stats.searches_with_match,
+                stats.searches,
+                stats.bytes_searched,
+                stats.elapsed_seconds,
+            ));
+        }
+
+        output
+    }
+
+    /// Summarizes the results as plain text for the model, in at most `token_budget` tokens.
+    ///
+    /// The files come first, since a later step is most likely to narrow a search to them, then
+    /// as many matching lines as still fit. Whatever doesn't fit is left out and counted in a note
+    /// at the end, so the model knows the results are incomplete.
+    pub fn summarize(
+        &self,
+        token_budget: usize,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let bpe = tiktoken_rs::cl100k_base()?;
+
+        // Warnings come before any results, since a search that only partly worked needs to be
+        // read that way.
+        let mut warnings = String::new();
+        for warning in &self.warnings {
+            let entry = format!("Warning: {}\n", truncate(warning.trim()));
+            if bpe.encode_ordinary(&warnings).len() + bpe.encode_ordinary(&entry).len()
+                > token_budget / 4
+            {
+                break;
+            }
+            warnings.push_str(&entry);
+        }
+
+        if self.files.is_empty() && self.matches.is_empty() {
+            return Ok(format!("{}No matches found.", warnings));
+        }
+
+        let matched_lines: Vec<&SearchMatch> =
+            self.matches.iter().filter(|line| !line.context).collect();
+
+        let mut summary = match (&self.stats, matched_lines.is_empty()) {
+            (Some(stats), _) => format!(
+                "Found {} matching lines in {} files ({} files searched).\n",
+                stats.matched_lines, stats.searches_with_match, stats.searches
+            ),
+            (None, false) => format!(
+                "Found {} matching lines in {} files.\n",
+                matched_lines.len(),
+                self.files.len()
+            ),
+            (None, true) => format!("Found {} files.\n", self.files.len()),
+        };
+
+        summary.push_str(&warnings);
+
+        // Room for the note about what was left out.
+        let mut remaining = token_budget
+            .saturating_sub(bpe.encode_ordinary(&summary).len())
+            .saturating_sub(TRUNCATION_NOTE_TOKENS);
+
+        let mut files_shown = 0;
+        for file in &self.files {
+            let entry = match file.matched_lines {
+                0 => format!("{}\n", file.path),
+                1 => format!("{} (1 matching line)\n", file.path),
+                count => format!("{} ({} matching lines)\n", file.path, count),
+            };
+            let tokens = bpe.encode_ordinary(&entry).len();
+            if tokens > remaining {
+                break;
+            }
+            remaining -= tokens;
+            summary.push_str(&entry);
+            files_shown += 1;
+        }
+
+        let mut lines_shown = 0;
+        if files_shown == self.files.len() {
+            for line in &matched_lines {
+                let mut entry = String::from(if lines_shown == 0 {
+                    "Matching lines:\n"
+                } else {
+                    ""
+                });
+                entry.push_str(&match line.line_number {
+                    Some(line_number) => format!("{}:{}: ", line.path, line_number),
+                    None => format!("{}: ", line.path),
+                });
+                entry.push_str(&truncate(line.text.trim()));
+                entry.push('\n');
+
+                let tokens = bpe.encode_ordinary(&entry).len();
+                if tokens > remaining {
+                    break;
+                }
+                remaining -= tokens;
+                summary.push_str(&entry);
+                lines_shown += 1;
+            }
+        }
+
+        if files_shown < self.files.len() || lines_shown < matched_lines.len() {
+            summary.push_str(&format!(
+                "(Results truncated: showing {} of {} files and {} of {} matching lines.)\n",
+                files_shown,
+                self.files.len(),
+                lines_shown,
+                matched_lines.len()
+            ));
+        }
+
+        Ok(summary)
+    }
+}
+
+fn truncate(text: &str) -> String {
+    match text.char_indices().nth(MAX_SUMMARY_LINE_CHARS) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+fn highlight(line: &SearchMatch) -> String {
+    let text = line.text.trim_end_matches(['\n', '\r']);
+    let mut highlighted = String::new();
+    let mut position = 0;
+
+    for submatch in &line.submatches {
+        // Offsets come from ripgrep and should always land on character boundaries of the text
+        // it sent, but don't trust them enough to panic over.
+        match (
+            text.get(position..submatch.start),
+            text.get(submatch.start..submatch.end),
+        ) {
+            (Some(before), Some(matched)) => {
+                highlighted.push_str(before);
+                highlighted.push_str(&format!("\x1b[1m\x1b[31m{}\x1b[0m", matched));
+                position = submatch.end;
+            }
+            _ => break,
+        }
+    }
+
+    highlighted.push_str(text.get(position..).unwrap_or_default());
+    highlighted
+}
+
+// What follows mirrors the subset of ripgrep's JSON Lines format that dripgrep uses.
+
+#[derive(Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "lowercase")]
+enum Message {
+    Begin {},
+    Match(Line),
+    Context(Line),
+    End(End),
+    Summary(Summary),
+}
+
+/// ripgrep sends text as `{"text": ...}`, unless it isn't valid UTF-8, in which case it sends
+/// the raw bytes base64 encoded as `{"bytes": ...}`.
+#[derive(Deserialize)]
+struct Data {
+    text: Option<String>,
+    bytes: Option<String>,
+}
+
+impl Data {
+    fn into_string(self) -> String {
+        match (self.text, self.bytes) {
+            (Some(text), _) => text,
+            (None, Some(bytes)) => String::from_utf8_lossy(&decode_base64(&bytes)).into_owned(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Line {
+    path: Data,
+    lines: Data,
+    line_number: Option<u64>,
+    submatches: Vec<RawSubMatch>,
+}
+
+impl Line {
+    fn is_lossy(&self) -> bool {
+        self.lines.text.is_none() || self.path.text.is_none()
+    }
+
+    fn into_search_match(self, context: bool) -> SearchMatch {
+        SearchMatch {
+            path: self.path.into_string(),
+            line_number: self.line_number,
+            text: self.lines.into_string(),
+            submatches: self
+                .submatches
+                .into_iter()
+                .map(|submatch| SubMatch {
+                    text: submatch.r#match.into_string(),
+                    start: submatch.start,
+                    end: submatch.end,
+                })
+                .collect(),
+            context,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawSubMatch {
+    r#match: Data,
+    start: usize,
+    end: usize,
+}
+
+#[derive(Deserialize)]
+struct End {
+    path: Data,
+    stats: RawStats,
+}
+
+#[derive(Deserialize)]
+struct Summary {
+    stats: RawStats,
+}
+
+#[derive(Deserialize)]
+struct Elapsed {
+    secs: u64,
+    nanos: u32,
+}
+
+#[derive(Deserialize)]
+struct RawStats {
+    elapsed: Elapsed,
+    searches: u64,
+    searches_with_match: u64,
+    bytes_searched: u64,
+    matched_lines: u64,
+    matches: u64,
+}
+
+impl From<RawStats> for Stats {
+    fn from(stats: RawStats) -> Self {
+        Stats {
+            searches: stats.searches,
+            searches_with_match: stats.searches_with_match,
+            bytes_searched: stats.bytes_searched,
+            matched_lines: stats.matched_lines,
+            matches: stats.matches,
+            elapsed_seconds: stats.elapsed.secs as f64 + stats.elapsed.nanos as f64 / 1e9,
+        }
+    }
+}
+
+/// Standard base64, which is all ripgrep produces. Invalid characters are skipped.
+fn decode_base64(encoded: &str) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut buffer = 0_u32;
+    let mut bits = 0;
+
+    for byte in encoded.bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => continue,
+        };
+
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON_OUTPUT: &str = r#"{"type":"begin","data":{"path":{"text":"notes.txt"}}}
+{"type":"context","data":{"path":{"text":"notes.txt"},"lines":{"text":"before\n"},"line_number":1,"absolute_offset":0,"submatches":[]}}
+{"type":"match","data":{"path":{"text":"notes.txt"},"lines":{"text":"a needle here\n"},"line_number":2,"absolute_offset":7,"submatches":[{"match":{"text":"needle"},"start":2,"end":8}]}}
+{"type":"end","data":{"path":{"text":"notes.txt"},"binary_offset":null,"stats":{"elapsed":{"secs":0,"nanos":100,"human":"0s"},"searches":1,"searches_with_match":1,"bytes_searched":30,"bytes_printed":200,"matched_lines":1,"matches":1}}}
+{"type":"summary","data":{"elapsed_total":{"secs":0,"nanos":500000000,"human":"0.5s"},"stats":{"elapsed":{"secs":1,"nanos":500000000,"human":"1.5s"},"searches":1,"searches_with_match":1,"bytes_searched":30,"bytes_printed":200,"matched_lines":1,"matches":1}}}
+"#;
+
+    #[test]
+    fn json_output_is_parsed() {
+        let results = SearchResults::parse(OutputFormat::Json, JSON_OUTPUT.as_bytes()).unwrap();
+
+        assert_eq!(results.matches.len(), 2);
+        assert!(results.matches[0].context);
+        let matched = &results.matches[1];
+        assert!(!matched.context);
+        assert_eq!(matched.line_number, Some(2));
+        assert_eq!(
+            matched.submatches,
+            [SubMatch {
+                text: String::from("needle"),
+                start: 2,
+                end: 8,
+            }]
+        );
+        assert_eq!(
+            results.files,
+            [FileSummary {
+                path: String::from("notes.txt"),
+                matched_lines: 1,
+                matches: 1,
+            }]
+        );
+        let stats = results.stats.unwrap();
+        assert_eq!(stats.bytes_searched, 30);
+        assert_eq!(stats.elapsed_seconds, 1.5);
+        assert!(results.warnings.is_empty());
+    }
+
+    #[test]
+    fn json_bytes_are_decoded_and_warned_about() {
+        // "caf\xe9 needle", which isn't valid UTF-8.
+        let output = r#"{"type":"match","data":{"path":{"text":"a.txt"},"lines":{"bytes":"Y2Fm6SBuZWVkbGU="},"line_number":3,"absolute_offset":0,"submatches":[{"match":{"text":"needle"},"start":5,"end":11}]}}"#;
+        let results = SearchResults::parse(OutputFormat::Json, output.as_bytes()).unwrap();
+
+        assert_eq!(results.matches[0].text, "caf\u{FFFD} needle");
+        assert_eq!(
+            results.warnings,
+            ["1 line of the results contained invalid UTF-8, which was replaced with U+FFFD."]
+        );
+    }
+
+    #[test]
+    fn invalid_json_output_is_an_error() {
+        let error = SearchResults::parse(OutputFormat::Json, b"not json\n").unwrap_err();
+        assert!(matches!(error, DripgrepError::InvalidOutput(_)));
+    }
+
+    #[test]
+    fn line_output_is_parsed() {
+        let output = b"src/a.rs\x003-fn before() {}\nsrc/a.rs\x004:let needle = 1;\n--\nsrc/b.rs\x0010:needle: 2\n";
+        let results = SearchResults::parse(OutputFormat::Lines, output).unwrap();
+
+        assert_eq!(results.matches.len(), 3);
+        assert!(results.matches[0].context);
+        assert_eq!(results.matches[1].line_number, Some(4));
+        assert_eq!(results.matches[1].text, "let needle = 1;");
+        // The text can contain the separator too.
+        assert_eq!(results.matches[2].text, "needle: 2");
+
+        let files: Vec<(&str, u64)> = results
+            .files
+            .iter()
+            .map(|file| (file.path.as_str(), file.matched_lines))
+            .collect();
+        assert_eq!(files, [("src/a.rs", 1), ("src/b.rs", 1)]);
+    }
+
+    #[test]
+    fn invalid_utf8_in_plain_output_is_warned_about() {
+        let results = SearchResults::parse(OutputFormat::Lines, b"a.txt\x001:caf\xe9\n").unwrap();
+        assert_eq!(results.matches[0].text, "caf\u{FFFD}");
+        assert_eq!(results.warnings.len(), 1);
+    }
+
+    #[test]
+    fn path_output_is_parsed() {
+        let results = SearchResults::parse(OutputFormat::Paths, b"a.txt\0dir/b c.txt\0").unwrap();
+        let paths: Vec<&str> = results
+            .files
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect();
+        assert_eq!(paths, ["a.txt", "dir/b c.txt"]);
+        assert!(results.matches.is_empty());
+    }
+
+    #[test]
+    fn base64_is_decoded() {
+        assert_eq!(decode_base64(""), b"");
+        assert_eq!(decode_base64("aGVsbG8="), b"hello");
+        assert_eq!(decode_base64("aGVsbG8"), b"hello");
+        assert_eq!(decode_base64("aGk="), b"hi");
+        assert_eq!(decode_base64("+/8="), [0xfb, 0xff]);
+        // Line breaks and anything else that isn't base64 is skipped.
+        assert_eq!(decode_base64("aGVs\nbG8="), b"hello");
+    }
+
+    #[test]
+    fn no_matches_is_not_an_error() {
+        let results =
+            SearchResults::from_rg_output(OutputFormat::Json, "x", Some(1), b"", b"").unwrap();
+        assert_eq!(results, SearchResults::default());
+    }
+
+    #[test]
+    fn regex_parse_errors_are_invalid_patterns() {
+        let stderr = b"rg: regex parse error:\n    (foo\n    ^\nerror: unclosed group\n";
+        let error = SearchResults::from_rg_output(OutputFormat::Json, "(foo", Some(2), b"", stderr)
+            .unwrap_err();
+        match error {
+            DripgrepError::InvalidPattern { pattern, message } => {
+                assert_eq!(pattern, "(foo");
+                assert!(message.starts_with("regex parse error:"));
+                assert!(message.ends_with("error: unclosed group"));
+            }
+            other => panic!("expected an invalid pattern, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_without_results_fail_the_search() {
+        let stderr = b"rg: missing: No such file or directory (os error 2)\n";
+        let error = SearchResults::from_rg_output(OutputFormat::Json, "x", Some(2), b"", stderr)
+            .unwrap_err();
+        match error {
+            DripgrepError::Failed { code, stderr } => {
+                assert_eq!(code, Some(2));
+                assert_eq!(
+                    stderr,
+                    "rg: missing: No such file or directory (os error 2)"
+                );
+            }
+            other => panic!("expected a failed search, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_alongside_results_are_warnings() {
+        let stderr = b"rg: locked.txt: Permission denied (os error 13)\n";
+        let results =
+            SearchResults::from_rg_output(OutputFormat::Paths, "x", Some(2), b"a.txt\0", stderr)
+                .unwrap();
+        assert_eq!(results.files.len(), 1);
+        assert_eq!(
+            results.warnings,
+            ["rg: locked.txt: Permission denied (os error 13)"]
+        );
+    }
+}
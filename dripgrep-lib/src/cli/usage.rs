@@ -0,0 +1,358 @@
+use super::call_guard::BudgetSpent;
+use super::config::config;
+use super::run_context::run_context;
+use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionResponse};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// What a model costs, in US dollars per million tokens.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct ModelPrice {
+    /// Per million prompt tokens, which is everything an embedding request is billed for.
+    pub input: f64,
+    /// Per million completion tokens.
+    #[serde(default)]
+    pub output: f64,
+}
+
+impl ModelPrice {
+    fn cost(&self, prompt_tokens: usize, completion_tokens: usize) -> f64 {
+        (prompt_tokens as f64 * self.input + completion_tokens as f64 * self.output) / 1_000_000.0
+    }
+}
+
+/// Prices for the models dripgrep might call, by model name.
+///
+/// A pricing file is TOML, with a table per model:
+///
+/// ```
+/// use dripgrep_lib::cli::usage::Pricing;
+///
+/// let pricing = Pricing::from_toml(
+///     r#"
+///     [models.gpt-4-1106-preview]
+///     input = 10.0
+///     output = 30.0
+///
+///     [models."my.local-model"]
+///     input = 0.0
+///     "#,
+/// )
+/// .unwrap();
+/// assert_eq!(pricing.models["gpt-4-1106-preview"].output, 30.0);
+/// assert_eq!(pricing.models["my.local-model"].output, 0.0);
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Pricing {
+    #[serde(default)]
+    pub models: HashMap<String, ModelPrice>,
+}
+
+impl Pricing {
+    /// The published prices of the models dripgrep uses out of the box. These go out of date, so
+    /// the config or a pricing file can replace any of them.
+    pub fn builtin() -> Pricing {
+        let models = [
+            ("gpt-4-1106-preview", 10.0, 30.0),
+            ("gpt-4-turbo", 10.0, 30.0),
+            ("gpt-3.5-turbo-0125", 0.5, 1.5),
+            ("text-embedding-3-small", 0.02, 0.0),
+            ("text-embedding-3-large", 0.13, 0.0),
+            ("text-embedding-ada-002", 0.1, 0.0),
+        ];
+        Pricing {
+            models: models
+                .into_iter()
+                .map(|(model, input, output)| (model.to_string(), ModelPrice { input, output }))
+                .collect(),
+        }
+    }
+
+    /// Reads prices from TOML text, without the built-in ones.
+    pub fn from_toml(
+        text: &str,
+    ) -> Result<Pricing, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Reads prices from the pricing file at `path`, without the built-in ones.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<Pricing, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        Pricing::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /// Adds `other`'s prices, replacing any for the same model.
+    pub fn extend(&mut self, other: Pricing) {
+        self.models.extend(other.models);
+    }
+}
+
+/// What has been sent to and got back from one model.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ModelUsage {
+    pub calls: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+static USAGE: Mutex<BTreeMap<String, ModelUsage>> = Mutex::new(BTreeMap::new());
+static RESERVED: Mutex<f64> = Mutex::new(0.0);
+static COST_REFUSED: Mutex<Option<String>> = Mutex::new(None);
+
+/// Why a call was refused for the `--max-cost` budget, to finish a sentence, if one has been.
+pub fn cost_refused() -> Option<String> {
+    COST_REFUSED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Counts `text`'s tokens the way OpenAI's current models do.
+pub fn count_tokens(text: &str) -> usize {
+    tiktoken_rs::cl100k_base_singleton()
+        .lock()
+        .encode_ordinary(text)
+        .len()
+}
+
+/// Roughly how many prompt tokens `request` will be billed for: its messages and the schemas of
+/// the tools it offers, counted as the JSON they're sent as.
+pub fn request_tokens(request: &CreateChatCompletionRequest) -> usize {
+    let messages = serde_json::to_string(&request.messages).unwrap_or_default();
+    let tools = match &request.tools {
+        Some(tools) => serde_json::to_string(tools).unwrap_or_default(),
+        None => String::new(),
+    };
+    count_tokens(&messages) + count_tokens(&tools)
+}
+
+/// Roughly how many completion tokens `response` was billed for: what the model wrote, and the
+/// names and arguments of the functions it called.
+pub fn response_tokens(response: &CreateChatCompletionResponse) -> usize {
+    response
+        .choices
+        .iter()
+        .map(|choice| {
+            let content = choice.message.content.as_deref().unwrap_or_default();
+            let calls: usize = choice
+                .message
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|call| {
+                    count_tokens(&call.function.name) + count_tokens(&call.function.arguments)
+                })
+                .sum();
+            count_tokens(content) + calls
+        })
+        .sum()
+}
+
+/// The most a call to `model` with `prompt_tokens` and up to `max_completion_tokens` could cost,
+/// if its price is known.
+fn cost_at_most(model: &str, prompt_tokens: usize, max_completion_tokens: usize) -> Option<f64> {
+    if run_context().unbilled {
+        return Some(0.0);
+    }
+    config()
+        .pricing
+        .models
+        .get(model)
+        .map(|price| price.cost(prompt_tokens, max_completion_tokens))
+}
+
+/// The most a call that's been let through could still cost, set aside from the `--max-cost`
+/// budget until the call is recorded. Dropping it gives the amount back, so a call that fails
+/// doesn't hold on to it.
+#[derive(Debug)]
+pub struct CostReservation {
+    dollars: f64,
+    reserved: &'static Mutex<f64>,
+}
+
+impl Drop for CostReservation {
+    fn drop(&mut self) {
+        if self.dollars > 0.0 {
+            *self.reserved.lock().unwrap_or_else(|e| e.into_inner()) -= self.dollars;
+        }
+    }
+}
+
+/// Refuses a call that could take the session past the `--max-cost` budget, before it's made,
+/// counting what calls that are still waiting for an answer could cost. Otherwise sets aside what
+/// this one could cost until the returned reservation is dropped, which should be after the call
+/// is recorded. What a call to a model without a price could cost isn't known, so with a budget
+/// those calls are refused too.
+pub fn reserve_cost(
+    model: &str,
+    prompt_tokens: usize,
+    max_completion_tokens: usize,
+) -> Result<CostReservation, BudgetSpent> {
+    let Some(max_cost) = run_context().max_cost else {
+        return Ok(CostReservation {
+            dollars: 0.0,
+            reserved: &RESERVED,
+        });
+    };
+    let call_cost = cost_at_most(model, prompt_tokens, max_completion_tokens);
+    reserve(&RESERVED, max_cost, model, call_cost, total_cost).map_err(|reason| {
+        let message = format!("Not asking the model, because {}.", reason);
+        *COST_REFUSED.lock().unwrap_or_else(|e| e.into_inner()) = Some(reason);
+        BudgetSpent { message }
+    })
+}
+
+/// Sets aside `call_cost` in `reserved` if it fits in `max_cost` along with what's `spent` and
+/// already set aside, or says why it doesn't, to finish a sentence.
+fn reserve(
+    reserved: &'static Mutex<f64>,
+    max_cost: f64,
+    model: &str,
+    call_cost: Option<f64>,
+    spent: impl FnOnce() -> f64,
+) -> Result<CostReservation, String> {
+    let Some(call_cost) = call_cost else {
+        return Err(format!(
+            "{} has no price, so what a call to it costs can't be kept within the session's \
+             budget of ${:.4} (give it one with --pricing)",
+            model, max_cost
+        ));
+    };
+
+    // Checking and reserving under one lock keeps calls made at the same time from each fitting
+    // in what's left of the budget on their own.
+    let mut reserved_lock = reserved.lock().unwrap_or_else(|e| e.into_inner());
+    let spent = spent() + *reserved_lock;
+    if spent + call_cost > max_cost {
+        return Err(format!(
+            "a call that could cost up to ${:.4} would take the session past its budget of \
+             ${:.4}, with ${:.4} spent or set aside",
+            call_cost, max_cost, spent
+        ));
+    }
+    *reserved_lock += call_cost;
+    Ok(CostReservation {
+        dollars: call_cost,
+        reserved,
+    })
+}
+
+/// Records a call to `model` that used `prompt_tokens` and `completion_tokens`.
+pub fn record(model: &str, prompt_tokens: usize, completion_tokens: usize) {
+    let mut usage = USAGE.lock().unwrap_or_else(|e| e.into_inner());
+    let model_usage = usage.entry(model.to_string()).or_default();
+    model_usage.calls += 1;
+    model_usage.prompt_tokens += prompt_tokens;
+    model_usage.completion_tokens += completion_tokens;
+}
+
+/// Everything used so far, by model.
+pub fn usage() -> BTreeMap<String, ModelUsage> {
+    USAGE.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// What the session has cost so far, leaving out models without a price.
+pub fn total_cost() -> f64 {
+    usage()
+        .iter()
+        .filter_map(|(model, usage)| {
+            cost_at_most(model, usage.prompt_tokens, usage.completion_tokens)
+        })
+        .sum()
+}
+
+/// A few lines saying what each model was used for and what it cost, or `None` if nothing was
+/// called.
+pub fn summary() -> Option<String> {
+    let usage = usage();
+    if usage.is_empty() {
+        return None;
+    }
+
+    let mut lines = vec![String::from(if run_context().unbilled {
+        "Model usage (not billed):"
+    } else {
+        "Model usage:"
+    })];
+    for (model, model_usage) in &usage {
+        let tokens = if model_usage.completion_tokens == 0 {
+            format!("{} tokens", model_usage.prompt_tokens)
+        } else {
+            format!(
+                "{} prompt + {} completion tokens",
+                model_usage.prompt_tokens, model_usage.completion_tokens
+            )
+        };
+        let cost = match cost_at_most(
+            model,
+            model_usage.prompt_tokens,
+            model_usage.completion_tokens,
+        ) {
+            Some(cost) => format!("${:.4}", cost),
+            None => String::from("no price configured"),
+        };
+        lines.push(format!(
+            "  {}: {} call{}, {}, {}",
+            model,
+            model_usage.calls,
+            if model_usage.calls == 1 { "" } else { "s" },
+            tokens,
+            cost
+        ));
+    }
+    lines.push(format!("  Total: ${:.4}", total_cost()));
+    Some(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(reserved: &Mutex<f64>) -> f64 {
+        *reserved.lock().unwrap()
+    }
+
+    #[test]
+    fn reservations_are_given_back_when_dropped() {
+        static RESERVED: Mutex<f64> = Mutex::new(0.0);
+
+        let reservation = reserve(&RESERVED, 1.0, "model", Some(0.25), || 0.0).unwrap();
+        assert_eq!(held(&RESERVED), 0.25);
+        drop(reservation);
+        assert_eq!(held(&RESERVED), 0.0);
+    }
+
+    #[test]
+    fn calls_that_could_go_over_the_budget_are_refused() {
+        static RESERVED: Mutex<f64> = Mutex::new(0.0);
+
+        // What's spent counts, and so does what calls still waiting for an answer set aside.
+        let first = reserve(&RESERVED, 1.0, "model", Some(0.5), || 0.25).unwrap();
+        let refused = reserve(&RESERVED, 1.0, "model", Some(0.5), || 0.25).unwrap_err();
+        assert!(
+            refused.contains("past its budget of $1.0000"),
+            "{}",
+            refused
+        );
+        assert_eq!(held(&RESERVED), 0.5);
+
+        drop(first);
+        assert!(reserve(&RESERVED, 1.0, "model", Some(0.5), || 0.25).is_ok());
+    }
+
+    #[test]
+    fn calls_to_models_without_a_price_are_refused() {
+        static RESERVED: Mutex<f64> = Mutex::new(0.0);
+
+        let refused = reserve(&RESERVED, 1.0, "unpriced-model", None, || 0.0).unwrap_err();
+        assert!(
+            refused.starts_with("unpriced-model has no price"),
+            "{}",
+            refused
+        );
+        assert_eq!(held(&RESERVED), 0.0);
+    }
+}
@@ -1,5 +1,15 @@
 use clap::Parser;
-use dripgrep_lib::cli::cli_entry::Cli;
+use dripgrep_lib::cli::cassette::{cassette, set_cassette, Cassette};
+use dripgrep_lib::cli::chat::chat;
+use dripgrep_lib::cli::cli_entry::{Cli, CliCommand};
+use dripgrep_lib::cli::commands::CommandKind;
+use dripgrep_lib::cli::config::{set_config, Config};
+use dripgrep_lib::cli::function_embeddings::FunctionEmbeddings;
+use dripgrep_lib::cli::mock_server::{MockFixture, MockServer};
+use dripgrep_lib::cli::provider::{from_config, set_api_base, set_provider};
+use dripgrep_lib::cli::rank::rank;
+use dripgrep_lib::cli::run_context::set_run_context;
+use dripgrep_lib::cli::usage::summary;
 use openai_func_enums::{logger_task, CommandError, Logger, RunCommand, ToolCallExecutionStrategy};
 use std::sync::Arc;
 use std::time::Instant;
@@ -10,45 +20,105 @@ use tokio::sync::mpsc;
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let (sender, receiver) = mpsc::channel(100);
     let logger = Arc::new(Logger { sender });
-    spawn(logger_task(receiver));
+    let logger_handle = spawn(logger_task(receiver));
     let logger_clone = logger.clone();
-    let system_instructions = Some((
-        String::from(
-            "You are a highly capable function-calling bot, trained to process complex, \
-                      multi-step requests from users. Your main function is to oversee an application \
-                      for conducting searches within file systems, accommodating a range of optional \
-                      settings as specified by users. For requests that involve multiple sequential steps, \
-                      initiate the process with the CallMultiStep function. This function requires an array \
-                      of text prompts, each delineating a distinct step in the task sequence. The essence of \
-                      CallMultiStep is to ensure that multi-step tasks are executed in an orderly fashion, \
-                      maintaining the correct sequence and respecting the dependencies between steps. \
-                      Always prioritize setting adjustments before the search action in your prompts. \
-                      For example, to search for 'fast' within markdown files, CallMultiStep should receive \
-                      two prompts: the first to activate a filetype filter for markdown files, and the second \
-                      to command the search for 'fast'. Importantly, if a request includes translating a phrase \
-                      prior to search, translate it using your internal knowledge before incorporating it \
-                      into the CallMultiStep prompts. This ensures that all steps, from option setting to \
-                      translation and search, are methodically organized and executed according to user instructions.",
-        ), 
-        226_usize,
-    ));
 
     let cli = Cli::parse();
 
-    let start_time = Instant::now();
+    let overrides = cli
+        .config_overrides()
+        .map_err(|e| CommandError::new(&e.to_string()))?;
+    let config = Config::load(cli.config.as_deref(), overrides)
+        .map_err(|e| CommandError::new(&format!("Failed to load config: {}", e)))?;
+    let system_instructions = Some(config.system_message());
+    let embed_model = config.embed_model.clone();
+    // The mock server stands in for whichever provider the config asks for.
+    let provider = match &cli.mock {
+        Some(_) => None,
+        None => Some(from_config(&config).map_err(|e| {
+            CommandError::new(&format!("Failed to set up the model provider: {}", e))
+        })?),
+    };
+    set_config(config);
+    set_run_context(cli.run_context());
+
+    // Keep the mock server alive until the command finishes.
+    let mut _mock_server = None;
+    if let Some(fixture_path) = &cli.mock {
+        let fixture = MockFixture::from_file(fixture_path)
+            .map_err(|e| CommandError::new(&format!("Failed to load mock fixture: {}", e)))?;
+        let server = MockServer::start(fixture, "127.0.0.1:0").await?;
+        set_api_base(server.base_url());
+        _mock_server = Some(server);
+    } else if let Some(provider) = provider {
+        set_provider(provider);
+    }
 
-    cli.command
-        .run(ToolCallExecutionStrategy::Async, None, logger_clone, system_instructions)
-        .await
-        .map_err(|e| {
-            Box::new(CommandError::new(&format!(
-                "Command failed with error: {}",
-                e
-            )))
+    if let Some(dir) = &cli.record {
+        let cassette = Cassette::record(dir)
+            .map_err(|e| CommandError::new(&format!("Failed to start recording: {}", e)))?;
+        set_cassette(cassette);
+    } else if let Some(dir) = &cli.replay {
+        let cassette = Cassette::replay(dir)
+            .map_err(|e| CommandError::new(&format!("Failed to load cassette: {}", e)))?;
+        set_cassette(cassette);
+    }
+
+    // Commands that ask the model rank functions with the stored embeddings, so make sure those
+    // can be compared with prompts before starting, and say if any are stale.
+    let uses_model = match &cli.command {
+        CliCommand::Chat | CliCommand::Rank { .. } => true,
+        CliCommand::Run(command) => command.kind() == CommandKind::Conversation,
+    };
+    if uses_model {
+        let stale = FunctionEmbeddings::check(&embed_model).map_err(|e| {
+            CommandError::new(&format!("Failed to check the function embeddings: {}", e))
         })?;
+        if let Some(message) = stale {
+            logger.log(message).await;
+        }
+    }
+
+    let start_time = Instant::now();
+
+    let run_result = match &cli.command {
+        CliCommand::Chat => chat(ToolCallExecutionStrategy::Async, system_instructions).await,
+        CliCommand::Run(command) => command
+            .run(
+                ToolCallExecutionStrategy::Async,
+                None,
+                logger_clone,
+                system_instructions,
+            )
+            .await
+            .map(|_| ()),
+        CliCommand::Rank { prompt } => rank(logger_clone, prompt).await,
+    };
+    // A replay that didn't send everything that was recorded didn't do what the recorded run did.
+    let run_result = match (run_result, cassette()) {
+        (Ok(()), Some(cassette)) => cassette.finish().await,
+        (run_result, _) => run_result,
+    };
+
+    // Let everything that was logged get printed before reporting the time or the error.
+    drop(logger);
+    let _ = logger_handle.await;
+
+    if run_result.is_ok() {
+        let duration = start_time.elapsed();
+        println!("Command completed in {:.2} seconds", duration.as_secs_f64());
+    }
+    // A run that failed, or stopped at --max-cost, still spent what it spent.
+    if let Some(summary) = summary() {
+        println!("{}", summary);
+    }
 
-    let duration = start_time.elapsed();
-    println!("Command completed in {:.2} seconds", duration.as_secs_f64());
+    run_result.map_err(|e| {
+        Box::new(CommandError::new(&format!(
+            "Command failed with error: {}",
+            e
+        )))
+    })?;
 
     Ok(())
 }